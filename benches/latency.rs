@@ -18,16 +18,76 @@ impl RpcSpec for Server {
     fn ack(&self) {}
 }
 
+/// the bytes on the wire for each call, both the request and the response
+/// the calls go through a relay that counts the forwarded bytes
 #[cfg(test)]
-#[bench]
-fn latency(bencher: &mut Bencher) {
+fn bytes_per_call(port: u16, config: &may_rpc::ClientConfig) -> u64 {
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    fn relay(mut r: may::net::TcpStream, mut w: may::net::TcpStream, counter: Arc<AtomicU64>) {
+        let mut buf = vec![0; 4096];
+        while let Ok(n @ 1..) = r.read(&mut buf) {
+            // count before forwarding, so the response is counted before the call returns
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+            if w.write_all(&buf[..n]).is_err() {
+                break;
+            }
+        }
+        w.shutdown(std::net::Shutdown::Both).ok();
+    }
+
+    let listener = may::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    let forwarded = Arc::new(AtomicU64::new(0));
+    let counter = forwarded.clone();
+    may::go!(move || {
+        let (client, _) = listener.accept().unwrap();
+        let server = may::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (r, w) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+        let c = counter.clone();
+        may::go!(move || relay(r, w, c));
+        relay(server, client, counter);
+    });
+
+    const CALLS: u64 = 1000;
+    let tcp_stream = may::net::TcpStream::connect(relay_addr).unwrap();
+    let client = RpcSpecClient::with_config(tcp_stream, config.clone()).unwrap();
+    // exclude the handshake
+    client.ack().unwrap();
+    let start = forwarded.load(Ordering::Relaxed);
+    for _ in 0..CALLS {
+        client.ack().unwrap();
+    }
+    (forwarded.load(Ordering::Relaxed) - start) / CALLS
+}
+
+#[cfg(test)]
+fn bench_ack(bencher: &mut Bencher, port: u16, config: may_rpc::ClientConfig) {
     use may_rpc::TcpServer;
-    let addr = ("127.0.0.1", 4000);
+    let addr = ("127.0.0.1", port);
     let _server = Server.start(addr).unwrap();
     let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
-    let client = RpcSpecClient::new(tcp_stream).unwrap();
+    let client = RpcSpecClient::with_config(tcp_stream, config.clone()).unwrap();
 
+    let bytes = bytes_per_call(port, &config);
+    eprintln!("bytes per call: {bytes}");
     bencher.iter(|| {
         client.ack().unwrap();
     });
 }
+
+#[cfg(test)]
+#[bench]
+fn latency(bencher: &mut Bencher) {
+    bench_ack(bencher, 4000, may_rpc::ClientConfig::new());
+}
+
+#[cfg(test)]
+#[bench]
+fn latency_compact_header(bencher: &mut Bencher) {
+    let mut config = may_rpc::ClientConfig::new();
+    config.set_compact_header(true);
+    bench_ack(bencher, 4001, config);
+}
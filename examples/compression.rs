@@ -0,0 +1,39 @@
+use may_rpc::{ClientConfig, Compression, CompressionStats, TcpServer};
+
+#[may_rpc::service]
+trait RpcSpec {
+    /// echo the records back
+    fn echo(&self, records: Vec<String>) -> Vec<String>;
+}
+
+#[derive(may_rpc::Server)]
+#[service(RpcSpec)]
+struct EchoServer;
+
+impl RpcSpec for EchoServer {
+    fn echo(&self, records: Vec<String>) -> Vec<String> {
+        records
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let addr = ("127.0.0.1", 4000);
+    let _server = EchoServer.start(addr).unwrap();
+
+    let mut config = ClientConfig::new();
+    // compress the payloads that are larger than 1 KiB
+    config.set_compression(Compression::Lz4, 1024);
+    let stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = RpcSpecClient::with_config(stream, config).unwrap();
+
+    let records: Vec<String> = (0..10000).map(|i| format!("record {}", i % 100)).collect();
+    for _ in 0..10 {
+        assert_eq!(client.echo(records.clone()).unwrap(), records);
+    }
+
+    // the stats include both the client and the server in this process
+    let stats = CompressionStats::get();
+    println!("{stats:#?}");
+    println!("compress ratio = {:.3}", stats.compress_ratio());
+}
@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use may_rpc::{ConnState, Reject, ReqContext, ServerConfig, TcpServer};
+
+#[may_rpc::service]
+trait RpcSpec {
+    /// return the session id of the connection
+    fn session(&self) -> usize;
+}
+
+#[derive(may_rpc::Server)]
+#[service(RpcSpec)]
+struct SessionServer;
+
+impl RpcSpec for SessionServer {
+    fn session(&self) -> usize {
+        let ctx = ReqContext::current().unwrap();
+        *ctx.state::<usize>().unwrap()
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let addr = ("127.0.0.1", 4000);
+
+    let mut config = ServerConfig::new();
+    let sessions = AtomicUsize::new(0);
+    config.on_connect(move |peer| {
+        if !matches!(peer, may_rpc::Peer::Tcp(addr) if addr.ip().is_loopback()) {
+            return Err(Reject(format!("{peer} is not allowed")));
+        }
+        let id = sessions.fetch_add(1, Ordering::Relaxed);
+        println!("{peer} connected, session={id}");
+        Ok(ConnState::new(id))
+    });
+    config.on_disconnect(|peer, reason| println!("{peer} disconnected, reason={reason:?}"));
+    let _server = SessionServer.start_with_config(addr, config).unwrap();
+
+    for _ in 0..3 {
+        let stream = may::net::TcpStream::connect(addr).unwrap();
+        let client = RpcSpecClient::new(stream).unwrap();
+        println!("rsp = {:?}", client.session());
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use may_rpc::{ClientConfig, ServerConfig, TcpServer};
+
+#[may_rpc::service]
+trait RpcSpec {
+    /// echo the value after a while
+    fn slow_echo(&self, v: u32) -> u32;
+}
+
+#[derive(may_rpc::Server)]
+#[service(RpcSpec)]
+struct EchoServer;
+
+impl RpcSpec for EchoServer {
+    fn slow_echo(&self, v: u32) -> u32 {
+        may::coroutine::sleep(Duration::from_millis(50));
+        v
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let addr = ("127.0.0.1", 4000);
+
+    let mut config = ServerConfig::new();
+    // rotate the connections frequently
+    config.set_max_conn_age(Duration::from_millis(200));
+    config.set_goaway_timeout(Duration::from_secs(1));
+    config.on_disconnect(|peer, reason| println!("{peer} disconnected, reason={reason:?}"));
+    let server = EchoServer.start_with_config(addr, config).unwrap();
+
+    let connect = move || may::net::TcpStream::connect(addr);
+    let client = RpcSpecClient::with_connector(connect, ClientConfig::new()).unwrap();
+    let client = std::sync::Arc::new(client);
+
+    // the calls keep succeeding while the server rotates the connections
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let client = client.clone();
+            may::go!(move || {
+                for j in 0..10 {
+                    let v = i * 100 + j;
+                    assert_eq!(client.slow_echo(v).unwrap(), v);
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    println!("all calls succeeded");
+
+    // wait the outstanding requests and close all the connections
+    server.shutdown();
+}
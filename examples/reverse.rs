@@ -0,0 +1,59 @@
+use may_rpc::{ClientConfig, Error, ReqContext, TcpServer};
+
+#[may_rpc::service]
+trait Hub {
+    /// register the agent, the hub calls back into it for its status
+    fn register(&self, name: String) -> Result<String, String>;
+}
+
+#[may_rpc::service]
+trait Agent {
+    /// the status of the agent, served by the client
+    fn status(&self) -> String;
+}
+
+#[derive(may_rpc::Server)]
+#[service(Hub)]
+struct HubServer;
+
+impl Hub for HubServer {
+    fn register(&self, name: String) -> Result<String, String> {
+        let ctx = ReqContext::current().unwrap();
+        // the agent is called over the same connection
+        let reverse = ctx.reverse().ok_or("no callback service")?;
+        let agent = AgentStub::from_transport(reverse);
+        let status = agent.status().map_err(|e: Error| e.to_string())?;
+        Ok(format!(
+            "{name} registered from {}, status: {status}",
+            ctx.peer()
+        ))
+    }
+}
+
+#[derive(may_rpc::Server)]
+#[service(Agent)]
+struct AgentServer;
+
+impl Agent for AgentServer {
+    fn status(&self) -> String {
+        "all good".to_owned()
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let addr = ("127.0.0.1", 4000);
+    let _server = HubServer.start(addr).unwrap();
+
+    // the agent doesn't listen on any port
+    let mut config = ClientConfig::new();
+    config.set_callback(AgentServer);
+    let stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = HubClient::with_config(stream, config).unwrap();
+    println!("{:?}", client.register("agent-1".to_owned()).unwrap());
+
+    // without a callback service the hub can't call back
+    let stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = HubClient::new(stream).unwrap();
+    println!("{:?}", client.register("agent-2".to_owned()).unwrap());
+}
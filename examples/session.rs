@@ -0,0 +1,65 @@
+use std::sync::Mutex;
+
+use may_rpc::{Peer, ServiceFactory, TcpSessionServer};
+
+#[may_rpc::service]
+trait RpcSpec {
+    /// login with the user name
+    fn login(&self, user: String) -> bool;
+    /// only logged in user can say hello
+    fn hello(&self) -> Option<String>;
+}
+
+/// each connection has its own session
+#[derive(may_rpc::Server)]
+#[service(RpcSpec)]
+struct Session {
+    peer: Peer,
+    user: Mutex<Option<String>>,
+}
+
+impl RpcSpec for Session {
+    fn login(&self, user: String) -> bool {
+        println!("{} login as {user}", self.peer);
+        *self.user.lock().unwrap() = Some(user);
+        true
+    }
+
+    fn hello(&self) -> Option<String> {
+        let user = self.user.lock().unwrap();
+        user.as_ref().map(|u| format!("Hello, {u}!"))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        println!("{} session closed", self.peer);
+    }
+}
+
+struct SessionFactory;
+
+impl ServiceFactory for SessionFactory {
+    type Session = Session;
+
+    fn new_session(&self, peer: &Peer) -> Session {
+        Session {
+            peer: peer.clone(),
+            user: Mutex::new(None),
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let addr = ("127.0.0.1", 4000);
+    let _server = SessionFactory.start(addr).unwrap();
+
+    for name in ["Alice", "Bob"] {
+        let stream = may::net::TcpStream::connect(addr).unwrap();
+        let client = RpcSpecClient::new(stream).unwrap();
+        println!("before login: {:?}", client.hello());
+        client.login(name.to_owned()).unwrap();
+        println!("after login: {:?}", client.hello());
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use may_rpc::{Stream, TcpServer};
+
+#[may_rpc::service]
+trait RpcSpec {
+    /// the numbers in `0..n`, produced one by one
+    fn range(&self, n: u64) -> Stream<u64>;
+    /// the lines until the given one, which fails the stream
+    fn lines_until_bad(&self, bad: u32) -> Stream<String>;
+    /// the sum of the uploaded numbers
+    fn sum(&self, nums: Stream<u64>) -> u64;
+    /// the first uploaded number that is over the limit, the rest are not needed
+    fn first_over(&self, limit: u64, nums: Stream<u64>) -> Option<u64>;
+    /// reply each message, and say bye once the client is done
+    fn chat(&self, name: String, msgs: Stream<String>) -> Stream<String>;
+}
+
+#[derive(Default, may_rpc::Server)]
+#[service(RpcSpec)]
+struct StreamServer {
+    // the number of items that are pulled from the iterators
+    produced: Arc<AtomicUsize>,
+}
+
+impl RpcSpec for StreamServer {
+    fn range(&self, n: u64) -> Stream<u64> {
+        let produced = self.produced.clone();
+        Stream::new((0..n).inspect(move |_| {
+            produced.fetch_add(1, Ordering::Relaxed);
+        }))
+    }
+
+    fn lines_until_bad(&self, bad: u32) -> Stream<String> {
+        Stream::try_new((0..).map(move |i| match i {
+            i if i == bad => Err(format!("bad line {i}")),
+            i => Ok(format!("line {i}")),
+        }))
+    }
+
+    fn sum(&self, nums: Stream<u64>) -> u64 {
+        nums.map(|v| v.unwrap_or(0)).sum()
+    }
+
+    fn first_over(&self, limit: u64, mut nums: Stream<u64>) -> Option<u64> {
+        nums.find_map(|v| v.ok().filter(|&v| v > limit))
+    }
+
+    fn chat(&self, name: String, msgs: Stream<String>) -> Stream<String> {
+        let (tx, replies) = Stream::channel();
+        may::go!(move || {
+            for msg in msgs {
+                let Ok(msg) = msg else { return };
+                if tx.send(format!("{name} said: {msg}")).is_err() {
+                    return;
+                }
+            }
+            // the client half closed the call, the server can still send
+            tx.send(format!("bye {name}")).ok();
+        });
+        replies
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let addr = ("127.0.0.1", 4000);
+    let server = StreamServer::default();
+    let produced = server.produced.clone();
+    let _server = server.start(addr).unwrap();
+
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = RpcSpecClient::new(tcp_stream).unwrap();
+
+    // consume the whole stream
+    let sum: u64 = client.range(1000).unwrap().map(|v| v.unwrap()).sum();
+    println!("sum of range(1000) = {sum}");
+
+    // stop early, the server is cancelled and stops producing
+    produced.store(0, Ordering::Relaxed);
+    let first: Vec<_> = client.range(u64::MAX).unwrap().take(5).collect();
+    println!("first = {first:?}");
+    may::coroutine::sleep(std::time::Duration::from_millis(100));
+    // the server is at most a window ahead of the client
+    println!("produced {} items", produced.load(Ordering::Relaxed));
+
+    // the server ends the stream with an error
+    for line in client.lines_until_bad(3).unwrap() {
+        println!("{line:?}");
+    }
+
+    // upload the items of an iterator
+    let sum = client.sum(0..1000).unwrap();
+    println!("sum of uploaded 0..1000 = {sum}");
+
+    // the server stops reading early, the client stops uploading
+    let uploaded = Arc::new(AtomicUsize::new(0));
+    let counter = uploaded.clone();
+    let nums = (0..).inspect(move |_| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    });
+    let first = client.first_over(10, nums).unwrap();
+    println!("first over 10 = {first:?}");
+    // the client is at most a window ahead of the server
+    println!("uploaded {} items", uploaded.load(Ordering::Relaxed));
+
+    // send and receive on the same call
+    let (tx, replies) = client.chat("Mom".to_owned()).unwrap();
+    for msg in ["hi", "how are you"] {
+        tx.send(msg.to_owned()).unwrap();
+    }
+    tx.close();
+    for reply in replies {
+        println!("{}", reply.unwrap());
+    }
+}
@@ -58,4 +58,6 @@ fn main() {
     let dur = dur.as_secs() as f32 + dur.subsec_nanos() as f32 / 1_000_000_000.0;
     let throughput = workers as f32 * jobs_per_worker as f32 / dur;
     println!("elapsed {dur:?}s, {throughput} rpc/second");
+    let pool = may_rpc::BufPoolStats::get();
+    println!("buf pool hit ratio {:.3}, {pool:?}", pool.hit_ratio());
 }
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use may_rpc::fuzz::decode_assemble;
+
+// decode the frames with the negotiated checksum and compact header
+// and reassemble the continuation frames into messages
+fuzz_target!(|input: (u8, &[u8])| {
+    let (caps, data) = input;
+    decode_assemble(data, caps & 1 != 0, caps & 2 != 0);
+});
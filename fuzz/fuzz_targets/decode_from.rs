@@ -0,0 +1,17 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use may_rpc::Frame;
+
+// decode all the frames from an arbitrary stream
+fuzz_target!(|data: &[u8]| {
+    let mut r = Cursor::new(data);
+    let mut buf = BytesMut::new();
+    while let Ok(frame) = Frame::decode_from(&mut r, &mut buf) {
+        let _ = frame.decode_req();
+        let _ = frame.decode_rsp();
+    }
+});
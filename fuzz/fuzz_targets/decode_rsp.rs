@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use may_rpc::Frame;
+
+// decode a response from a valid frame with an arbitrary payload
+fuzz_target!(|payload: &[u8]| {
+    let mut data = Vec::with_capacity(payload.len() + 16);
+    data.extend_from_slice(&1u64.to_be_bytes());
+    data.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    data.extend_from_slice(payload);
+
+    let frame = Frame::decode_from(&mut Cursor::new(&data), &mut BytesMut::new()).unwrap();
+    if let Ok(rsp) = frame.decode_rsp() {
+        assert!(rsp.len() <= payload.len());
+    }
+});
@@ -0,0 +1,28 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use may_rpc::{Error, Frame};
+
+// parse a response with an arbitrary type, inner len and data
+fuzz_target!(|input: (u8, u64, &[u8])| {
+    let (ty, len, body) = input;
+    let mut data = Vec::with_capacity(body.len() + 25);
+    data.extend_from_slice(&1u64.to_be_bytes());
+    data.extend_from_slice(&(body.len() as u64 + 9).to_be_bytes());
+    data.push(ty);
+    data.extend_from_slice(&len.to_be_bytes());
+    data.extend_from_slice(body);
+
+    let frame = Frame::decode_from(&mut Cursor::new(&data), &mut BytesMut::new()).unwrap();
+    match frame.decode_rsp() {
+        Ok(rsp) => assert!(ty == 0 && rsp.len() as u64 == len),
+        Err(Error::ServerDeserialize(_) | Error::ServerSerialize(_) | Error::Status(_)) => {
+            assert!((1..=3).contains(&ty))
+        }
+        Err(Error::ClientDeserialize(_)) => {}
+        Err(e) => panic!("unexpected err: {e:?}"),
+    }
+});
@@ -15,7 +15,8 @@ use syn::{
     parse::{Parse, ParseStream},
     parse_macro_input, parse_quote,
     spanned::Spanned,
-    Attribute, FnArg, Ident, Pat, PatType, ReturnType, Token, Type, Visibility,
+    Attribute, FnArg, GenericArgument, Ident, Meta, Pat, PatType, PathArguments, ReturnType, Token,
+    Type, TypePath, Visibility,
 };
 
 /// Accumulates multiple errors into a result.
@@ -48,7 +49,51 @@ struct RpcMethod {
     attrs: Vec<Attribute>,
     ident: Ident,
     args: Vec<PatType>,
+    // the index of the `Stream<Item>` arg whose items are uploaded
+    stream_arg: Option<usize>,
     output: ReturnType,
+    // marked by `#[oneway]`, the client doesn't wait for the response
+    oneway: bool,
+    // marked by `#[idempotent]`, the client can retry it by the retry policy
+    idempotent: bool,
+}
+
+/// remove the method attr that is handled by the macro, e.g. `#[oneway]`
+fn take_attr(attrs: &mut Vec<Attribute>, name: &str) -> Option<Attribute> {
+    let pos = attrs.iter().position(|attr| attr.path().is_ident(name))?;
+    let attr = attrs.remove(pos);
+    // the duplicated ones are ignored
+    attrs.retain(|attr| !attr.path().is_ident(name));
+    Some(attr)
+}
+
+/// check if the type is a `Stream<Item>` whose items are sent incrementally
+/// only `Stream` and `may_rpc::Stream` are matched, the other paths like
+/// `futures::Stream` are normal types
+fn is_stream(ty: &Type) -> bool {
+    let Type::Path(TypePath { qself: None, path }) = ty else {
+        return false;
+    };
+    let mut idents = path.segments.iter().map(|seg| seg.ident.to_string());
+    match (idents.next(), idents.next(), idents.next()) {
+        (Some(a), None, None) => path.leading_colon.is_none() && a == "Stream",
+        (Some(a), Some(b), None) => a == "may_rpc" && b == "Stream",
+        _ => false,
+    }
+}
+
+/// get the `Item` type of a `Stream<Item>`
+fn stream_item(ty: &Type) -> Option<&Type> {
+    let Type::Path(TypePath { qself: None, path }) = ty else {
+        return None;
+    };
+    match path.segments.last()?.arguments {
+        PathArguments::AngleBracketed(ref generic) => match generic.args.first()? {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
 }
 
 impl Parse for Service {
@@ -77,6 +122,19 @@ impl Parse for Service {
                     )
                 );
             }
+            let start = format!("call_{}_start", rpc.ident.unraw());
+            if let Some(other) = rpcs.iter().find(|other| other.ident == start) {
+                extend_errors!(
+                    ident_errors,
+                    syn::Error::new(
+                        other.ident.span(),
+                        format!(
+                            "method name conflicts with generated fn `{}Client::{start}`",
+                            ident.unraw()
+                        )
+                    )
+                );
+            }
         }
         ident_errors?;
 
@@ -91,7 +149,9 @@ impl Parse for Service {
 
 impl Parse for RpcMethod {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let attrs = input.call(Attribute::parse_outer)?;
+        let mut attrs = input.call(Attribute::parse_outer)?;
+        let oneway = take_attr(&mut attrs, "oneway");
+        let idempotent = take_attr(&mut attrs, "idempotent");
         input.parse::<Token![fn]>()?;
         let ident = input.parse()?;
         let content;
@@ -127,15 +187,75 @@ impl Parse for RpcMethod {
                 syn::Error::new(content.span(), "rpc method must start with &self")
             );
         }
-        errors?;
+        let mut stream_arg = None;
+        for (i, arg) in args
+            .iter()
+            .enumerate()
+            .filter(|(_, arg)| is_stream(&arg.ty))
+        {
+            if stream_item(&arg.ty).is_none() {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(arg.ty.span(), "stream arg must be `Stream<Item>`")
+                );
+            } else if stream_arg.is_some() {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(arg.span(), "rpc method can only take one stream arg")
+                );
+            }
+            stream_arg.get_or_insert(i);
+        }
         let output = input.parse()?;
         input.parse::<Token![;]>()?;
+        for attr in oneway.iter().chain(idempotent.iter()) {
+            if !matches!(attr.meta, Meta::Path(_)) {
+                let name = attr.path().get_ident().unwrap();
+                extend_errors!(
+                    errors,
+                    syn::Error::new(attr.span(), format!("`{name}` attr doesn't take any args"))
+                );
+            }
+        }
+        if let (Some(attr), Some(_)) = (&idempotent, &oneway) {
+            extend_errors!(
+                errors,
+                syn::Error::new(attr.span(), "oneway method is never retried")
+            );
+        }
+        let streaming =
+            stream_arg.is_some() || matches!(&output, ReturnType::Type(_, ty) if is_stream(ty));
+        if let (Some(attr), true) = (&idempotent, streaming) {
+            extend_errors!(
+                errors,
+                syn::Error::new(attr.span(), "streaming method can't be retried")
+            );
+        }
+        if let Some(attr) = &oneway {
+            let unit = match &output {
+                ReturnType::Default => true,
+                ReturnType::Type(_, ty) => matches!(&**ty, Type::Tuple(t) if t.elems.is_empty()),
+            };
+            if !unit || stream_arg.is_some() {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(
+                        attr.span(),
+                        "oneway method must return `()` and can't take a stream arg"
+                    )
+                );
+            }
+        }
+        errors?;
 
         Ok(Self {
             attrs,
             ident,
             args,
+            stream_arg,
             output,
+            oneway: oneway.is_some(),
+            idempotent: idempotent.is_some(),
         })
     }
 }
@@ -202,6 +322,8 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
     let generator = ServiceGenerator {
         service_ident: ident,
         client_ident: &format_ident!("{}Client", ident),
+        stub_ident: &format_ident!("{}Stub", ident),
+        batch_ident: &format_ident!("{}Batch", ident),
         request_ident: &format_ident!("{}Request", ident),
         vis,
         args,
@@ -220,6 +342,20 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
             .iter()
             .map(|args| args.iter().map(|arg| &*arg.pat).collect())
             .collect::<Vec<_>>(),
+        req_args: &rpcs
+            .iter()
+            .map(|rpc| {
+                let stream_arg = rpc.stream_arg;
+                let args = rpc.args.iter().enumerate();
+                args.filter(|(i, _)| Some(*i) != stream_arg)
+                    .map(|(_, arg)| arg)
+                    .collect()
+            })
+            .collect::<Vec<_>>(),
+        stream_args: &rpcs
+            .iter()
+            .map(|rpc| rpc.stream_arg.map(|i| &rpc.args[i]))
+            .collect::<Vec<_>>(),
         camel_case_idents: &rpcs
             .iter()
             .zip(camel_case_fn_names.iter())
@@ -237,6 +373,8 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
 struct ServiceGenerator<'a> {
     service_ident: &'a Ident,
     client_ident: &'a Ident,
+    stub_ident: &'a Ident,
+    batch_ident: &'a Ident,
     request_ident: &'a Ident,
     vis: &'a Visibility,
     attrs: &'a [Attribute],
@@ -247,6 +385,9 @@ struct ServiceGenerator<'a> {
     args: &'a [&'a [PatType]],
     return_types: &'a [&'a Type],
     arg_pats: &'a [Vec<&'a Pat>],
+    // the args that are sent in the request, without the stream arg
+    req_args: &'a [Vec<&'a PatType>],
+    stream_args: &'a [Option<&'a PatType>],
     derive_serialize: &'a TokenStream2,
 }
 
@@ -289,11 +430,47 @@ impl ServiceGenerator<'_> {
             service_ident,
             camel_case_idents,
             arg_pats,
+            req_args,
+            stream_args,
             method_idents,
+            return_types,
             vis,
             ..
         } = self;
 
+        let req_pats = req_args
+            .iter()
+            .map(|args| args.iter().map(|arg| &arg.pat).collect::<Vec<_>>());
+        let recv_streams = stream_args.iter().map(|arg| match arg {
+            // the uploaded items are received by the stream arg
+            Some(arg) => {
+                let pat = &arg.pat;
+                quote!(let #pat = may_rpc::Stream::receive(rsp)?;)
+            }
+            None => quote!(),
+        });
+
+        // the uploaded stream is not unwind safe, it's dropped with the panicked handler
+        let call_methods = method_idents
+            .iter()
+            .zip(arg_pats.iter().zip(stream_args.iter()))
+            .map(|(ident, (pats, stream_arg))| {
+                let call = quote!(|| self.#ident(#( #pats ),*));
+                match stream_arg {
+                    Some(_) => quote!(std::panic::AssertUnwindSafe(#call)),
+                    None => call,
+                }
+            });
+        let serve_rets = return_types.iter().map(|ty| {
+            if is_stream(ty) {
+                // the items are sent by the connection after the dispatch
+                quote!(ret.serve(rsp))
+            } else {
+                quote! {
+                    may_rpc::bincode::serialize_into(rsp, &ret).map_err(|e| may_rpc::WireError::ServerSerialize(e.to_string()))
+                }
+            }
+        });
         let dispatch_service_indent = format_ident!("{}ServiceDispatch", service_ident);
         quote! {
             #vis trait #dispatch_service_indent: #service_ident + std::panic::RefUnwindSafe
@@ -301,9 +478,12 @@ impl ServiceGenerator<'_> {
                 fn dispatch_req(&self, req: #request_ident, rsp: &mut may_rpc::RspBuf) -> Result<(), may_rpc::WireError> {
                     match req {
                         #(
-                            #request_ident::#camel_case_idents{ #( #arg_pats ),* } => match std::panic::catch_unwind(|| self.#method_idents(#( #arg_pats ),*)) {
-                                Ok(ret) => may_rpc::bincode::serialize_into(rsp, &ret).map_err(|e| may_rpc::WireError::ServerSerialize(e.to_string())),
-                                Err(_) => Err(may_rpc::WireError::Status("rpc panicked in server!".to_owned())),
+                            #request_ident::#camel_case_idents{ #( #req_pats ),* } => {
+                                #recv_streams
+                                match std::panic::catch_unwind(#call_methods) {
+                                    Ok(ret) => #serve_rets,
+                                    Err(_) => Err(may_rpc::WireError::Status("rpc panicked in server!".to_owned())),
+                                }
                             }
                         )*
                     }
@@ -320,7 +500,7 @@ impl ServiceGenerator<'_> {
             vis,
             request_ident,
             camel_case_idents,
-            args,
+            req_args,
             ..
         } = self;
 
@@ -330,14 +510,17 @@ impl ServiceGenerator<'_> {
             #[derive(Debug)]
             #derive_serialize
             #vis enum #request_ident {
-                #( #camel_case_idents{ #( #args ),* } ),*
+                #( #camel_case_idents{ #( #req_args ),* } ),*
             }
         }
     }
 
     fn struct_client(&self) -> TokenStream2 {
         let &Self {
-            vis, client_ident, ..
+            vis,
+            client_ident,
+            stub_ident,
+            ..
         } = self;
 
         quote! {
@@ -347,15 +530,33 @@ impl ServiceGenerator<'_> {
             #vis struct #client_ident<S: may_rpc::StreamExt>{
                 transport: may_rpc::MultiplexClient<S>,
             }
+
+            #[allow(unused)]
+            #[derive(Debug)]
+            /// The client stub that makes the unary RPC calls through any transport.
+            #vis struct #stub_ident<T>{
+                transport: T,
+            }
         }
     }
 
     fn impl_client_new(&self) -> TokenStream2 {
         let &Self {
-            client_ident, vis, ..
+            client_ident,
+            stub_ident,
+            vis,
+            ..
         } = self;
 
         quote! {
+            impl<T: may_rpc::Client> #stub_ident<T> {
+                /// Returns a new client stub that sends requests through the transport,
+                /// e.g. the reverse client from `ReqContext::reverse`.
+                #vis fn from_transport(transport: T) -> Self {
+                    Self { transport }
+                }
+            }
+
             impl<S: may_rpc::StreamExt> #client_ident<S> {
                 /// Returns a new client stub that sends requests over the given transport.
                 #vis fn new(stream: S) -> std::io::Result<Self> {
@@ -363,10 +564,31 @@ impl ServiceGenerator<'_> {
                     Ok(Self { transport })
                 }
 
+                /// Returns a new client stub with the given config.
+                #vis fn with_config(stream: S, config: may_rpc::ClientConfig) -> std::io::Result<Self> {
+                    let transport = may_rpc::MultiplexClient::with_config(stream, config)?;
+                    Ok(Self { transport })
+                }
+
+                /// Returns a new client stub that reconnects with the connector
+                /// when the connection is closed or the server is going away.
+                #vis fn with_connector<F>(connector: F, config: may_rpc::ClientConfig) -> std::io::Result<Self>
+                where
+                    F: Fn() -> std::io::Result<S> + Send + Sync + 'static,
+                {
+                    let transport = may_rpc::MultiplexClient::with_connector(connector, config)?;
+                    Ok(Self { transport })
+                }
+
                 /// set the read timeout value for the client
                 #vis fn set_timeout(&mut self, timeout: std::time::Duration) {
                     self.transport.set_timeout(timeout);
                 }
+
+                /// get the counters of the client
+                #vis fn stats(&self) -> may_rpc::ClientStats {
+                    self.transport.stats()
+                }
             }
         }
     }
@@ -374,37 +596,246 @@ impl ServiceGenerator<'_> {
     fn impl_client_rpc_methods(&self) -> TokenStream2 {
         let &Self {
             client_ident,
+            stub_ident,
             request_ident,
             method_attrs,
             vis,
             method_idents,
             args,
+            rpcs,
             return_types,
-            arg_pats,
+            req_args,
+            stream_args,
             camel_case_idents,
             ..
         } = self;
 
-        quote! {
-            impl<S: may_rpc::StreamExt> #client_ident<S> {
-                #(
+        // a bidirectional method takes a stream arg and returns a stream
+        let is_bidi =
+            |ty: &Type, stream_arg: &Option<&PatType>| is_stream(ty) && stream_arg.is_some();
+        // the stream arg is taken as any iterator of the items,
+        // or it's sent by the returned sender for a bidirectional method
+        let client_args = args
+            .iter()
+            .zip(stream_args.iter().zip(return_types.iter()))
+            .map(|(args, (stream_arg, ty))| {
+                args.iter()
+                    .filter_map(|arg| match stream_arg {
+                        Some(stream_arg) if std::ptr::eq(*stream_arg, arg) => {
+                            if is_bidi(ty, &Some(arg)) {
+                                return None;
+                            }
+                            let pat = &arg.pat;
+                            let item = stream_item(&arg.ty);
+                            Some(quote!(#pat: impl IntoIterator<Item = #item>))
+                        }
+                        _ => Some(quote!(#arg)),
+                    })
+                    .collect::<Vec<_>>()
+            });
+        let client_rets = return_types
+            .iter()
+            .zip(stream_args.iter())
+            .map(|(ty, stream_arg)| match stream_arg {
+                Some(arg) if is_bidi(ty, stream_arg) => {
+                    let item = stream_item(&arg.ty);
+                    quote!((may_rpc::Sender<#item>, #ty))
+                }
+                _ => quote!(#ty),
+            });
+        let req_pats = req_args
+            .iter()
+            .map(|args| args.iter().map(|arg| &arg.pat).collect::<Vec<_>>());
+        let call_rets = return_types
+            .iter()
+            .zip(stream_args.iter().zip(rpcs.iter()))
+            .map(|(ty, (stream_arg, rpc))| {
+                if rpc.oneway {
+                    // return once the request is sent, there is no response
+                    quote!(self.transport.call_oneway(req))
+                } else if is_bidi(ty, stream_arg) {
+                    // the items are sent and received on the same request
+                    quote!(self.transport.call_bidi(req))
+                } else if is_stream(ty) {
+                    // the items are received by the returned stream
+                    quote!(self.transport.call_stream(req))
+                } else {
+                    let call = match stream_arg {
+                        // the items are uploaded before waiting for the response
+                        Some(arg) => {
+                            let pat = &arg.pat;
+                            quote!(self.transport.call_upload(req, #pat)?)
+                        }
+                        // the idempotent method may be retried by the client
+                        None if rpc.idempotent => quote!(self.transport.call_idempotent(req)?),
+                        None => quote!(self.transport.call_service(req)?),
+                    };
+                    quote! {
+                        let rsp_frame = #call;
+                        let rsp = rsp_frame.decode_rsp()?;
+                        // deserialized the response
+                        may_rpc::bincode::deserialize(rsp)
+                            .map_err(|e| may_rpc::Error::ClientDeserialize(e.to_string()))
+                    }
+                }
+            });
+
+        let methods = method_attrs
+            .iter()
+            .zip(method_idents.iter())
+            .zip(client_args.zip(client_rets))
+            .zip(camel_case_idents.iter().zip(req_pats))
+            .zip(call_rets)
+            .map(
+                |((((method_attrs, method_ident), (client_args, client_ret)), (camel_case_ident, req_pats)), call_ret)| {
+                    quote! {
+                        #[allow(unused)]
+                        #( #method_attrs )*
+                        #vis fn #method_ident(&self, #( #client_args ),*) -> Result<#client_ret, may_rpc::Error> {
+                            use may_rpc::Client;
+                            let mut req = may_rpc::ReqBuf::new();
+                            // serialize the request
+                            let request = #request_ident::#camel_case_ident { #( #req_pats ),* };
+                            may_rpc::bincode::serialize_into(&mut req, &request)
+                                .map_err(|e| may_rpc::Error::ClientSerialize(e.to_string()))?;
+                            // call the server
+                            #call_ret
+                        }
+                    }
+                },
+            );
+        // the streaming methods are only supported by the multiplexed client
+        let (stream_methods, unary_methods): (Vec<_>, Vec<_>) = methods
+            .zip(return_types.iter().zip(stream_args.iter()))
+            .partition(|(_, (ty, stream_arg))| is_stream(ty) || stream_arg.is_some());
+        let stream_methods = stream_methods.into_iter().map(|(method, _)| method);
+        let unary_methods: Vec<_> = unary_methods
+            .into_iter()
+            .map(|(method, _)| method)
+            .collect();
+
+        // the unary methods that return a response can be started without waiting
+        let start_methods = rpcs
+            .iter()
+            .zip(return_types.iter())
+            .zip(camel_case_idents.iter())
+            .filter(|((rpc, ty), _)| !rpc.oneway && rpc.stream_arg.is_none() && !is_stream(ty))
+            .map(|((rpc, ty), camel_case_ident)| {
+                let method_ident = &rpc.ident;
+                let start_ident = format_ident!("call_{}_start", method_ident.unraw());
+                let args = &rpc.args;
+                let pats = rpc.args.iter().map(|arg| &arg.pat);
+                let doc = format!(
+                    " start the `{}` call without waiting, the response is received by the returned handle",
+                    method_ident.unraw()
+                );
+                let limits = " the call is sent only once, the retry policy, the idempotency key \
+                    and the resending after GOAWAY of the client are not applied";
+                quote! {
                     #[allow(unused)]
-                    #( #method_attrs )*
-                    #vis fn #method_idents(&self, #( #args ),*) -> Result<#return_types, may_rpc::Error> {
-                        use may_rpc::Client;
+                    #[doc = #doc]
+                    #[doc = ""]
+                    #[doc = #limits]
+                    #vis fn #start_ident(&self, #( #args ),*) -> Result<may_rpc::PendingCall<#ty>, may_rpc::Error> {
                         let mut req = may_rpc::ReqBuf::new();
                         // serialize the request
-                        let request = #request_ident::#camel_case_idents { #( #arg_pats ),* };
+                        let request = #request_ident::#camel_case_ident { #( #pats ),* };
                         may_rpc::bincode::serialize_into(&mut req, &request)
                             .map_err(|e| may_rpc::Error::ClientSerialize(e.to_string()))?;
-                        // call the server
-                        let rsp_frame = self.transport.call_service(req)?;
-                        let rsp = rsp_frame.decode_rsp()?;
-                        // deserialized the response
-                        may_rpc::bincode::deserialize(rsp)
-                            .map_err(|e| may_rpc::Error::ClientDeserialize(e.to_string()))
+                        // send the request, the response is waited by the handle
+                        self.transport.call_start(req)
                     }
-                )*
+                }
+            });
+
+        quote! {
+            impl<T: may_rpc::Client> #stub_ident<T> {
+                #( #unary_methods )*
+            }
+
+            impl<S: may_rpc::StreamExt> #client_ident<S> {
+                #( #unary_methods )*
+
+                #( #stream_methods )*
+
+                #( #start_methods )*
+            }
+        }
+    }
+}
+
+impl ServiceGenerator<'_> {
+    fn impl_client_batch(&self) -> TokenStream2 {
+        let &Self {
+            client_ident,
+            stub_ident,
+            batch_ident,
+            request_ident,
+            vis,
+            rpcs,
+            return_types,
+            camel_case_idents,
+            ..
+        } = self;
+
+        // the streaming methods can't be called in a batch
+        let methods = rpcs
+            .iter()
+            .zip(return_types.iter())
+            .zip(camel_case_idents.iter())
+            .filter(|((rpc, ty), _)| rpc.stream_arg.is_none() && !is_stream(ty))
+            .map(|((rpc, ty), camel_case_ident)| {
+                let RpcMethod {
+                    attrs, ident, args, ..
+                } = rpc;
+                let pats = args.iter().map(|arg| &arg.pat);
+                quote! {
+                    #[allow(unused)]
+                    #( #attrs )*
+                    #vis fn #ident(__batch: &mut may_rpc::Batch, #( #args ),*) -> Result<may_rpc::BatchItem<#ty>, may_rpc::Error> {
+                        let request = #request_ident::#camel_case_ident { #( #pats ),* };
+                        __batch.push(&request)
+                    }
+                }
+            });
+
+        // the clients are used to send the batch, their own methods are not shadowed
+        quote! {
+            #[allow(unused)]
+            #[derive(Debug)]
+            /// The calls that are added to a `may_rpc::Batch`, which is sent in one request frame.
+            #vis enum #batch_ident {}
+
+            impl #batch_ident {
+                #( #methods )*
+            }
+
+            impl<S: may_rpc::StreamExt> may_rpc::Client for #client_ident<S> {
+                fn call_service(&self, req: may_rpc::ReqBuf) -> Result<may_rpc::Frame, may_rpc::Error> {
+                    self.transport.call_service(req)
+                }
+
+                fn call_idempotent(&self, req: may_rpc::ReqBuf) -> Result<may_rpc::Frame, may_rpc::Error> {
+                    self.transport.call_idempotent(req)
+                }
+
+                fn call_oneway(&self, req: may_rpc::ReqBuf) -> Result<(), may_rpc::Error> {
+                    self.transport.call_oneway(req)
+                }
+            }
+
+            impl<T: may_rpc::Client> may_rpc::Client for #stub_ident<T> {
+                fn call_service(&self, req: may_rpc::ReqBuf) -> Result<may_rpc::Frame, may_rpc::Error> {
+                    self.transport.call_service(req)
+                }
+
+                fn call_idempotent(&self, req: may_rpc::ReqBuf) -> Result<may_rpc::Frame, may_rpc::Error> {
+                    self.transport.call_idempotent(req)
+                }
+
+                fn call_oneway(&self, req: may_rpc::ReqBuf) -> Result<(), may_rpc::Error> {
+                    self.transport.call_oneway(req)
+                }
             }
         }
     }
@@ -418,6 +849,7 @@ impl ToTokens for ServiceGenerator<'_> {
             self.struct_client(),
             self.impl_client_new(),
             self.impl_client_rpc_methods(),
+            self.impl_client_batch(),
             self.impl_dispatch_for_server(),
         ])
     }
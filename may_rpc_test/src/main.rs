@@ -1,3 +1,4 @@
+mod test_conn;
 mod test_hello_bar;
 mod test_hello_foo;
 
@@ -32,7 +33,7 @@ fn test_bar() {
     let addr = ("127.0.0.1", 4000);
 
     let service = HelloService;
-    let _server = service.start(addr).unwrap();
+    let server = service.start(addr).unwrap();
 
     let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
     let mut client = HelloClient::new(tcp_stream).unwrap();
@@ -57,6 +58,250 @@ fn test_bar() {
     for _i in 0..10 {
         client.yyyy("no return".to_string()).unwrap();
     }
+
+    for _i in 0..10 {
+        client.zzzz("oneway".to_string()).unwrap();
+    }
+    // the oneway calls are not waited, the server counts them once received
+    for _i in 0..100 {
+        if server.oneway_stats().calls == 10 {
+            break;
+        }
+        may::coroutine::sleep(::std::time::Duration::from_millis(10));
+    }
+    assert_eq!(server.oneway_stats().calls, 10);
+    assert_eq!(server.oneway_stats().failed, 0);
+}
+
+/// the server survives a connection that fails to write the responses
+fn test_write_failure() {
+    use may_rpc::{ServerConfig, TcpServer};
+    use std::io::Write;
+    use std::time::Duration;
+    use test_conn::{ConnClient, ConnRequest, ConnService, Disconnects};
+    let addr = ("127.0.0.1", 4001);
+
+    let disconnects = Disconnects::default();
+    let mut config = ServerConfig::new();
+    let d = disconnects.clone();
+    config.on_disconnect(move |peer, reason| d.push(peer, reason));
+    let _server = ConnService.start_with_config(addr, config).unwrap();
+
+    // ask for a large response and never read it, the unread data would reset the
+    // connection when it's closed, while the server is still writing the response
+    let mut raw = std::net::TcpStream::connect(addr).unwrap();
+    let peer = raw.local_addr().unwrap().to_string();
+    raw.write_all(test_conn::PREFACE).unwrap();
+    let len = 8 * 1024 * 1024;
+    raw.write_all(&test_conn::raw_req(1, &ConnRequest::Blob { len }))
+        .unwrap();
+    may::coroutine::sleep(Duration::from_millis(200));
+    drop(raw);
+
+    let reason = disconnects.wait(&peer).expect("no disconnect event");
+    println!("raw client disconnected, reason={reason}");
+    assert!(reason.starts_with("Write"), "{reason}");
+
+    // the server keeps serving the other connections
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let client = ConnClient::new(tcp_stream).unwrap();
+    assert_eq!(
+        client.echo("still alive".to_owned()).unwrap(),
+        "still alive"
+    );
+    assert_eq!(client.blob(1024 * 1024).unwrap().len(), 1024 * 1024);
+}
+
+/// the connection hooks see each connection and can reject it
+fn test_conn_hooks() {
+    use may_rpc::{ConnState, Reject, ServerConfig, TcpServer};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use test_conn::{ConnClient, ConnService, Disconnects};
+    let addr = ("127.0.0.1", 4002);
+
+    let connects = Arc::new(AtomicUsize::new(0));
+    let reject = Arc::new(AtomicBool::new(false));
+    let disconnects = Disconnects::default();
+    let mut config = ServerConfig::new();
+    let (c, r) = (connects.clone(), reject.clone());
+    config.on_connect(move |peer| {
+        let n = c.fetch_add(1, Ordering::Relaxed);
+        match r.load(Ordering::Relaxed) {
+            true => Err(Reject(format!("{peer} is banned"))),
+            false => Ok(ConnState::new(format!("user-{n}"))),
+        }
+    });
+    let d = disconnects.clone();
+    config.on_disconnect(move |peer, reason| d.push(peer, reason));
+    let _server = ConnService.start_with_config(addr, config).unwrap();
+
+    // the request sees the state of its connection
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let peer = tcp_stream.local_addr().unwrap().to_string();
+    let client = ConnClient::new(tcp_stream).unwrap();
+    let (who, user) = client.whoami().unwrap();
+    assert_eq!(who, peer);
+    assert_eq!(user.as_deref(), Some("user-0"));
+    assert_eq!(client.whoami().unwrap().1.as_deref(), Some("user-0"));
+
+    // a rejected connection is closed before the handshake
+    reject.store(true, Ordering::Relaxed);
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let rejected = tcp_stream.local_addr().unwrap().to_string();
+    assert!(ConnClient::new(tcp_stream).is_err());
+    assert_eq!(connects.load(Ordering::Relaxed), 2);
+
+    // the closed connection is reported, the rejected one is never connected
+    drop(client);
+    let reason = disconnects.wait(&peer).expect("no disconnect event");
+    assert_eq!(reason, "Closed");
+    assert!(disconnects.wait(&rejected).is_none());
+}
+
+/// each connection is served by its own session
+fn test_session() {
+    use may_rpc::TcpSessionServer;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use test_conn::{CounterClient, CounterFactory};
+    let addr = ("127.0.0.1", 4003);
+
+    let factory = CounterFactory::default();
+    let _server = factory.clone().start(addr).unwrap();
+
+    let connect = || CounterClient::new(may::net::TcpStream::connect(addr).unwrap()).unwrap();
+    let (a, b) = (connect(), connect());
+    for i in 1..=3 {
+        assert_eq!(a.incr().unwrap(), i);
+    }
+    // the other connection starts from its own counter
+    assert_eq!(b.incr().unwrap(), 1);
+    assert_eq!(a.incr().unwrap(), 4);
+    assert_eq!(factory.created.load(Ordering::Relaxed), 2);
+    assert_eq!(factory.closed.load(Ordering::Relaxed), 0);
+
+    // the session is dropped once its connection is closed
+    drop(a);
+    for _ in 0..100 {
+        if factory.closed.load(Ordering::Relaxed) == 1 {
+            break;
+        }
+        may::coroutine::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(factory.closed.load(Ordering::Relaxed), 1);
+    assert_eq!(b.incr().unwrap(), 2);
+}
+
+/// the dead connections are closed by the heartbeat and idle timeout
+fn test_keepalive() {
+    use may_rpc::{ClientConfig, ServerConfig, TcpServer};
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+    use test_conn::{ConnClient, ConnService, Disconnects};
+
+    // a live client answers the pings of the server
+    let addr = ("127.0.0.1", 4004);
+    let mut config = ServerConfig::new();
+    config.set_heartbeat(Duration::from_millis(50), 2);
+    let _server = ConnService.start_with_config(addr, config).unwrap();
+    let client = ConnClient::new(may::net::TcpStream::connect(addr).unwrap()).unwrap();
+    may::coroutine::sleep(Duration::from_millis(400));
+    assert_eq!(client.echo("pong".to_owned()).unwrap(), "pong");
+
+    // an idle connection is closed by the server
+    let addr = ("127.0.0.1", 4005);
+    let disconnects = Disconnects::default();
+    let mut config = ServerConfig::new();
+    config.set_idle_timeout(Duration::from_millis(100));
+    let d = disconnects.clone();
+    config.on_disconnect(move |peer, reason| d.push(peer, reason));
+    let _server = ConnService.start_with_config(addr, config).unwrap();
+    let tcp_stream = may::net::TcpStream::connect(addr).unwrap();
+    let peer = tcp_stream.local_addr().unwrap().to_string();
+    let client = ConnClient::new(tcp_stream).unwrap();
+    assert_eq!(client.echo("idle".to_owned()).unwrap(), "idle");
+    let reason = disconnects.wait(&peer).expect("no disconnect event");
+    assert_eq!(reason, "IdleTimeout");
+    assert!(client.echo("closed".to_owned()).is_err());
+
+    // a server that never answers the pings, the pending call fails without a timeout
+    let listener = std::net::TcpListener::bind("127.0.0.1:4006").unwrap();
+    let dead = std::thread::spawn(move || {
+        let (mut s, _) = listener.accept().unwrap();
+        let mut preface = [0; 12];
+        s.read_exact(&mut preface).unwrap();
+        s.write_all(test_conn::PREFACE).unwrap();
+        // read all the requests and pings until the client is gone
+        let mut buf = [0; 1024];
+        while s.read(&mut buf).is_ok_and(|n| n > 0) {}
+    });
+    let mut config = ClientConfig::new();
+    config.set_timeout(Duration::from_secs(10));
+    config.set_heartbeat(Duration::from_millis(50), 2);
+    let tcp_stream = may::net::TcpStream::connect("127.0.0.1:4006").unwrap();
+    let client = ConnClient::with_config(tcp_stream, config).unwrap();
+    let start = Instant::now();
+    let err = client.echo("no reply".to_owned()).unwrap_err();
+    println!("pending call failed after {:?}, err={err}", start.elapsed());
+    assert!(err.to_string().contains("heartbeat timeout"), "{err}");
+    assert!(start.elapsed() < Duration::from_secs(2));
+    drop(client);
+    dead.join().unwrap();
+}
+
+/// the outstanding calls survive GOAWAY and the client moves to a new connection
+fn test_goaway() {
+    use may_rpc::{ClientConfig, ServerConfig, TcpServer};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use test_conn::{ConnClient, ConnService, Disconnects};
+    let addr = ("127.0.0.1", 4007);
+
+    let disconnects = Disconnects::default();
+    let mut config = ServerConfig::new();
+    config.set_max_conn_age(Duration::from_millis(100));
+    config.set_goaway_timeout(Duration::from_secs(2));
+    let d = disconnects.clone();
+    config.on_disconnect(move |peer, reason| d.push(peer, reason));
+    let _server = ConnService.start_with_config(addr, config).unwrap();
+
+    // record the local address of each connection
+    let peers = Arc::new(Mutex::new(Vec::new()));
+    let p = peers.clone();
+    let connect = move || {
+        let stream = may::net::TcpStream::connect(addr)?;
+        p.lock().unwrap().push(stream.local_addr()?.to_string());
+        Ok(stream)
+    };
+    let client = ConnClient::with_connector(connect, ClientConfig::new()).unwrap();
+
+    // the call is sent before GOAWAY, so it's processed on the old connection
+    assert_eq!(client.sleep(300, 1).unwrap(), 1);
+    let first = peers.lock().unwrap()[0].clone();
+    let reason = disconnects.wait(&first).expect("no disconnect event");
+    assert_eq!(reason, "GoAway");
+    // the next call is sent on a new connection
+    assert_eq!(client.sleep(0, 2).unwrap(), 2);
+    assert_eq!(peers.lock().unwrap().len(), 2);
+
+    // the calls keep succeeding while the server rotates the connections
+    let client = Arc::new(client);
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let client = client.clone();
+            may::go!(move || {
+                for j in 0..10 {
+                    let v = i * 100 + j;
+                    assert_eq!(client.sleep(20, v).unwrap(), v);
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    assert!(peers.lock().unwrap().len() > 2);
 }
 
 fn main() {
@@ -64,4 +309,9 @@ fn main() {
 
     test_foo();
     test_bar();
+    test_write_failure();
+    test_conn_hooks();
+    test_session();
+    test_keepalive();
+    test_goaway();
 }
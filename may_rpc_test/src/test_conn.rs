@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use may_rpc::{DisconnectReason, Peer, ServiceFactory};
+
+/// the service that is used to test the connection events
+#[may_rpc::service]
+pub trait Conn {
+    /// echo the data back
+    fn echo(&self, data: String) -> String;
+    /// a large response of `len` bytes
+    fn blob(&self, len: usize) -> Vec<u8>;
+    /// the peer and the user of the connection that are set by `on_connect`
+    fn whoami(&self) -> (String, Option<String>);
+    /// reply the value after `ms` milliseconds
+    fn sleep(&self, ms: u64, v: u32) -> u32;
+}
+
+#[derive(may_rpc::Server)]
+#[service(Conn)]
+pub struct ConnService;
+
+impl Conn for ConnService {
+    fn echo(&self, data: String) -> String {
+        data
+    }
+
+    fn blob(&self, len: usize) -> Vec<u8> {
+        vec![0x5a; len]
+    }
+
+    fn whoami(&self) -> (String, Option<String>) {
+        let ctx = may_rpc::ReqContext::current().expect("no request context");
+        (ctx.peer().to_string(), ctx.state::<String>().cloned())
+    }
+
+    fn sleep(&self, ms: u64, v: u32) -> u32 {
+        may::coroutine::sleep(std::time::Duration::from_millis(ms));
+        v
+    }
+}
+
+/// the service that keeps a counter for each connection
+#[may_rpc::service]
+pub trait Counter {
+    /// increase the counter of the session
+    fn incr(&self) -> usize;
+}
+
+#[derive(may_rpc::Server)]
+#[service(Counter)]
+pub struct CounterSession {
+    count: AtomicUsize,
+    closed: Arc<AtomicUsize>,
+}
+
+impl Counter for CounterSession {
+    fn incr(&self) -> usize {
+        self.count.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl Drop for CounterSession {
+    fn drop(&mut self) {
+        self.closed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// create a session for each connection and count them
+#[derive(Clone, Default)]
+pub struct CounterFactory {
+    pub created: Arc<AtomicUsize>,
+    pub closed: Arc<AtomicUsize>,
+}
+
+impl ServiceFactory for CounterFactory {
+    type Session = CounterSession;
+
+    fn new_session(&self, _peer: &Peer) -> CounterSession {
+        self.created.fetch_add(1, Ordering::Relaxed);
+        CounterSession {
+            count: AtomicUsize::new(0),
+            closed: self.closed.clone(),
+        }
+    }
+}
+
+/// record the disconnected peers and the reasons
+#[derive(Clone, Default)]
+pub struct Disconnects(Arc<Mutex<Vec<(String, String)>>>);
+
+impl Disconnects {
+    pub fn push(&self, peer: &Peer, reason: &DisconnectReason) {
+        let reason = format!("{reason:?}");
+        self.0.lock().unwrap().push((peer.to_string(), reason));
+    }
+
+    /// wait for the disconnect reason of the peer
+    pub fn wait(&self, peer: &str) -> Option<String> {
+        for _ in 0..200 {
+            let all = self.0.lock().unwrap();
+            if let Some((_, reason)) = all.iter().find(|(p, _)| p == peer) {
+                return Some(reason.clone());
+            }
+            drop(all);
+            may::coroutine::sleep(std::time::Duration::from_millis(10));
+        }
+        None
+    }
+}
+
+/// the raw connection preface of the protocol version 1 without any caps
+pub const PREFACE: &[u8] = b"MAYRPC\x00\x01\x00\x00\x00\x00";
+
+/// encode a raw request frame
+pub fn raw_req(id: u64, request: &ConnRequest) -> Vec<u8> {
+    let mut req = may_rpc::ReqBuf::new();
+    bincode::serialize_into(&mut req, request).unwrap();
+    req.finish(id)
+}
@@ -10,6 +10,9 @@ pub trait Hello {
     fn xxxx(&self) -> String;
     /// no return
     fn yyyy(&self, data: String);
+    /// no return, the client doesn't wait for the server
+    #[oneway]
+    fn zzzz(&self, data: String);
 }
 
 #[derive(may_rpc::Server)]
@@ -30,7 +33,12 @@ impl Hello for HelloService {
         "no args".to_string()
     }
 
+    #[allow(clippy::unused_unit)]
     fn yyyy(&self, data: String) -> () {
         println!("yyyy: {}", data);
     }
+
+    fn zzzz(&self, data: String) {
+        println!("zzzz: {}", data);
+    }
 }
@@ -0,0 +1,522 @@
+use std::fmt;
+use std::io::{self, Cursor, Write};
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::context::ReqContext;
+use super::errors::{Error, WireError};
+use super::frame::{
+    rsp_result, Frame, ReqBuf, RspBuf, FLAG_BATCH, MODE_KEYED, MODE_PARALLEL, MODE_SINGLE,
+};
+use super::reply_cache::ReplyCache;
+use super::{Client, Server};
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use may::coroutine::JoinHandle;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// the requests that are sent in one request frame
+///
+/// the calls are added by the generated `XxxBatch` fns, and sent by any client
+/// including the generated client stubs, the results are returned in one response frame
+pub struct Batch {
+    req: ReqBuf,
+    len: usize,
+}
+
+impl fmt::Debug for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Batch").field("len", &self.len).finish()
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Batch::new()
+    }
+}
+
+impl Batch {
+    /// create an empty batch
+    pub fn new() -> Self {
+        let mut req = ReqBuf::new();
+        req.add_flags(FLAG_BATCH);
+        // the mode is the first byte of the batch
+        req.write_u8(0).unwrap();
+        Batch { req, len: 0 }
+    }
+
+    /// dispatch the requests in parallel on the server
+    /// the requests are dispatched one by one by default
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.req.data_mut()[0] = if parallel { MODE_PARALLEL } else { 0 };
+    }
+
+    /// attach an idempotency key to the batch, see `ReqBuf::set_idempotency_key`
+    pub fn set_idempotency_key(&mut self, client: u64, key: u64) {
+        self.req.set_idempotency_key(client, key);
+    }
+
+    /// number of the requests in the batch
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// check if there is no request in the batch
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// add a serialized request into the batch
+    /// used by the generated client code
+    #[doc(hidden)]
+    pub fn push<T, R: Serialize>(&mut self, request: &R) -> Result<BatchItem<T>, Error> {
+        let len =
+            bincode::serialized_size(request).map_err(|e| Error::ClientSerialize(e.to_string()))?;
+        self.req.write_u64::<BigEndian>(len)?;
+        bincode::serialize_into(&mut self.req, request)
+            .map_err(|e| Error::ClientSerialize(e.to_string()))?;
+        let index = self.len;
+        self.len += 1;
+        Ok(BatchItem {
+            index,
+            _marker: PhantomData,
+        })
+    }
+
+    /// send the batch by the client and wait for the results
+    pub fn send<C: Client + ?Sized>(self, client: &C) -> Result<BatchResults, Error> {
+        let frame = client.call_service(self.req)?;
+        BatchResults::decode(frame, self.len)
+    }
+}
+
+/// the handle of a request in the batch, its result is got from `BatchResults`
+pub struct BatchItem<T> {
+    index: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> BatchItem<T> {
+    /// the index of the request in the batch
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<T> fmt::Debug for BatchItem<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BatchItem").field(&self.index).finish()
+    }
+}
+
+/// the results of a batch, in the same order as the requests
+pub struct BatchResults {
+    frame: Frame,
+    // the type and data range of each result in the response data
+    items: Vec<(u8, Range<usize>)>,
+}
+
+impl fmt::Debug for BatchResults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BatchResults")
+            .field("len", &self.items.len())
+            .finish()
+    }
+}
+
+impl BatchResults {
+    fn decode(frame: Frame, len: usize) -> Result<Self, Error> {
+        let data = frame.decode_rsp()?;
+        let mut items = Vec::with_capacity(len);
+        let mut r = Cursor::new(data);
+        while (r.position() as usize) < data.len() {
+            let ty = r.read_u8()?;
+            let len = r.read_u64::<BigEndian>()?;
+            let start = r.position() as usize;
+            let Some(end) = (len as usize)
+                .checked_add(start)
+                .filter(|end| *end <= data.len())
+            else {
+                let s = format!("invalid batch result len. len={len}");
+                error!("{s}");
+                return Err(Error::ClientDeserialize(s));
+            };
+            items.push((ty, start..end));
+            r.set_position(end as u64);
+        }
+        if items.len() != len {
+            let s = format!(
+                "batch results mismatch. len={}, expected={len}",
+                items.len()
+            );
+            error!("{s}");
+            return Err(Error::ClientDeserialize(s));
+        }
+        Ok(BatchResults { frame, items })
+    }
+
+    /// number of the results
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// check if there is no result
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// get the result of the request in the batch
+    pub fn get<T: DeserializeOwned>(&self, item: &BatchItem<T>) -> Result<T, Error> {
+        let Some((ty, range)) = self.items.get(item.index) else {
+            let s = format!("batch item out of range. index={}", item.index);
+            return Err(Error::ClientDeserialize(s));
+        };
+        let data = self.frame.decode_rsp()?;
+        let data = rsp_result(*ty, &data[range.clone()])?;
+        bincode::deserialize(data).map_err(|e| Error::ClientDeserialize(e.to_string()))
+    }
+}
+
+/// split the packed requests of the batch
+fn decode_batch(req: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut r = Cursor::new(req);
+    let mut reqs = Vec::new();
+    while (r.position() as usize) < req.len() {
+        let len = r.read_u64::<BigEndian>()?;
+        let start = r.position() as usize;
+        let end = (len as usize)
+            .checked_add(start)
+            .filter(|end| *end <= req.len())
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        reqs.push(&req[start..end]);
+        r.set_position(end as u64);
+    }
+    Ok(reqs)
+}
+
+/// encode the result with the rsp layout without the head
+fn encode_err(e: WireError, max_len: usize) -> Vec<u8> {
+    let mut data = RspBuf::new().finish_limited(0, Err(e), max_len);
+    data.drain(..16);
+    data
+}
+
+/// serve one request of the batch, return its result with the rsp layout without the head
+fn serve_one<T: Server>(server: &T, req: &[u8], max_len: usize) -> Vec<u8> {
+    let mut rsp = RspBuf::new();
+    let mut ret = server.service(req, &mut rsp);
+    if rsp.take_stream().is_some() {
+        let s = "streaming method can't be called in a batch".to_owned();
+        ret = Err(WireError::Status(s));
+    }
+    let mut data = rsp.finish_limited(0, ret, max_len);
+    data.drain(..16);
+    data
+}
+
+/// the coroutines of the parallel requests, the running ones are cancelled when dropped
+struct Calls(Vec<JoinHandle<Vec<u8>>>);
+
+impl Drop for Calls {
+    fn drop(&mut self) {
+        for handle in self.0.iter().filter(|handle| !handle.is_done()) {
+            unsafe { handle.coroutine().cancel() };
+        }
+    }
+}
+
+/// serve the requests of the batch, return the result of the whole batch
+/// with the rsp layout without the head
+fn serve_all<T: Server>(server: &Arc<T>, mode: u8, reqs: Vec<&[u8]>, max_len: usize) -> Vec<u8> {
+    if mode & MODE_SINGLE != 0 {
+        return serve_one(&**server, reqs[0], max_len);
+    }
+    // the ty and len of the packed results are filled at last
+    let mut data = vec![0; 9];
+    if mode & MODE_PARALLEL == 0 {
+        for req in reqs {
+            data.extend_from_slice(&serve_one(&**server, req, max_len));
+        }
+    } else {
+        let ctx = ReqContext::current();
+        let handles = reqs
+            .into_iter()
+            .map(|req| {
+                let (server, req, ctx) = (server.clone(), req.to_vec(), ctx.clone());
+                may::go!(move || {
+                    if let Some(ctx) = ctx {
+                        ctx.set_current();
+                    }
+                    serve_one(&*server, &req, max_len)
+                })
+            })
+            .collect::<Vec<_>>();
+        // the requests are cancelled if the batch is cancelled, e.g. on disconnect
+        let mut calls = Calls(handles);
+        calls.0.iter().for_each(|handle| handle.wait());
+        for handle in std::mem::take(&mut calls.0) {
+            let ret = handle.join().unwrap_or_else(|_| {
+                let s = "batch request panicked in server!".to_owned();
+                encode_err(WireError::Status(s), max_len)
+            });
+            data.extend_from_slice(&ret);
+        }
+    }
+    let len = data.len() as u64 - 9;
+    BigEndian::write_u64(&mut data[1..9], len);
+    data
+}
+
+/// write the result that has the rsp layout without the head into the rsp
+fn write_result(data: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+    let msg = || String::from_utf8_lossy(&data[9..]).into_owned();
+    match data[0] {
+        0 => {
+            rsp.write_all(&data[9..]).unwrap();
+            Ok(())
+        }
+        1 => Err(WireError::ServerDeserialize(msg())),
+        2 => Err(WireError::ServerSerialize(msg())),
+        _ => Err(WireError::Status(msg())),
+    }
+}
+
+/// dispatch the requests of a batch and write the results into the rsp
+/// the result of a keyed batch is cached by the reply cache if the server has one
+pub(crate) fn serve<T: Server>(
+    server: &Arc<T>,
+    req: &[u8],
+    rsp: &mut RspBuf,
+    max_len: usize,
+    cache: Option<&ReplyCache>,
+) -> Result<(), WireError> {
+    let invalid =
+        |e: io::Error| WireError::ServerDeserialize(format!("invalid batch request: {e}"));
+    let mut r = Cursor::new(req);
+    let mode = r.read_u8().map_err(invalid)?;
+    let key = match mode & MODE_KEYED {
+        0 => None,
+        _ => {
+            let client = r.read_u64::<BigEndian>().map_err(invalid)?;
+            let key = r.read_u64::<BigEndian>().map_err(invalid)?;
+            Some((client, key))
+        }
+    };
+    let req = &req[r.position() as usize..];
+    let reqs = match mode & MODE_SINGLE {
+        0 => decode_batch(req).map_err(invalid)?,
+        _ => vec![req],
+    };
+    info!("serve batch: len={}, mode={mode}, key={key:?}", reqs.len());
+
+    let run = || serve_all(server, mode, reqs, max_len);
+    match (key, cache) {
+        (Some(key), Some(cache)) => write_result(&cache.get_or_run(key, run), rsp),
+        _ => write_result(&run(), rsp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conetty::frame::DEFAULT_MAX_MESSAGE_LEN;
+    use crate::conetty::Stream;
+    use bytes::BytesMut;
+    use may::coroutine;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// serve the commands that are serialized as strings
+    #[derive(Default)]
+    struct TestServer {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        dropped: AtomicUsize,
+    }
+
+    struct DropGuard<'a>(&'a AtomicUsize);
+
+    impl Drop for DropGuard<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Server for TestServer {
+        fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            let cmd: String = bincode::deserialize(req)
+                .map_err(|e| WireError::ServerDeserialize(e.to_string()))?;
+            match cmd.as_str() {
+                "fail" => Err(WireError::Status("failed".to_owned())),
+                "stream" => Stream::new(vec![1u32]).serve(rsp),
+                "panic" => panic!("panic in batch"),
+                "hang" => {
+                    let _guard = DropGuard(&self.dropped);
+                    coroutine::sleep(Duration::from_secs(10));
+                    Ok(())
+                }
+                "slow" => {
+                    let running = self.running.fetch_add(1, Ordering::Relaxed) + 1;
+                    self.max_running.fetch_max(running, Ordering::Relaxed);
+                    coroutine::sleep(Duration::from_millis(20));
+                    self.running.fetch_sub(1, Ordering::Relaxed);
+                    bincode::serialize_into(rsp, &cmd).unwrap();
+                    Ok(())
+                }
+                _ => {
+                    bincode::serialize_into(rsp, &cmd).unwrap();
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn decode(buf: Vec<u8>) -> Frame {
+        Frame::decode_from(&mut Cursor::new(buf), &mut BytesMut::new()).unwrap()
+    }
+
+    /// serve the batch and return the response frame
+    fn serve_batch(server: &Arc<TestServer>, batch: Batch) -> Frame {
+        let req = decode(batch.req.finish(1));
+        let mut rsp = RspBuf::new();
+        let ret = serve(
+            server,
+            req.decode_req(),
+            &mut rsp,
+            DEFAULT_MAX_MESSAGE_LEN,
+            None,
+        );
+        decode(rsp.finish(1, ret))
+    }
+
+    fn push(batch: &mut Batch, cmd: &str) -> BatchItem<String> {
+        batch.push(&cmd.to_owned()).unwrap()
+    }
+
+    fn packed(reqs: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for req in reqs {
+            buf.write_u64::<BigEndian>(req.len() as u64).unwrap();
+            buf.extend_from_slice(req);
+        }
+        buf
+    }
+
+    #[test]
+    fn decode_batch_requests() {
+        let buf = packed(&[b"a", b"", b"bc"]);
+        let reqs = decode_batch(&buf).unwrap();
+        assert_eq!(reqs, [&b"a"[..], b"", b"bc"]);
+        assert!(decode_batch(&[]).unwrap().is_empty());
+
+        // the len is truncated
+        assert!(decode_batch(&buf[..buf.len() - 2 - 8 + 3]).is_err());
+        // the data is shorter than the len
+        assert!(decode_batch(&buf[..buf.len() - 1]).is_err());
+        // the len overflows
+        let mut buf = vec![0xff; 8];
+        buf.push(0);
+        assert!(decode_batch(&buf).is_err());
+    }
+
+    #[test]
+    fn decode_batch_results() {
+        let server = Arc::new(TestServer::default());
+        let mut batch = Batch::new();
+        push(&mut batch, "a");
+        push(&mut batch, "b");
+        let frame = serve_batch(&server, batch);
+        let data = frame.decode_rsp().unwrap().to_vec();
+
+        // the count doesn't match the batch
+        let err = BatchResults::decode(frame, 3).unwrap_err();
+        assert!(matches!(err, Error::ClientDeserialize(_)), "{err:?}");
+
+        // the len of the last result is truncated
+        let mut rsp = RspBuf::new();
+        rsp.write_all(&data[..data.len() - 1]).unwrap();
+        let frame = decode(rsp.finish(1, Ok(())));
+        let err = BatchResults::decode(frame, 2).unwrap_err();
+        assert!(matches!(err, Error::ClientDeserialize(_)), "{err:?}");
+    }
+
+    #[test]
+    fn batch_item_errors() {
+        let server = Arc::new(TestServer::default());
+        let mut batch = Batch::new();
+        let a = push(&mut batch, "a");
+        let fail = push(&mut batch, "fail");
+        let stream = push(&mut batch, "stream");
+        let b = push(&mut batch, "b");
+        let bad = batch.push::<String, _>(&1u8).unwrap();
+        let results = BatchResults::decode(serve_batch(&server, batch), 5).unwrap();
+        assert_eq!(results.len(), 5);
+
+        // a failed request doesn't fail the others
+        assert_eq!(results.get(&a).unwrap(), "a");
+        assert_eq!(results.get(&b).unwrap(), "b");
+        assert!(matches!(results.get(&fail), Err(Error::Status(s)) if s == "failed"));
+        assert!(
+            matches!(results.get(&stream), Err(Error::Status(s)) if s.contains("streaming method"))
+        );
+        assert!(results.get(&bad).is_err());
+    }
+
+    #[test]
+    fn serve_serial_and_parallel() {
+        let server = Arc::new(TestServer::default());
+        let run = |parallel| {
+            let mut batch = Batch::new();
+            batch.set_parallel(parallel);
+            let items: Vec<_> = (0..4).map(|_| push(&mut batch, "slow")).collect();
+            let results = BatchResults::decode(serve_batch(&server, batch), 4).unwrap();
+            for item in &items {
+                assert_eq!(results.get(item).unwrap(), "slow");
+            }
+            server.max_running.swap(0, Ordering::Relaxed)
+        };
+        // the requests are dispatched one by one by default
+        assert_eq!(run(false), 1);
+        assert!(run(true) > 1);
+    }
+
+    #[test]
+    fn parallel_panic() {
+        let server = Arc::new(TestServer::default());
+        let mut batch = Batch::new();
+        batch.set_parallel(true);
+        let a = push(&mut batch, "a");
+        let panicked = push(&mut batch, "panic");
+        let results = BatchResults::decode(serve_batch(&server, batch), 2).unwrap();
+        assert_eq!(results.get(&a).unwrap(), "a");
+        assert!(matches!(results.get(&panicked), Err(Error::Status(s)) if s.contains("panicked")));
+    }
+
+    #[test]
+    fn parallel_cancelled() {
+        let server = Arc::new(TestServer::default());
+        let s = server.clone();
+        let handle = may::go!(move || {
+            let mut batch = Batch::new();
+            batch.set_parallel(true);
+            for _ in 0..3 {
+                push(&mut batch, "hang");
+            }
+            serve_batch(&s, batch);
+        });
+        coroutine::sleep(Duration::from_millis(50));
+        // the batch is cancelled like a request of a closed connection
+        unsafe { handle.coroutine().cancel() };
+        assert!(handle.join().is_err());
+        for _ in 0..100 {
+            if server.dropped.load(Ordering::Relaxed) == 3 {
+                break;
+            }
+            coroutine::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.dropped.load(Ordering::Relaxed), 3);
+    }
+}
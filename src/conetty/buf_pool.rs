@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+/// the initial capacity of a new buffer
+const INIT_CAP: usize = 128;
+/// the buffers larger than this are not pooled
+const MAX_POOLED_CAP: usize = 64 * 1024;
+/// the max number of buffers pooled by each thread
+const MAX_POOLED: usize = 64;
+/// the max number of buffers in the shared pool
+const MAX_SHARED: usize = 256;
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+// the coroutines are not bound to a thread, a buffer is often returned on another
+// thread, the thread pools spill into the shared pool and refill from it
+static SHARED: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// get an empty buffer from the pool
+pub(crate) fn get() -> Vec<u8> {
+    let buf = POOL
+        .with(|pool| pool.borrow_mut().pop())
+        .or_else(|| SHARED.lock().unwrap().pop());
+    match buf {
+        Some(buf) => {
+            STATS.hits.fetch_add(1, Ordering::Relaxed);
+            buf
+        }
+        None => {
+            STATS.misses.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(INIT_CAP)
+        }
+    }
+}
+
+/// return a buffer to the pool
+pub(crate) fn put(mut buf: Vec<u8>) {
+    let cap = buf.capacity();
+    if !(INIT_CAP..=MAX_POOLED_CAP).contains(&cap) {
+        STATS.dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    buf.clear();
+    let buf = POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() >= MAX_POOLED {
+            return Some(buf);
+        }
+        pool.push(buf);
+        None
+    });
+    let pooled = match buf {
+        None => true,
+        Some(buf) => {
+            let mut shared = SHARED.lock().unwrap();
+            let room = shared.len() < MAX_SHARED;
+            if room {
+                shared.push(buf);
+            }
+            room
+        }
+    };
+    if pooled {
+        STATS.recycled.fetch_add(1, Ordering::Relaxed);
+    } else {
+        STATS.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// return the buffer of a written frame to the pool if it's not shared
+pub(crate) fn recycle(data: Bytes) {
+    if let Ok(buf) = data.try_into_mut() {
+        put(buf.into());
+    }
+}
+
+/// the process wide pool counters
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    recycled: AtomicU64,
+    dropped: AtomicU64,
+}
+
+static STATS: Stats = Stats {
+    hits: AtomicU64::new(0),
+    misses: AtomicU64::new(0),
+    recycled: AtomicU64::new(0),
+    dropped: AtomicU64::new(0),
+};
+
+/// a snapshot of the process wide buffer pool counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufPoolStats {
+    /// number of the buffers that are taken from the pool
+    pub hits: u64,
+    /// number of the buffers that are allocated since the pool is empty
+    pub misses: u64,
+    /// number of the buffers that are returned to the pool
+    pub recycled: u64,
+    /// number of the buffers that are too large or exceed the pool size
+    pub dropped: u64,
+}
+
+impl BufPoolStats {
+    /// get the current counters
+    pub fn get() -> Self {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        BufPoolStats {
+            hits: load(&STATS.hits),
+            misses: load(&STATS.misses),
+            recycled: load(&STATS.recycled),
+            dropped: load(&STATS.dropped),
+        }
+    }
+
+    /// the hits over all the buffer requests
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
+    }
+}
@@ -0,0 +1,498 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use may::sync::Mutex;
+
+use super::errors::Error;
+use super::frame::{Frame, ReqBuf};
+use super::retry::RetryCode;
+use super::Client;
+
+/// the default failure rate that opens the circuit
+const DEFAULT_FAILURE_RATE: f32 = 0.5;
+/// the default min calls in a window before the failure rate is checked
+const DEFAULT_MIN_CALLS: usize = 20;
+/// the default window that the calls are counted in
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+/// the default time to stay open before probing
+const DEFAULT_OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+/// the default probe calls in the half open state
+const DEFAULT_HALF_OPEN_CALLS: usize = 1;
+
+type StateHook = Arc<dyn Fn(CircuitState, CircuitState) + Send + Sync>;
+
+/// the state of a circuit breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CircuitState {
+    /// the calls are sent and their failures are counted
+    Closed,
+    /// the calls are rejected with `Error::CircuitOpen` without being sent
+    Open,
+    /// a few probe calls are sent to check if the server is back
+    HalfOpen,
+}
+
+/// the policy of a circuit breaker
+#[derive(Debug, Clone)]
+pub struct CircuitPolicy {
+    failure_rate: f32,
+    min_calls: usize,
+    window: Duration,
+    open_timeout: Duration,
+    half_open_calls: usize,
+}
+
+impl Default for CircuitPolicy {
+    fn default() -> Self {
+        CircuitPolicy {
+            failure_rate: DEFAULT_FAILURE_RATE,
+            min_calls: DEFAULT_MIN_CALLS,
+            window: DEFAULT_WINDOW,
+            open_timeout: DEFAULT_OPEN_TIMEOUT,
+            half_open_calls: DEFAULT_HALF_OPEN_CALLS,
+        }
+    }
+}
+
+impl CircuitPolicy {
+    /// create a default policy
+    /// it opens when half of at least 20 calls in 10 seconds failed, and probes after 5 seconds
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// open the circuit when the failed calls reach `rate` of all the calls in the window
+    /// the default value is 0.5
+    pub fn set_failure_rate(&mut self, rate: f32) {
+        self.failure_rate = rate.clamp(0.0, 1.0);
+    }
+
+    /// the failure rate is only checked after `calls` in the window are done
+    /// the default value is 20
+    pub fn set_min_calls(&mut self, calls: usize) {
+        self.min_calls = calls.max(1);
+    }
+
+    /// count the calls in a window of `window`, the counters are reset for each window
+    /// the default value is 10 seconds
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// stay open for `timeout` before probing the server, the default value is 5 seconds
+    pub fn set_open_timeout(&mut self, timeout: Duration) {
+        self.open_timeout = timeout;
+    }
+
+    /// send at most `calls` probe calls in the half open state, the circuit is closed
+    /// once all of them succeed and opened again if any fails, the default value is 1
+    pub fn set_half_open_calls(&mut self, calls: usize) {
+        self.half_open_calls = calls.max(1);
+    }
+}
+
+/// the counters of a circuit breaker
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CircuitStats {
+    /// number of the times the circuit is opened
+    pub opened: u64,
+    /// number of the times the circuit is half opened
+    pub half_opened: u64,
+    /// number of the times the circuit is closed after probing
+    pub closed: u64,
+    /// number of the calls that are rejected
+    pub rejected: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    opened: AtomicU64,
+    half_opened: AtomicU64,
+    closed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+struct State {
+    state: CircuitState,
+    // bumped on each transition, the calls of the former states are not counted
+    gen: u64,
+    // the start of the window, or when the circuit is opened
+    since: Instant,
+    // the done calls and the failed ones in the window or in the half open state
+    calls: usize,
+    failures: usize,
+    // the probe calls that are sent in the half open state
+    probes: usize,
+}
+
+/// a wrapper of a client that fails fast when the server is down
+///
+/// the circuit is opened when too many calls are failing, then the calls are rejected
+/// with `Error::CircuitOpen` instead of waiting for the timeout. after the open timeout
+/// a few probe calls are sent, the circuit is closed again if they succeed.
+/// only the timeouts, the connection errors and the status errors are counted as failures
+pub struct CircuitBreaker<C> {
+    client: C,
+    policy: CircuitPolicy,
+    state: Mutex<State>,
+    // invoked when the state is changed
+    on_state_change: Option<StateHook>,
+    counters: Counters,
+}
+
+impl<C> fmt::Debug for CircuitBreaker<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("policy", &self.policy)
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl<C> CircuitBreaker<C> {
+    /// wrap the client with the policy
+    pub fn new(client: C, policy: CircuitPolicy) -> Self {
+        CircuitBreaker {
+            client,
+            policy,
+            state: Mutex::new(State {
+                state: CircuitState::Closed,
+                gen: 0,
+                since: Instant::now(),
+                calls: 0,
+                failures: 0,
+                probes: 0,
+            }),
+            on_state_change: None,
+            counters: Counters::default(),
+        }
+    }
+
+    /// set the callback that would be invoked with the old and new state when it's changed
+    /// e.g. to report the transitions to the metrics
+    pub fn on_state_change<F>(&mut self, f: F)
+    where
+        F: Fn(CircuitState, CircuitState) + Send + Sync + 'static,
+    {
+        self.on_state_change = Some(Arc::new(f));
+    }
+
+    /// the wrapped client
+    pub fn get_ref(&self) -> &C {
+        &self.client
+    }
+
+    /// the current state, an open circuit reports `HalfOpen` only after the next call
+    pub fn state(&self) -> CircuitState {
+        self.state.lock().unwrap().state
+    }
+
+    /// get the current counters of the circuit breaker
+    pub fn stats(&self) -> CircuitStats {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        CircuitStats {
+            opened: load(&self.counters.opened),
+            half_opened: load(&self.counters.half_opened),
+            closed: load(&self.counters.closed),
+            rejected: load(&self.counters.rejected),
+        }
+    }
+
+    /// change the state, return the transition for the callback
+    fn transit(&self, s: &mut State, to: CircuitState) -> (CircuitState, CircuitState) {
+        let from = s.state;
+        let counter = match to {
+            CircuitState::Open => &self.counters.opened,
+            CircuitState::HalfOpen => &self.counters.half_opened,
+            CircuitState::Closed => &self.counters.closed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        info!("circuit state changed: {from:?} -> {to:?}");
+        *s = State {
+            state: to,
+            gen: s.gen + 1,
+            since: Instant::now(),
+            calls: 0,
+            failures: 0,
+            probes: 0,
+        };
+        (from, to)
+    }
+
+    fn notify(&self, transition: Option<(CircuitState, CircuitState)>) {
+        if let (Some((from, to)), Some(f)) = (transition, self.on_state_change.as_ref()) {
+            f(from, to);
+        }
+    }
+
+    /// check if a call can be sent, return the generation of the state
+    fn acquire(&self) -> Result<u64, Error> {
+        let mut s = self.state.lock().unwrap();
+        let mut transition = None;
+        match s.state {
+            CircuitState::Closed if s.since.elapsed() >= self.policy.window => {
+                s.since = Instant::now();
+                s.calls = 0;
+                s.failures = 0;
+            }
+            CircuitState::Open if s.since.elapsed() >= self.policy.open_timeout => {
+                transition = Some(self.transit(&mut s, CircuitState::HalfOpen));
+            }
+            _ => {}
+        }
+        let ret = match s.state {
+            CircuitState::Closed => Ok(s.gen),
+            CircuitState::HalfOpen if s.probes < self.policy.half_open_calls => {
+                s.probes += 1;
+                Ok(s.gen)
+            }
+            _ => {
+                self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(Error::CircuitOpen)
+            }
+        };
+        drop(s);
+        self.notify(transition);
+        ret
+    }
+
+    /// count the done call that is sent in the generation
+    fn record(&self, gen: u64, failed: bool) {
+        let mut s = self.state.lock().unwrap();
+        if s.gen != gen {
+            return;
+        }
+        s.calls += 1;
+        s.failures += failed as usize;
+        let policy = &self.policy;
+        let transition = match s.state {
+            CircuitState::Closed => {
+                let rate = s.failures as f32 / s.calls as f32;
+                (s.calls >= policy.min_calls && failed && rate >= policy.failure_rate)
+                    .then(|| self.transit(&mut s, CircuitState::Open))
+            }
+            CircuitState::HalfOpen if failed => Some(self.transit(&mut s, CircuitState::Open)),
+            CircuitState::HalfOpen => (s.calls >= policy.half_open_calls)
+                .then(|| self.transit(&mut s, CircuitState::Closed)),
+            CircuitState::Open => None,
+        };
+        drop(s);
+        self.notify(transition);
+    }
+
+    /// send the call through the breaker
+    fn call<T>(
+        &self,
+        f: impl FnOnce(&C) -> Result<T, Error>,
+        is_err: impl Fn(&T) -> bool,
+    ) -> Result<T, Error> {
+        let gen = self.acquire()?;
+        // a cancelled or panicked call is counted as a failure
+        let mut permit = Permit {
+            breaker: self,
+            gen,
+            failed: true,
+        };
+        let ret = f(&self.client);
+        permit.failed = match &ret {
+            Ok(v) => is_err(v),
+            Err(e) => RetryCode::of(e).is_some(),
+        };
+        ret
+    }
+}
+
+/// the permit of a call that is sent, the call is counted when it's dropped
+struct Permit<'a, C> {
+    breaker: &'a CircuitBreaker<C>,
+    gen: u64,
+    failed: bool,
+}
+
+impl<C> Drop for Permit<'_, C> {
+    fn drop(&mut self) {
+        self.breaker.record(self.gen, self.failed);
+    }
+}
+
+/// the status error is only known after decoding the response
+fn is_status(frame: &Frame) -> bool {
+    matches!(frame.decode_rsp(), Err(Error::Status(_)))
+}
+
+impl<C: Client> Client for CircuitBreaker<C> {
+    fn call_service(&self, req: ReqBuf) -> Result<Frame, Error> {
+        self.call(|c| c.call_service(req), is_status)
+    }
+
+    fn call_idempotent(&self, req: ReqBuf) -> Result<Frame, Error> {
+        self.call(|c| c.call_idempotent(req), is_status)
+    }
+
+    fn call_oneway(&self, req: ReqBuf) -> Result<(), Error> {
+        self.call(|c| c.call_oneway(req), |_| false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+    /// a client that fails the calls with a timeout when `fail` is set
+    #[derive(Default)]
+    struct Fake {
+        fail: AtomicBool,
+        calls: AtomicUsize,
+    }
+
+    impl Client for Fake {
+        fn call_service(&self, _req: ReqBuf) -> Result<Frame, Error> {
+            unreachable!()
+        }
+
+        fn call_oneway(&self, _req: ReqBuf) -> Result<(), Error> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match self.fail.load(Ordering::Relaxed) {
+                true => Err(Error::Timeout),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn breaker(half_open_calls: usize) -> CircuitBreaker<Fake> {
+        let mut policy = CircuitPolicy::new();
+        policy.set_failure_rate(0.5);
+        policy.set_min_calls(4);
+        policy.set_open_timeout(Duration::from_millis(50));
+        policy.set_half_open_calls(half_open_calls);
+        CircuitBreaker::new(Fake::default(), policy)
+    }
+
+    fn call(b: &CircuitBreaker<Fake>, fail: bool) -> Result<(), Error> {
+        b.get_ref().fail.store(fail, Ordering::Relaxed);
+        b.call_oneway(ReqBuf::new())
+    }
+
+    /// open the circuit by 4 failed calls
+    fn open(b: &CircuitBreaker<Fake>) {
+        for _ in 0..4 {
+            assert!(matches!(call(b, true), Err(Error::Timeout)));
+        }
+        assert_eq!(b.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn open_at_failure_rate() {
+        let b = breaker(1);
+        // the rate is not checked before the min calls
+        call(&b, false).unwrap();
+        call(&b, true).unwrap_err();
+        call(&b, true).unwrap_err();
+        assert_eq!(b.state(), CircuitState::Closed);
+        // a success never opens the circuit
+        call(&b, false).unwrap();
+        assert_eq!(b.state(), CircuitState::Closed);
+        // 3 of 5 calls failed
+        call(&b, true).unwrap_err();
+        assert_eq!(b.state(), CircuitState::Open);
+        assert_eq!(b.stats().opened, 1);
+    }
+
+    #[test]
+    fn fail_fast_when_open() {
+        let b = breaker(1);
+        open(&b);
+        for _ in 0..3 {
+            assert!(matches!(call(&b, false), Err(Error::CircuitOpen)));
+        }
+        assert_eq!(b.get_ref().calls.load(Ordering::Relaxed), 4);
+        assert_eq!(b.stats().rejected, 3);
+    }
+
+    #[test]
+    fn half_open_after_timeout() {
+        let b = breaker(2);
+        open(&b);
+        may::coroutine::sleep(Duration::from_millis(60));
+        // the state is only changed by the next call
+        assert_eq!(b.state(), CircuitState::Open);
+        call(&b, false).unwrap();
+        assert_eq!(b.state(), CircuitState::HalfOpen);
+        call(&b, false).unwrap();
+        assert_eq!(b.state(), CircuitState::Closed);
+        let stats = b.stats();
+        assert_eq!((stats.opened, stats.half_opened, stats.closed), (1, 1, 1));
+    }
+
+    #[test]
+    fn half_open_probe_limit() {
+        let b = breaker(2);
+        open(&b);
+        may::coroutine::sleep(Duration::from_millis(60));
+        let gen = b.acquire().unwrap();
+        assert_eq!(b.acquire().unwrap(), gen);
+        assert!(matches!(b.acquire(), Err(Error::CircuitOpen)));
+        assert_eq!(b.stats().rejected, 1);
+        // the probes are done
+        b.record(gen, false);
+        b.record(gen, false);
+        assert_eq!(b.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_to_open() {
+        let b = breaker(2);
+        open(&b);
+        may::coroutine::sleep(Duration::from_millis(60));
+        call(&b, false).unwrap();
+        call(&b, true).unwrap_err();
+        assert_eq!(b.state(), CircuitState::Open);
+        assert!(matches!(call(&b, false), Err(Error::CircuitOpen)));
+        let stats = b.stats();
+        assert_eq!((stats.opened, stats.half_opened, stats.closed), (2, 1, 0));
+    }
+
+    #[test]
+    fn ignore_stale_gen() {
+        let b = breaker(1);
+        // a call sent when closed is done after the circuit is opened
+        let stale = b.acquire().unwrap();
+        open(&b);
+        may::coroutine::sleep(Duration::from_millis(60));
+        let gen = b.acquire().unwrap();
+        assert_ne!(gen, stale);
+        b.record(stale, true);
+        assert_eq!(b.state(), CircuitState::HalfOpen);
+        b.record(stale, false);
+        assert_eq!(b.state(), CircuitState::HalfOpen);
+        b.record(gen, false);
+        assert_eq!(b.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn state_change_hook() {
+        let mut b = breaker(1);
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let c = changes.clone();
+        b.on_state_change(move |from, to| c.lock().unwrap().push((from, to)));
+        open(&b);
+        may::coroutine::sleep(Duration::from_millis(60));
+        call(&b, true).unwrap_err();
+        may::coroutine::sleep(Duration::from_millis(60));
+        call(&b, false).unwrap();
+        use CircuitState::*;
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                (Closed, Open),
+                (Open, HalfOpen),
+                (HalfOpen, Open),
+                (Open, HalfOpen),
+                (HalfOpen, Closed)
+            ]
+        );
+    }
+}
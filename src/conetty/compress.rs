@@ -0,0 +1,216 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "lz4")]
+use super::handshake::CAP_LZ4;
+#[cfg(feature = "zstd")]
+use super::handshake::CAP_ZSTD;
+
+/// payload compression algorithms, enabled by the cargo features of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// lz4 block compression, fast with a moderate ratio
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// zstd compression with the given level, better ratio with more cpu
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// the capability bit used in the connection preface
+    pub(crate) fn cap(self) -> u32 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => CAP_LZ4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => CAP_ZSTD,
+        }
+    }
+
+    /// the algorithm id that is written into the compressed payload
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 1,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => 2,
+        }
+    }
+
+    /// get the compression from the capabilities
+    /// the client asks for at most one algorithm
+    pub(crate) fn from_caps(caps: u32) -> Option<Self> {
+        #[cfg(feature = "lz4")]
+        if caps & CAP_LZ4 != 0 {
+            return Some(Compression::Lz4);
+        }
+        #[cfg(feature = "zstd")]
+        if caps & CAP_ZSTD != 0 {
+            return Some(Compression::Zstd(DEFAULT_ZSTD_LEVEL));
+        }
+        let _ = caps;
+        None
+    }
+
+    /// the capabilities of all the supported algorithms
+    pub(crate) fn supported_caps() -> u32 {
+        let caps = 0;
+        #[cfg(feature = "lz4")]
+        let caps = caps | CAP_LZ4;
+        #[cfg(feature = "zstd")]
+        let caps = caps | CAP_ZSTD;
+        caps
+    }
+
+    /// compress the data
+    #[cfg(not(any(feature = "lz4", feature = "zstd")))]
+    pub(crate) fn compress(self, _data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {}
+    }
+
+    /// compress the data
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub(crate) fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let start = Instant::now();
+        let out = match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::block::compress(data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => zstd::bulk::compress(data, level)?,
+        };
+        STATS.compressed(data.len(), out.len(), start.elapsed());
+        Ok(out)
+    }
+}
+
+/// the zstd level used by the server, the client level is not negotiated
+#[cfg(feature = "zstd")]
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// decompress the data that is compressed by the algorithm `id`
+/// the decompressed data must be exactly `len` bytes
+pub(crate) fn decompress(id: u8, data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let start = Instant::now();
+    let out: Vec<u8> = match id {
+        #[cfg(feature = "lz4")]
+        1 => lz4_flex::block::decompress(data, len)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        #[cfg(feature = "zstd")]
+        2 => zstd::bulk::decompress(data, len),
+        _ => {
+            let s = format!("unsupported compression algorithm: {id}");
+            Err(io::Error::new(io::ErrorKind::InvalidData, s))
+        }
+    }?;
+    if out.len() != len {
+        let s = format!(
+            "decompressed len mismatch: len={}, expected={len}",
+            out.len()
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, s));
+    }
+    STATS.decompressed(len, data.len(), start.elapsed());
+    Ok(out)
+}
+
+/// the process wide compression counters
+struct Stats {
+    compressed_frames: AtomicU64,
+    compress_in: AtomicU64,
+    compress_out: AtomicU64,
+    compress_nanos: AtomicU64,
+    decompressed_frames: AtomicU64,
+    decompress_in: AtomicU64,
+    decompress_out: AtomicU64,
+    decompress_nanos: AtomicU64,
+}
+
+static STATS: Stats = Stats {
+    compressed_frames: AtomicU64::new(0),
+    compress_in: AtomicU64::new(0),
+    compress_out: AtomicU64::new(0),
+    compress_nanos: AtomicU64::new(0),
+    decompressed_frames: AtomicU64::new(0),
+    decompress_in: AtomicU64::new(0),
+    decompress_out: AtomicU64::new(0),
+    decompress_nanos: AtomicU64::new(0),
+};
+
+impl Stats {
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn compressed(&self, raw: usize, compressed: usize, cost: Duration) {
+        self.compressed_frames.fetch_add(1, Ordering::Relaxed);
+        self.compress_in.fetch_add(raw as u64, Ordering::Relaxed);
+        self.compress_out
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+        let nanos = cost.as_nanos() as u64;
+        self.compress_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn decompressed(&self, raw: usize, compressed: usize, cost: Duration) {
+        self.decompressed_frames.fetch_add(1, Ordering::Relaxed);
+        self.decompress_in
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+        self.decompress_out.fetch_add(raw as u64, Ordering::Relaxed);
+        let nanos = cost.as_nanos() as u64;
+        self.decompress_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// a snapshot of the process wide compression counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompressionStats {
+    /// number of the compressed messages that are sent
+    pub compressed_frames: u64,
+    /// total bytes before compression
+    pub compress_in: u64,
+    /// total bytes after compression
+    pub compress_out: u64,
+    /// total cpu time spent on compression
+    pub compress_time: Duration,
+    /// number of the compressed messages that are received
+    pub decompressed_frames: u64,
+    /// total bytes before decompression
+    pub decompress_in: u64,
+    /// total bytes after decompression
+    pub decompress_out: u64,
+    /// total cpu time spent on decompression
+    pub decompress_time: Duration,
+}
+
+impl CompressionStats {
+    /// get the current counters
+    pub fn get() -> Self {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        CompressionStats {
+            compressed_frames: load(&STATS.compressed_frames),
+            compress_in: load(&STATS.compress_in),
+            compress_out: load(&STATS.compress_out),
+            compress_time: Duration::from_nanos(load(&STATS.compress_nanos)),
+            decompressed_frames: load(&STATS.decompressed_frames),
+            decompress_in: load(&STATS.decompress_in),
+            decompress_out: load(&STATS.decompress_out),
+            decompress_time: Duration::from_nanos(load(&STATS.decompress_nanos)),
+        }
+    }
+
+    /// the compressed size over the raw size of the sent messages
+    pub fn compress_ratio(&self) -> f64 {
+        ratio(self.compress_out, self.compress_in)
+    }
+
+    /// the compressed size over the raw size of the received messages
+    pub fn decompress_ratio(&self) -> f64 {
+        ratio(self.decompress_in, self.decompress_out)
+    }
+}
+
+fn ratio(compressed: u64, raw: u64) -> f64 {
+    if raw == 0 {
+        return 1.0;
+    }
+    compressed as f64 / raw as f64
+}
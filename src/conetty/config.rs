@@ -0,0 +1,435 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::compress::Compression;
+use super::context::{ConnState, Reject};
+use super::frame::{RspBuf, DEFAULT_MAX_FRAME_LEN, DEFAULT_MAX_MESSAGE_LEN};
+use super::frame::{DEFAULT_MAX_PARTIAL_LEN, DEFAULT_MAX_PARTIAL_MESSAGES};
+use super::handshake::DEFAULT_HANDSHAKE_TIMEOUT;
+use super::keepalive::KeepAlive;
+use super::reply_cache::ReplyCache;
+use super::retry::RetryPolicy;
+use super::server::{DisconnectReason, Peer};
+use super::{Server, WireError};
+
+/// the default min payload len to compress
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+type ConnectHook = Arc<dyn Fn(&Peer) -> Result<ConnState, Reject> + Send + Sync>;
+type DisconnectHook = Arc<dyn Fn(&Peer, &DisconnectReason) + Send + Sync>;
+pub(crate) type Callback = Arc<dyn Fn(&[u8], &mut RspBuf) -> Result<(), WireError> + Send + Sync>;
+
+/// configuration for the stream servers
+#[derive(Clone, Default)]
+pub struct ServerConfig {
+    // heartbeat and idle timeout settings
+    keepalive: KeepAlive,
+    // the max time to wait for the client preface
+    handshake_timeout: Option<Duration>,
+    // the max time to wait the outstanding requests after GOAWAY
+    goaway_timeout: Option<Duration>,
+    // send GOAWAY when the connection is older than this
+    max_conn_age: Option<Duration>,
+    // refuse the checksum requested by the clients
+    no_checksum: bool,
+    // refuse the compression requested by the clients
+    no_compression: bool,
+    // refuse the compact frame header requested by the clients
+    no_compact_header: bool,
+    // the min payload len of a response to be compressed
+    compression_threshold: Option<usize>,
+    // the max payload len of a frame
+    max_frame_len: Option<usize>,
+    // the max payload len of a message that is split into frames
+    max_message_len: Option<usize>,
+    // the max number and total len of the messages that are being reassembled
+    max_partial: Option<(usize, usize)>,
+    // invoked when a connection is accepted
+    on_connect: Option<ConnectHook>,
+    // invoked when a connection is closed
+    on_disconnect: Option<DisconnectHook>,
+    // the replies of the keyed requests
+    reply_cache: Option<Arc<ReplyCache>>,
+}
+
+impl fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("keepalive", &self.keepalive)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("goaway_timeout", &self.goaway_timeout)
+            .field("max_conn_age", &self.max_conn_age)
+            .field("checksum", &!self.no_checksum)
+            .field("compression", &!self.no_compression)
+            .field("compact_header", &!self.no_compact_header)
+            .field("compression_threshold", &self.compression_threshold)
+            .field("max_frame_len", &self.max_frame_len)
+            .field("max_message_len", &self.max_message_len)
+            .field("max_partial", &self.max_partial)
+            .field("on_connect", &self.on_connect.is_some())
+            .field("on_disconnect", &self.on_disconnect.is_some())
+            .field("reply_cache", &self.reply_cache)
+            .finish()
+    }
+}
+
+impl ServerConfig {
+    /// create a default server config
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// send a ping frame to the client every `interval`
+    /// the connection is closed if `max_missed` pings in a row are not answered
+    /// the client must be able to answer pings, e.g. a `MultiplexClient`
+    pub fn set_heartbeat(&mut self, interval: Duration, max_missed: usize) {
+        self.keepalive.heartbeat = Some(interval);
+        self.keepalive.max_missed = max_missed.max(1);
+    }
+
+    /// close the connection if there is no request for longer than `timeout`
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.keepalive.idle_timeout = Some(timeout);
+    }
+
+    /// set the max time to wait for the connection preface from the client
+    /// the connection is closed when the timeout expired, the default value is 10 seconds
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = Some(timeout);
+    }
+
+    /// set the max time to wait for the outstanding requests after sending GOAWAY
+    /// the connection is closed when the timeout expired, the default value is 10 seconds
+    pub fn set_goaway_timeout(&mut self, timeout: Duration) {
+        self.goaway_timeout = Some(timeout);
+    }
+
+    /// send GOAWAY to the client once the connection is older than `age`
+    /// this is useful to rebalance the clients between servers
+    pub fn set_max_conn_age(&mut self, age: Duration) {
+        self.max_conn_age = Some(age);
+    }
+
+    /// allow the clients to enable crc32c checksum for the connection
+    /// the checksum is enabled only if the client asks for it, the default value is true
+    pub fn set_checksum(&mut self, enable: bool) {
+        self.no_checksum = !enable;
+    }
+
+    /// allow the clients to enable compression for the connection
+    /// the compression is enabled only if the client asks for a supported algorithm
+    /// the default value is true
+    pub fn set_compression(&mut self, enable: bool) {
+        self.no_compression = !enable;
+    }
+
+    /// allow the clients to enable the compact frame header for the connection
+    /// the compact header is enabled only if the client asks for it, the default value is true
+    pub fn set_compact_header(&mut self, enable: bool) {
+        self.no_compact_header = !enable;
+    }
+
+    /// only compress the responses that are not smaller than `threshold`
+    /// the default value is 1 KiB
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = Some(threshold);
+    }
+
+    /// set the max payload len of a frame, the default value is 1 MiB
+    /// a too large request is replied with a status error
+    /// and a too large response is replaced by a status error
+    pub fn set_max_frame_len(&mut self, len: usize) {
+        self.max_frame_len = Some(len);
+    }
+
+    /// set the max payload len of a request or response, the default value is 16 MiB
+    /// messages larger than a frame are split into continuation frames
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = Some(len);
+    }
+
+    /// limit the messages that are being reassembled from the continuation frames
+    /// at most `count` of them and `len` bytes in total are buffered for a connection,
+    /// the connection is closed if the peer exceeds it. the default values are 64 and 64 MiB
+    pub fn set_max_partial_messages(&mut self, count: usize, len: usize) {
+        self.max_partial = Some((count, len));
+    }
+
+    /// set the callback that would be invoked when a connection is accepted
+    /// the returned state can be accessed by `ReqContext::state` for each request
+    /// of the connection, return an `Err(Reject)` would close the connection
+    pub fn on_connect<F>(&mut self, f: F)
+    where
+        F: Fn(&Peer) -> Result<ConnState, Reject> + Send + Sync + 'static,
+    {
+        self.on_connect = Some(Arc::new(f));
+    }
+
+    /// set the callback that would be invoked when a connection is closed
+    /// the outstanding requests of the connection are already cancelled at that time
+    pub fn on_disconnect<F>(&mut self, f: F)
+    where
+        F: Fn(&Peer, &DisconnectReason) + Send + Sync + 'static,
+    {
+        self.on_disconnect = Some(Arc::new(f));
+    }
+
+    /// cache the replies of the requests that carry an idempotency key
+    /// a retried request gets the reply of the first one instead of running again
+    /// the replies are kept for `ttl` and take at most `max_bytes` of memory,
+    /// the oldest ones are evicted first. the cache is shared by all the connections
+    pub fn set_reply_cache(&mut self, ttl: Duration, max_bytes: usize) {
+        self.reply_cache = Some(Arc::new(ReplyCache::new(ttl, max_bytes)));
+    }
+
+    pub(crate) fn keepalive(&self) -> &KeepAlive {
+        &self.keepalive
+    }
+
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT)
+    }
+
+    pub(crate) fn goaway_timeout(&self) -> Duration {
+        self.goaway_timeout.unwrap_or(Duration::from_secs(10))
+    }
+
+    pub(crate) fn max_conn_age(&self) -> Option<Duration> {
+        self.max_conn_age
+    }
+
+    pub(crate) fn checksum(&self) -> bool {
+        !self.no_checksum
+    }
+
+    pub(crate) fn compression(&self) -> bool {
+        !self.no_compression
+    }
+
+    pub(crate) fn compact_header(&self) -> bool {
+        !self.no_compact_header
+    }
+
+    pub(crate) fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+            .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD)
+    }
+
+    pub(crate) fn max_frame_len(&self) -> usize {
+        self.max_frame_len.unwrap_or(DEFAULT_MAX_FRAME_LEN)
+    }
+
+    pub(crate) fn max_message_len(&self) -> usize {
+        self.max_message_len.unwrap_or(DEFAULT_MAX_MESSAGE_LEN)
+    }
+
+    pub(crate) fn max_partial_messages(&self) -> (usize, usize) {
+        self.max_partial
+            .unwrap_or((DEFAULT_MAX_PARTIAL_MESSAGES, DEFAULT_MAX_PARTIAL_LEN))
+    }
+
+    pub(crate) fn reply_cache(&self) -> Option<&Arc<ReplyCache>> {
+        self.reply_cache.as_ref()
+    }
+
+    pub(crate) fn connected(&self, peer: &Peer) -> Result<ConnState, Reject> {
+        match self.on_connect.as_ref() {
+            Some(f) => f(peer),
+            None => Ok(ConnState::default()),
+        }
+    }
+
+    pub(crate) fn disconnected(&self, peer: &Peer, reason: &DisconnectReason) {
+        if let Some(f) = self.on_disconnect.as_ref() {
+            f(peer, reason);
+        }
+    }
+}
+
+/// configuration for the multiplexed client
+#[derive(Clone, Default)]
+pub struct ClientConfig {
+    // the default timeout of each call
+    timeout: Option<Duration>,
+    // heartbeat and idle timeout settings
+    keepalive: KeepAlive,
+    // the max time to wait for the server preface
+    handshake_timeout: Option<Duration>,
+    // ask the server to enable crc32c checksum
+    checksum: bool,
+    // ask the server to enable compression, and the min payload len to compress
+    compression: Option<(Compression, usize)>,
+    // ask the server to enable the compact frame header
+    compact_header: bool,
+    // the max payload len of a frame
+    max_frame_len: Option<usize>,
+    // the max payload len of a message that is split into frames
+    max_message_len: Option<usize>,
+    // the max number and total len of the messages that are being reassembled
+    max_partial: Option<(usize, usize)>,
+    // serve the reverse calls from the server
+    callback: Option<Callback>,
+    // retry the failed calls
+    retry_policy: Option<RetryPolicy>,
+    // attach an idempotency key to the non idempotent calls
+    idempotency_key: bool,
+}
+
+impl fmt::Debug for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientConfig")
+            .field("timeout", &self.timeout)
+            .field("keepalive", &self.keepalive)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("checksum", &self.checksum)
+            .field("compression", &self.compression)
+            .field("compact_header", &self.compact_header)
+            .field("max_frame_len", &self.max_frame_len)
+            .field("max_message_len", &self.max_message_len)
+            .field("max_partial", &self.max_partial)
+            .field("callback", &self.callback.is_some())
+            .field("retry_policy", &self.retry_policy)
+            .field("idempotency_key", &self.idempotency_key)
+            .finish()
+    }
+}
+
+impl ClientConfig {
+    /// create a default client config
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set the default timeout value of each call
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// send a ping frame to the server every `interval`
+    /// the connection is closed if `max_missed` pings in a row are not answered
+    /// and all the outstanding requests would fail
+    pub fn set_heartbeat(&mut self, interval: Duration, max_missed: usize) {
+        self.keepalive.heartbeat = Some(interval);
+        self.keepalive.max_missed = max_missed.max(1);
+    }
+
+    /// close the connection if there is no request for longer than `timeout`
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.keepalive.idle_timeout = Some(timeout);
+    }
+
+    /// set the max time to wait for the connection preface from the server
+    /// the connecting fails when the timeout expired, the default value is 10 seconds
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = Some(timeout);
+    }
+
+    /// ask the server to protect all the frames of the connection by crc32c checksum
+    /// the checksum is enabled only if the server accepts it, the default value is false
+    pub fn set_checksum(&mut self, enable: bool) {
+        self.checksum = enable;
+    }
+
+    /// ask the server to compress the payloads that are not smaller than `threshold`
+    /// the compression is enabled only if the server supports the algorithm
+    pub fn set_compression(&mut self, compression: Compression, threshold: usize) {
+        self.compression = Some((compression, threshold));
+    }
+
+    /// ask the server to encode the frame header with varint id and len
+    /// this saves most of the 16 bytes header for small frames
+    /// the compact header is enabled only if the server accepts it, the default value is false
+    pub fn set_compact_header(&mut self, enable: bool) {
+        self.compact_header = enable;
+    }
+
+    /// set the max payload len of a frame, the default value is 1 MiB
+    /// a too large request or response would fail the call with `Error::FrameTooLarge`
+    pub fn set_max_frame_len(&mut self, len: usize) {
+        self.max_frame_len = Some(len);
+    }
+
+    /// set the max payload len of a request or response, the default value is 16 MiB
+    /// messages larger than a frame are split into continuation frames
+    /// a too large message would fail the call with `Error::FrameTooLarge`
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = Some(len);
+    }
+
+    /// limit the messages that are being reassembled from the continuation frames
+    /// at most `count` of them and `len` bytes in total are buffered for a connection,
+    /// the connection is closed if the peer exceeds it. the default values are 64 and 64 MiB
+    pub fn set_max_partial_messages(&mut self, count: usize, len: usize) {
+        self.max_partial = Some((count, len));
+    }
+
+    /// serve the reverse calls from the server by the callback service
+    /// the server calls it through the client stub that is obtained from `ReqContext::reverse`
+    /// without the client listening on any port
+    pub fn set_callback<T: Server>(&mut self, service: T) {
+        self.callback = Some(Arc::new(move |req, rsp| service.service(req, rsp)));
+    }
+
+    /// retry the failed calls by the policy, the calls are not retried by default
+    /// except that the requests refused by a going away server are resent after reconnecting
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = Some(policy);
+    }
+
+    /// attach an idempotency key to each call of the methods that are not `#[idempotent]`
+    /// so that they can be retried like the idempotent ones, the server must enable the
+    /// reply cache by `ServerConfig::set_reply_cache`, the default value is false
+    pub fn set_idempotency_key(&mut self, enable: bool) {
+        self.idempotency_key = enable;
+    }
+
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn keepalive(&self) -> &KeepAlive {
+        &self.keepalive
+    }
+
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT)
+    }
+
+    pub(crate) fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    pub(crate) fn compression(&self) -> Option<(Compression, usize)> {
+        self.compression
+    }
+
+    pub(crate) fn compact_header(&self) -> bool {
+        self.compact_header
+    }
+
+    pub(crate) fn max_frame_len(&self) -> usize {
+        self.max_frame_len.unwrap_or(DEFAULT_MAX_FRAME_LEN)
+    }
+
+    pub(crate) fn max_message_len(&self) -> usize {
+        self.max_message_len.unwrap_or(DEFAULT_MAX_MESSAGE_LEN)
+    }
+
+    pub(crate) fn max_partial_messages(&self) -> (usize, usize) {
+        self.max_partial
+            .unwrap_or((DEFAULT_MAX_PARTIAL_MESSAGES, DEFAULT_MAX_PARTIAL_LEN))
+    }
+
+    pub(crate) fn callback(&self) -> Option<&Callback> {
+        self.callback.as_ref()
+    }
+
+    pub(crate) fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    pub(crate) fn idempotency_key(&self) -> bool {
+        self.idempotency_key
+    }
+}
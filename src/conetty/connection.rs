@@ -0,0 +1,852 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::batch;
+use super::compress::Compression;
+use super::config::ServerConfig;
+use super::context::ReqContext;
+use super::errors::{as_too_large, is_checksum_err, Error, FrameTooLarge};
+use super::frame::{add_frame_flags, RspBuf, FLAG_REVERSE};
+use super::frame::{compact_header, compress_frame};
+use super::frame::{seal_checksum, split_frame, Assembled, Assembler, Control, Decoder, Frame};
+use super::handshake::{server_handshake, Settings};
+use super::keepalive::{Expired, Liveness};
+use super::queued_writer::QueuedWriter;
+use super::reverse::{ReverseClient, ReverseConn};
+use super::server::{DisconnectReason, Peer};
+use super::stream_ext::StreamExt;
+use super::streaming::{send_items, Remote, Sent, StreamConn, Window};
+use crate::{Server, WireError};
+
+use bytes::BytesMut;
+use may::sync::{mpsc, Mutex};
+use may::{coroutine, go};
+use may_waiter::TokenWaiter;
+
+type RspWaiter = TokenWaiter<io::Result<Frame>>;
+
+#[derive(Default)]
+struct OnewayCounters {
+    calls: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl OnewayCounters {
+    /// log and count the failed oneway request
+    fn failed(&self, id: u64, err: &WireError, peer: &Peer) {
+        error!("oneway request failed: id={id}, err={err:?}, peer={peer}");
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// a snapshot of the oneway call counters of a server
+/// the failures of a oneway call are never sent back, they are only logged and counted here
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OnewayStats {
+    /// number of the oneway requests that are received
+    pub calls: u64,
+    /// number of the oneway requests that are failed
+    pub failed: u64,
+}
+
+/// a connection that can be asked to go away
+trait GoAway: Send + Sync {
+    /// send GOAWAY to the peer and close the connection after the outstanding requests are done
+    fn go_away(self: Arc<Self>);
+}
+
+/// the shared state of all the connections of a stream server
+pub(crate) struct Conns {
+    config: ServerConfig,
+    next_key: AtomicUsize,
+    // the live connections
+    map: Mutex<HashMap<usize, Arc<dyn GoAway>>>,
+    // the oneway requests of all the connections
+    oneway: Arc<OnewayCounters>,
+}
+
+impl Conns {
+    pub fn new(config: ServerConfig) -> Self {
+        Conns {
+            config,
+            next_key: AtomicUsize::new(0),
+            map: Mutex::new(HashMap::new()),
+            oneway: Arc::default(),
+        }
+    }
+
+    /// get the current oneway counters of the server
+    pub fn oneway_stats(&self) -> OnewayStats {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        OnewayStats {
+            calls: load(&self.oneway.calls),
+            failed: load(&self.oneway.failed),
+        }
+    }
+
+    fn add(&self, conn: Arc<dyn GoAway>) -> usize {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        self.map.lock().unwrap().insert(key, conn);
+        key
+    }
+
+    fn remove(&self, key: usize) {
+        self.map.lock().unwrap().remove(&key);
+    }
+
+    /// send GOAWAY to all the live connections
+    pub fn go_away_all(&self) {
+        let conns: Vec<_> = self.map.lock().unwrap().values().cloned().collect();
+        conns.into_iter().for_each(GoAway::go_away);
+    }
+
+    /// wait until all the connections are closed
+    pub fn wait_closed(&self) {
+        while !self.map.lock().unwrap().is_empty() {
+            coroutine::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// remove the connection from the live connections when dropped
+struct ConnGuard<'a>(&'a Conns, usize);
+
+impl Drop for ConnGuard<'_> {
+    fn drop(&mut self) {
+        self.0.remove(self.1);
+    }
+}
+
+/// the running coroutines of a connection
+#[derive(Default)]
+struct Running {
+    // the key of the next coroutine
+    next_key: u64,
+    coroutines: HashMap<u64, coroutine::Coroutine>,
+    // no more coroutines are allowed to run
+    closed: bool,
+}
+
+/// the request coroutines of a connection, they are cancelled when dropped
+/// each coroutine registers itself once it starts running, this only takes the lock
+/// of the connection instead of a shared registration
+#[derive(Default)]
+struct Reqs(Arc<Mutex<Running>>);
+
+impl Reqs {
+    /// run `f` in a new coroutine that is cancelled with the connection
+    fn add<F: FnOnce() + Send + 'static>(&self, f: F) {
+        let running = self.0.clone();
+        go!(move || {
+            let key = {
+                let mut running = running.lock().unwrap();
+                if running.closed {
+                    return;
+                }
+                let key = running.next_key;
+                running.next_key += 1;
+                running.coroutines.insert(key, coroutine::current());
+                key
+            };
+            let _guard = RunningGuard(&running, key);
+            f();
+        });
+    }
+}
+
+impl Drop for Reqs {
+    fn drop(&mut self) {
+        {
+            let mut running = self.0.lock().unwrap();
+            running.closed = true;
+            for co in running.coroutines.values() {
+                unsafe { co.cancel() };
+            }
+        }
+        // a cancelled coroutine can't wait for others, they would exit by themselves
+        if std::thread::panicking() {
+            return;
+        }
+        while !self.0.lock().unwrap().coroutines.is_empty() {
+            coroutine::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// remove the coroutine from the running ones once it's done
+struct RunningGuard<'a>(&'a Mutex<Running>, u64);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().coroutines.remove(&self.1);
+    }
+}
+
+/// serve a single stream connection until it's closed
+pub(crate) fn serve_conn<S, T, F>(new_service: &F, conns: &Conns, stream: S, peer: Peer)
+where
+    S: StreamExt,
+    T: Server,
+    F: Fn(&Peer) -> Arc<T>,
+{
+    let config = &conns.config;
+    let state = match config.connected(&peer) {
+        Ok(state) => state,
+        Err(reject) => {
+            info!("{reject}, peer={peer}");
+            stream.shutdown().ok();
+            return;
+        }
+    };
+    // the service that serves all the requests of the connection
+    let server = new_service(&peer);
+    let (rs, ctrl) = match stream
+        .try_clone()
+        .and_then(|s| Ok((s, stream.try_clone()?)))
+    {
+        Ok(s) => s,
+        Err(e) => {
+            error!("failed to clone stream: err = {e:?}");
+            config.disconnected(&peer, &DisconnectReason::Read(e));
+            return;
+        }
+    };
+    // the read half of the stream
+    let mut rs = BufReader::new(rs);
+    let mut stream = stream;
+    let settings = match server_handshake(&ctrl, &mut rs, &mut stream, config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("server handshake: err = {e}, peer={peer}");
+            stream.shutdown().ok();
+            config.disconnected(&peer, &DisconnectReason::Handshake(e));
+            return;
+        }
+    };
+    // the write half of the stream
+    let conn = Arc::new(Connection::new(stream, ctrl, config, settings));
+    let reverse = settings.reverse.then(|| {
+        let conn: Arc<dyn ReverseConn> = conn.clone();
+        ReverseClient::new(Arc::downgrade(&conn))
+    });
+    let ctx = ReqContext::new(peer, state, reverse);
+    let _guard = ConnGuard(conns, conns.add(conn.clone()));
+    // outstanding requests, they are cancelled once the connection is closed
+    let reqs = Reqs::default();
+    let keepalive = *config.keepalive();
+    if keepalive.enabled() {
+        let conn = conn.clone();
+        reqs.add(move || {
+            let expired = keepalive.run(&conn.live, |id| conn.write(Control::Ping.encode(id, &[])));
+            info!("server connection expired: {expired:?}");
+            conn.close(match expired {
+                Expired::Idle => DisconnectReason::IdleTimeout,
+                Expired::Heartbeat => DisconnectReason::HeartbeatTimeout,
+            });
+        });
+    }
+    if let Some(age) = config.max_conn_age() {
+        let conn = conn.clone();
+        reqs.add(move || {
+            coroutine::sleep(age);
+            info!("server connection reach the max age");
+            conn.go_away();
+        });
+    }
+    let max_len = config.max_frame_len();
+    let max_msg_len = config.max_message_len();
+    let reply_cache = config.reply_cache();
+    let mut buf = BytesMut::with_capacity(1024 * 32);
+    let mut decoder = Decoder::new(max_len, settings);
+    let (max_parts, max_partial_len) = config.max_partial_messages();
+    let mut assembler = Assembler::with_limits(max_msg_len, max_parts, max_partial_len);
+    // the responses of the reverse calls, their ids are assigned by the server
+    let mut reverse_assembler = Assembler::with_limits(max_msg_len, max_parts, max_partial_len);
+    loop {
+        let req = match decoder.decode(&mut rs, &mut buf) {
+            Ok(r) => r,
+            Err(e) if as_too_large(&e).is_some() => {
+                let too_large = as_too_large(&e).unwrap();
+                if let Err(e) = decoder.skip(&mut rs, too_large) {
+                    info!("server skip req: err = {e:?}, peer={}", ctx.peer());
+                    conn.close(DisconnectReason::Read(e));
+                    break;
+                }
+                if too_large.control {
+                    continue;
+                }
+                // an uploaded item of an outstanding streaming call, the handler is still
+                // running and would reply the call, only fail its upload
+                if conn.is_streaming(too_large.id) {
+                    conn.fail_upload(too_large.id, e);
+                    continue;
+                }
+                // reply the too large request with a status error
+                if conn.dispatch(too_large.id) {
+                    let FrameTooLarge { len, max, .. } = too_large;
+                    let status = format!("request frame too large: len={len}, max={max}");
+                    let status = WireError::Status(status);
+                    let data = RspBuf::new().finish_limited(too_large.id, Err(status), max_len);
+                    conn.write(data);
+                    conn.live.end();
+                }
+                continue;
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    info!("server decode req: connection closed, peer={}", ctx.peer());
+                    conn.close(DisconnectReason::Closed);
+                } else if is_checksum_err(&e) {
+                    error!("server decode req: checksum failed, peer={}", ctx.peer());
+                    conn.close(DisconnectReason::Checksum);
+                } else {
+                    // a failed write would shutdown the stream, keep the original reason
+                    info!("server decode req: err = {e:?}, peer={}", ctx.peer());
+                    conn.close(DisconnectReason::Read(e));
+                }
+                break;
+            }
+        };
+
+        if req.is_control() {
+            match req.decode_control() {
+                Some((Control::Ping, body)) => conn.write(Control::Pong.encode(req.id, body)),
+                Some((Control::Pong, _)) => conn.live.pong(),
+                Some((Control::Credit, body)) => match (conn.stream(req.id), body.try_into()) {
+                    (Some(window), Ok(n)) => window.grant(u32::from_be_bytes(n)),
+                    _ => info!("ignore stream credit: id={}", req.id),
+                },
+                Some((Control::Cancel, _)) => conn.cancel_stream(req.id),
+                _ => info!("ignore unexpected control frame: id={}", req.id),
+            }
+            continue;
+        }
+
+        if req.is_reverse() {
+            let (id, rsp) = match reverse_assembler.push(req) {
+                Ok(Assembled::Done(rsp)) => (rsp.id, rsp.decompress(max_msg_len)),
+                Ok(Assembled::Partial | Assembled::Discarded) => continue,
+                Ok(Assembled::TooLarge { id, len }) => {
+                    let len = len as u64;
+                    let max = max_msg_len;
+                    let e = FrameTooLarge {
+                        id,
+                        control: false,
+                        len,
+                        max,
+                    };
+                    (id, Err(e.into()))
+                }
+                Err(e) => {
+                    error!("server assemble rsp: err = {e}, peer={}", ctx.peer());
+                    conn.close(DisconnectReason::Read(e));
+                    break;
+                }
+            };
+            conn.set_reverse_rsp(id, rsp);
+            continue;
+        }
+
+        if req.is_stream() {
+            // the items that are uploaded to an outstanding streaming call
+            if conn.is_streaming(req.id) {
+                let (id, item) = match assembler.push(req) {
+                    Ok(Assembled::Done(item)) => (item.id, item.decompress(max_msg_len)),
+                    Ok(Assembled::Partial | Assembled::Discarded) => continue,
+                    Ok(Assembled::TooLarge { id, len }) => {
+                        let len = len as u64;
+                        let max = max_msg_len;
+                        let e = FrameTooLarge {
+                            id,
+                            control: false,
+                            len,
+                            max,
+                        };
+                        (id, Err(e.into()))
+                    }
+                    Err(e) => {
+                        error!("server assemble req: err = {e}, peer={}", ctx.peer());
+                        conn.close(DisconnectReason::Read(e));
+                        break;
+                    }
+                };
+                conn.push_upload(id, item);
+                continue;
+            }
+            // the late items of a closed streaming call, a new call always has a larger id
+            if assembler.is_first(&req) && req.id <= conn.last_id() {
+                info!("drop the item of a closed stream: id={}", req.id);
+                if let Err(e) = assembler.discard(&req) {
+                    error!("server assemble req: err = {e}, peer={}", ctx.peer());
+                    conn.close(DisconnectReason::Read(e));
+                    break;
+                }
+                continue;
+            }
+        }
+
+        // all the frames of a oneway request carry the flag
+        let oneway = req.is_oneway();
+        if oneway && assembler.is_first(&req) {
+            conns.oneway.calls.fetch_add(1, Ordering::Relaxed);
+        }
+        // the request is dispatched once its first frame is received
+        if assembler.is_first(&req) && !conn.dispatch(req.id) {
+            info!("refuse request after GOAWAY: id={}", req.id);
+            // the client is never told about a refused oneway request
+            if oneway {
+                let status = WireError::Status("refused after GOAWAY".to_owned());
+                conns.oneway.failed(req.id, &status, ctx.peer());
+            }
+            if let Err(e) = assembler.discard(&req) {
+                error!("server assemble req: err = {e}, peer={}", ctx.peer());
+                conn.close(DisconnectReason::Read(e));
+                break;
+            }
+            continue;
+        }
+        let req = match assembler.push(req) {
+            Ok(Assembled::Done(req)) => req,
+            Ok(Assembled::Partial | Assembled::Discarded) => continue,
+            Ok(Assembled::TooLarge { id, len }) => {
+                let status = format!("request message too large: len={len}, max={max_msg_len}");
+                let status = WireError::Status(status);
+                if oneway {
+                    conns.oneway.failed(id, &status, ctx.peer());
+                } else {
+                    conn.write_rsp(RspBuf::new().finish_limited(id, Err(status), max_msg_len));
+                }
+                conn.live.end();
+                continue;
+            }
+            Err(e) => {
+                error!("server assemble req: err = {e}, peer={}", ctx.peer());
+                conn.close(DisconnectReason::Read(e));
+                break;
+            }
+        };
+        let id = req.id;
+        let req = match req.decompress(max_msg_len) {
+            Ok(req) => req,
+            Err(e) => {
+                error!("server decompress req: err = {e:?}, peer={}", ctx.peer());
+                let status = WireError::Status(format!("failed to decompress request: {e}"));
+                if oneway {
+                    conns.oneway.failed(id, &status, ctx.peer());
+                } else {
+                    conn.write_rsp(RspBuf::new().finish_limited(id, Err(status), max_msg_len));
+                }
+                conn.live.end();
+                continue;
+            }
+        };
+        info!("get request: id={:?}", req.id);
+        // the stream is opened before any item, credit or cancel of it is received
+        let call = req.is_stream().then(|| conn.open_stream(id));
+        let conn = conn.clone();
+        let server = server.clone();
+        let ctx = ctx.clone();
+        let reply_cache = reply_cache.cloned();
+        let oneway_counters = conns.oneway.clone();
+        reqs.add(move || {
+            ctx.clone().set_current();
+            let mut rsp = RspBuf::new();
+            let window = call.map(|(window, upload)| {
+                rsp.set_upload(upload);
+                window
+            });
+            let ret = if req.is_batch() {
+                let cache = reply_cache.as_deref();
+                batch::serve(&server, req.decode_req(), &mut rsp, max_msg_len, cache)
+            } else {
+                server.service(req.decode_req(), &mut rsp)
+            };
+            // the method doesn't take the upload, so the client would not send any items
+            if let Some(upload) = rsp.take_upload() {
+                upload.close();
+            }
+            let ret = match (rsp.take_stream(), &window) {
+                (None, _) => Some(ret),
+                (Some(mut items), Some(window)) => {
+                    let sent = send_items(req.id, window, &mut *items, max_msg_len, None, |data| {
+                        conn.write_rsp(data);
+                        !conn.dead.load(Ordering::Acquire)
+                    });
+                    match sent {
+                        // the upload may fail the stream because of the cancel
+                        Sent::Done(_) if window.is_cancelled() => None,
+                        Sent::Done(ret) => Some(ret),
+                        Sent::Cancelled | Sent::TimedOut => None,
+                    }
+                }
+                (Some(_), None) => {
+                    let s = "streaming method is called without a streaming call".to_owned();
+                    Some(Err(WireError::Status(s)))
+                }
+            };
+
+            // the cancelled stream is closed without a response
+            // and a oneway request never has a response
+            if let (true, Some(Err(e))) = (oneway, &ret) {
+                oneway_counters.failed(req.id, e, ctx.peer());
+            } else if let (false, Some(ret)) = (oneway, ret) {
+                let data = rsp.finish_limited(req.id, ret, max_msg_len);
+                info!("send rsp: id={}", req.id);
+                // send the result back to client
+                conn.write_rsp(data);
+            }
+            if window.is_some() {
+                conn.close_stream(req.id);
+            }
+            conn.live.end();
+        });
+    }
+    // cancel all the outstanding requests
+    drop(reqs);
+    let reason = conn.reason.lock().unwrap().take();
+    config.disconnected(ctx.peer(), &reason.unwrap_or(DisconnectReason::Closed));
+}
+
+/// the dispatch state that used for GOAWAY
+#[derive(Debug, Default)]
+struct Dispatch {
+    // the max request id that is dispatched
+    last_id: u64,
+    // the GOAWAY frame is sent
+    going_away: bool,
+}
+
+/// the outstanding reverse calls to the client
+#[derive(Debug, Default)]
+struct ReverseCalls {
+    // the id of the last reverse call
+    last_id: u64,
+    // the waiters of the outstanding reverse calls
+    waiters: HashMap<u64, may_waiter::ID>,
+}
+
+/// the state of an outstanding streaming call
+struct StreamCall {
+    // the send window of the items to the client
+    window: Arc<Window>,
+    // the items that are uploaded by the client, `None` once the upload is closed
+    upload: Option<mpsc::Sender<io::Result<Frame>>>,
+}
+
+/// the write half of a server connection
+struct Connection<S: StreamExt> {
+    // set once the connection is dead
+    dead: AtomicBool,
+    // the first reason that kill the connection
+    reason: Mutex<Option<DisconnectReason>>,
+    // used for heartbeat and idle check
+    live: Liveness,
+    // used for GOAWAY
+    dispatch: Mutex<Dispatch>,
+    // the outstanding streaming calls
+    streams: Mutex<HashMap<u64, StreamCall>>,
+    // the outstanding reverse calls
+    reverse: Mutex<ReverseCalls>,
+    // the max time to wait the outstanding requests after GOAWAY
+    goaway_timeout: Duration,
+    // protect the frames by checksum
+    checksum: bool,
+    // encode the frames with the compact header
+    compact_header: bool,
+    // the max payload len of a frame, large responses are split by it
+    max_frame_len: usize,
+    // the max payload len of a message
+    max_message_len: usize,
+    // compress the responses, negotiated in the handshake
+    compression: Option<Compression>,
+    // the min payload len of a response to be compressed
+    compression_threshold: usize,
+    // used to shutdown the connection, so that the reader would exit
+    ctrl: Mutex<S>,
+    writer: QueuedWriter<S>,
+}
+
+impl<S: StreamExt> Connection<S> {
+    fn new(stream: S, ctrl: S, config: &ServerConfig, settings: Settings) -> Self {
+        Connection {
+            dead: AtomicBool::new(false),
+            reason: Mutex::new(None),
+            live: Liveness::default(),
+            dispatch: Mutex::new(Dispatch::default()),
+            streams: Mutex::new(HashMap::new()),
+            reverse: Mutex::new(ReverseCalls::default()),
+            goaway_timeout: config.goaway_timeout(),
+            checksum: settings.checksum,
+            compact_header: settings.compact_header,
+            max_frame_len: config.max_frame_len(),
+            max_message_len: config.max_message_len(),
+            compression: settings.compression,
+            compression_threshold: config.compression_threshold(),
+            ctrl: Mutex::new(ctrl),
+            writer: QueuedWriter::new(stream),
+        }
+    }
+
+    /// check if the request should be processed, requests after GOAWAY are refused
+    fn dispatch(&self, id: u64) -> bool {
+        let mut dispatch = self.dispatch.lock().unwrap();
+        if dispatch.going_away && id > dispatch.last_id {
+            return false;
+        }
+        dispatch.last_id = dispatch.last_id.max(id);
+        self.live.begin();
+        true
+    }
+
+    /// the max request id that is dispatched
+    fn last_id(&self) -> u64 {
+        self.dispatch.lock().unwrap().last_id
+    }
+
+    /// open a streaming call, return the send window and the upload of it
+    fn open_stream(self: &Arc<Self>, id: u64) -> (Arc<Window>, Remote) {
+        let window = Arc::new(Window::new());
+        let (tx, rx) = mpsc::channel();
+        let call = StreamCall {
+            window: window.clone(),
+            upload: Some(tx),
+        };
+        self.streams.lock().unwrap().insert(id, call);
+        (window, Remote::new(id, self.clone(), rx, None))
+    }
+
+    /// check if the streaming call is outstanding
+    fn is_streaming(&self, id: u64) -> bool {
+        self.streams.lock().unwrap().contains_key(&id)
+    }
+
+    /// get the send window of a streaming call
+    fn stream(&self, id: u64) -> Option<Arc<Window>> {
+        let streams = self.streams.lock().unwrap();
+        streams.get(&id).map(|call| call.window.clone())
+    }
+
+    /// deliver an uploaded item, it's dropped if the upload is closed
+    fn push_upload(&self, id: u64, item: io::Result<Frame>) {
+        let streams = self.streams.lock().unwrap();
+        if let Some(tx) = streams.get(&id).and_then(|call| call.upload.as_ref()) {
+            tx.send(item).ok();
+        }
+    }
+
+    /// the client cancelled the streaming call
+    fn cancel_stream(&self, id: u64) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(call) = streams.get_mut(&id) {
+            info!("stream cancelled by the client: id={id}");
+            call.window.cancel();
+            if let Some(tx) = call.upload.take() {
+                let e = io::Error::new(io::ErrorKind::ConnectionAborted, "stream cancelled");
+                tx.send(Err(e)).ok();
+            }
+        }
+    }
+
+    /// the upload of the streaming call failed, the rest items are dropped
+    fn fail_upload(&self, id: u64, e: io::Error) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(tx) = streams.get_mut(&id).and_then(|call| call.upload.take()) {
+            info!("stream upload failed: id={id}, err={e}");
+            tx.send(Err(e)).ok();
+        }
+    }
+
+    fn close_stream(&self, id: u64) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    /// wake up the waiter of a reverse call, the responses of unknown ids are dropped
+    fn set_reverse_rsp(&self, id: u64, rsp: io::Result<Frame>) {
+        // the waiter must be triggered within the lock, or it may be already dropped
+        let mut reverse = self.reverse.lock().unwrap();
+        match reverse.waiters.remove(&id) {
+            Some(waiter) => RspWaiter::set_rsp(waiter, rsp),
+            None => info!("drop the rsp of an unknown reverse call: id={id}"),
+        }
+    }
+
+    /// write a frame to the peer, the connection is closed if failed
+    fn write(&self, mut data: Vec<u8>) {
+        if self.dead.load(Ordering::Acquire) {
+            info!("connection is dead, discard the rsp");
+            return;
+        }
+        if self.checksum {
+            seal_checksum(&mut data);
+        }
+        if self.compact_header {
+            compact_header(&mut data);
+        }
+        if let Err(e) = self.writer.write(data) {
+            info!("server write rsp failed: err = {e:?}");
+            self.close(DisconnectReason::Write(e));
+        }
+    }
+
+    /// write a response, a large one is split into continuation frames
+    /// that interleave with the frames of other responses
+    fn write_rsp(&self, data: Vec<u8>) {
+        let data = match self.compression {
+            Some(c) => compress_frame(data, c, self.compression_threshold),
+            None => data,
+        };
+        let frames = split_frame(data, self.max_frame_len);
+        let last = frames.len() - 1;
+        for (i, frame) in frames.into_iter().enumerate() {
+            self.write(frame);
+            if i < last {
+                coroutine::yield_now();
+            }
+        }
+    }
+
+    /// mark the connection dead and wake up the reader
+    fn close(&self, reason: DisconnectReason) {
+        if self.dead.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        *self.reason.lock().unwrap() = Some(reason);
+        self.ctrl.lock().unwrap().shutdown().ok();
+        // fail the outstanding reverse calls
+        let mut reverse = self.reverse.lock().unwrap();
+        for (_, waiter) in reverse.waiters.drain() {
+            let e = io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed");
+            RspWaiter::set_rsp(waiter, Err(e));
+        }
+    }
+}
+
+impl<S: StreamExt> ReverseConn for Connection<S> {
+    fn call(&self, buf: Vec<u8>, timeout: Option<Duration>) -> Result<Frame, Error> {
+        // the poll payload is ty(u8) + len(u64) + req_data
+        let len = buf.len() - 16 + 9;
+        if len > self.max_message_len {
+            return Err(Error::FrameTooLarge(len, self.max_message_len));
+        }
+        let mut rsp = RspBuf::new();
+        rsp.write_all(&buf[16..])?;
+        let waiter = RspWaiter::new();
+        let id = {
+            let mut reverse = self.reverse.lock().unwrap();
+            // the waiters are failed once the connection is dead
+            if self.dead.load(Ordering::Acquire) {
+                return Err(io::Error::from(io::ErrorKind::NotConnected).into());
+            }
+            reverse.last_id += 1;
+            let id = reverse.last_id;
+            reverse.waiters.insert(id, waiter.id().unwrap());
+            id
+        };
+        let mut data = rsp.finish_limited(id, Err(WireError::Polling), self.max_message_len);
+        add_frame_flags(&mut data, FLAG_REVERSE);
+        info!("send reverse call: id={id}");
+        self.live.begin();
+        self.write_rsp(data);
+        let ret = waiter.wait_rsp(timeout).and_then(|rsp| rsp);
+        self.reverse.lock().unwrap().waiters.remove(&id);
+        self.live.end();
+        Ok(ret?)
+    }
+}
+
+impl<S: StreamExt> StreamConn for Connection<S> {
+    fn credit(&self, id: u64, n: u32) {
+        self.write(Control::Credit.encode(id, &n.to_be_bytes()));
+    }
+
+    fn finish(&self, id: u64, cancel: bool) {
+        if let Some(call) = self.streams.lock().unwrap().get_mut(&id) {
+            call.upload = None;
+        }
+        if cancel {
+            self.write(Control::Cancel.encode(id, &[]));
+        }
+    }
+}
+
+impl<S: StreamExt> GoAway for Connection<S> {
+    fn go_away(self: Arc<Self>) {
+        {
+            let mut dispatch = self.dispatch.lock().unwrap();
+            if dispatch.going_away {
+                return;
+            }
+            dispatch.going_away = true;
+            // the peer should not send new requests after this
+            self.write(Control::GoAway.encode(dispatch.last_id, &[]));
+        }
+
+        // close the connection after the outstanding requests are done
+        go!(move || {
+            let deadline = Instant::now() + self.goaway_timeout;
+            while self.live.inflight() > 0
+                && !self.dead.load(Ordering::Acquire)
+                && Instant::now() < deadline
+            {
+                coroutine::sleep(Duration::from_millis(10));
+            }
+            // make sure all the responses are written before shutdown
+            self.writer.sync();
+            self.close(DisconnectReason::GoAway);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conetty::frame::{ReqBuf, FLAG_ONEWAY};
+    use crate::conetty::TcpServer;
+    use std::io::Read;
+
+    /// sleep for the ms in the request
+    struct Sleep;
+
+    impl Server for Sleep {
+        fn service(&self, req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            coroutine::sleep(Duration::from_millis(req[0] as u64 * 10));
+            Ok(())
+        }
+    }
+
+    fn raw_req(id: u64, ms: u8, flags: u8) -> Vec<u8> {
+        let mut req = ReqBuf::new();
+        req.write_all(&[ms]).unwrap();
+        req.add_flags(flags);
+        req.finish(id)
+    }
+
+    #[test]
+    fn oneway_refused_after_goaway() {
+        let mut config = ServerConfig::new();
+        config.set_max_conn_age(Duration::from_millis(50));
+        let server = Sleep
+            .start_with_config(("127.0.0.1", 42331), config)
+            .unwrap();
+
+        let mut s = std::net::TcpStream::connect(("127.0.0.1", 42331)).unwrap();
+        s.write_all(b"MAYRPC\x00\x01\x00\x00\x00\x00").unwrap();
+        s.read_exact(&mut [0; 12]).unwrap();
+        // the outstanding request keeps the connection open after GOAWAY
+        s.write_all(&raw_req(1, 50, 0)).unwrap();
+        coroutine::sleep(Duration::from_millis(200));
+        // the oneway request is refused and counted as failed
+        s.write_all(&raw_req(2, 0, FLAG_ONEWAY)).unwrap();
+        for _ in 0..100 {
+            if server.oneway_stats().failed == 1 {
+                break;
+            }
+            coroutine::sleep(Duration::from_millis(10));
+        }
+        let stats = server.oneway_stats();
+        assert_eq!((stats.calls, stats.failed), (1, 1));
+    }
+}
@@ -0,0 +1,91 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+
+use super::reverse::ReverseClient;
+use super::server::Peer;
+
+may::coroutine_local!(static CONTEXT: RefCell<Option<ReqContext>> = RefCell::new(None));
+
+/// per connection state that returned from the `on_connect` hook
+/// it can be accessed from the request context of the connection
+#[derive(Clone, Default)]
+pub struct ConnState(Option<Arc<dyn Any + Send + Sync>>);
+
+impl ConnState {
+    /// create a connection state with the given value
+    pub fn new<T: Any + Send + Sync>(state: T) -> Self {
+        ConnState(Some(Arc::new(state)))
+    }
+
+    /// get the state value if it's the type `T`
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.0.as_ref().and_then(|s| s.downcast_ref())
+    }
+}
+
+impl fmt::Debug for ConnState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ConnState").field(&self.0.is_some()).finish()
+    }
+}
+
+/// returned from the `on_connect` hook to reject a connection
+#[derive(Debug, Clone)]
+pub struct Reject(pub String);
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection rejected: {}", self.0)
+    }
+}
+
+#[derive(Debug)]
+struct ConnContext {
+    peer: Peer,
+    state: ConnState,
+    // set if the client serves the reverse calls
+    reverse: Option<ReverseClient>,
+}
+
+/// the context of the request that is served in the current coroutine
+#[derive(Debug, Clone)]
+pub struct ReqContext(Arc<ConnContext>);
+
+impl ReqContext {
+    pub(crate) fn new(peer: Peer, state: ConnState, reverse: Option<ReverseClient>) -> Self {
+        ReqContext(Arc::new(ConnContext {
+            peer,
+            state,
+            reverse,
+        }))
+    }
+
+    /// get the context of the current request
+    /// return `None` if not called within a stream server request
+    pub fn current() -> Option<ReqContext> {
+        CONTEXT.with(|ctx| ctx.borrow().clone())
+    }
+
+    /// install the context for the current request coroutine
+    pub(crate) fn set_current(self) {
+        CONTEXT.with(|ctx| *ctx.borrow_mut() = Some(self));
+    }
+
+    /// the remote address of the connection
+    pub fn peer(&self) -> &Peer {
+        &self.0.peer
+    }
+
+    /// the state of the connection that returned from the `on_connect` hook
+    pub fn state<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.0.state.get()
+    }
+
+    /// get the client that calls back into the client of the connection
+    /// return `None` if the client doesn't register a callback service
+    pub fn reverse(&self) -> Option<ReverseClient> {
+        self.0.reverse.clone()
+    }
+}
@@ -7,7 +7,17 @@ use thiserror::Error;
 pub enum Error {
     /// Any IO error.
     #[error("IO err: {0}")]
-    Io(#[from] io::Error),
+    Io(io::Error),
+    /// The frame checksum doesn't match, the frame is corrupted.
+    ///
+    /// The connection is closed since the stream can't be trusted any more
+    #[error("frame checksum mismatch")]
+    Checksum,
+    /// The frame payload is larger than the max frame len.
+    ///
+    /// The first value is the payload len and the second one is the limit
+    #[error("frame too large: len={0}, max={1}")]
+    FrameTooLarge(usize, usize),
     /// Error in deserializing a server response.
     ///
     /// Typically this indicates a faulty implementation of `serde::Serialize` or
@@ -40,6 +50,64 @@ pub enum Error {
     /// Typically this indicates that the server is not healthy
     #[error("The server returns an status error due to different reasons: {0}")]
     Status(String),
+    /// The circuit breaker is open, the call is rejected without being sent.
+    ///
+    /// Typically this indicates that the server is down or too many calls are failing
+    #[error("the circuit breaker is open, the call is rejected")]
+    CircuitOpen,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if is_checksum_err(&e) {
+            return Error::Checksum;
+        }
+        if let Some(e) = as_too_large(&e) {
+            return Error::FrameTooLarge(e.len as usize, e.max);
+        }
+        Error::Io(e)
+    }
+}
+
+/// check if the io error is caused by a checksum failure
+pub(crate) fn is_checksum_err(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<ChecksumMismatch>())
+}
+
+/// get the too large frame info from the io error
+pub(crate) fn as_too_large(e: &io::Error) -> Option<&FrameTooLarge> {
+    e.get_ref().and_then(|e| e.downcast_ref::<FrameTooLarge>())
+}
+
+/// the io error payload that reports a too large frame
+#[derive(Debug, Error)]
+#[error("frame too large: id={id}, len={len}, max={max}")]
+pub(crate) struct FrameTooLarge {
+    /// the frame id
+    pub id: u64,
+    /// the frame is a control frame
+    pub control: bool,
+    /// the remaining len of the frame
+    pub len: u64,
+    /// the max payload len
+    pub max: usize,
+}
+
+impl From<FrameTooLarge> for io::Error {
+    fn from(e: FrameTooLarge) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// the io error payload that reports a checksum failure
+#[derive(Debug, Error)]
+#[error("frame checksum mismatch")]
+pub(crate) struct ChecksumMismatch;
+
+impl From<ChecksumMismatch> for io::Error {
+    fn from(e: ChecksumMismatch) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
 }
 
 /// A serializable, server-supplied error.
@@ -55,7 +123,8 @@ pub enum WireError {
     Status(String),
     /// Server polling
     /// this is a special error code that used for server polling request from client
-    /// client will first check this code in the very beginning before return to client rpc call
+    /// the rsp buf carries the request of a reverse call, it's served by the callback
+    /// service of the client instead of returning to the client rpc call
     #[error("Server polling")]
     Polling,
 }
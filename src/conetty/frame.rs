@@ -1,11 +1,18 @@
+use std::collections::HashMap;
 use std::io::{self, Cursor, ErrorKind, Read, Write};
 
+use super::buf_pool;
+use super::compress::{decompress, Compression};
+use super::errors::{ChecksumMismatch, FrameTooLarge};
+use super::handshake::Settings;
+use super::streaming::{Remote, Source};
 use crate::{Error, WireError};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{BufMut, Bytes, BytesMut};
 
 // Frame layout
 // id(u64) + len(u64) + payload([u8; len])
+// the highest byte of len is used as frame flags
 
 // req frame layout
 // id(u64) + len(u64) + req_data([u8; len])
@@ -13,51 +20,271 @@ use bytes::{BufMut, Bytes, BytesMut};
 // rsp frame layout
 // id(u64) + len(u64) + ty(u8) + len1(u64) + rsp_data([u8; len1])
 
-// max frame len
-const FRAME_MAX_LEN: u64 = 1024 * 1024;
+// control frame layout
+// id(u64) + len(u64) + kind(u8) + body([u8; len - 1])
+
+// a large message is split into continuation frames with the same id
+// all the frames except the last one have the MORE flag set
+// the payloads of the frames are concatenated into the message payload
+
+// a streaming call carries the items as frames with the STREAM flag on the request id
+// the item frames have the rsp layout, the call is closed by a rsp frame without the flag
+// the client half closes its items by an empty frame with the STREAM flag
+// the receiver grants the sender credits for more items by `Credit` control frames
+
+// a oneway request carries the ONEWAY flag, the server never sends a response for it
+
+// a batch request carries the BATCH flag, its req_data packs the requests of the batch
+// mode(u8) + [len(u64) + req_data([u8; len])]*
+// the rsp_data of the response packs the results in the same order
+// [ty(u8) + len(u64) + rsp_data([u8; len])]*
+// with the KEYED mode the idempotency key follows the mode, the reply is cached by the server
+// mode(u8) + client(u64) + key(u64) + ...
+// with the SINGLE mode the rest is one req_data without the len, and the response is not packed
+// a request with an idempotency key is sent as a batch with the KEYED and SINGLE mode
+
+// a reverse call is sent by the server to the callback service of the client
+// both the request and the response carry the REVERSE flag, the ids are assigned by the server
+// the request has the rsp layout with the poll type, whose data is the req_data
+// id(u64) + len(u64) + ty(u8 = SERVER_POLL_ENCODE) + len1(u64) + req_data([u8; len1])
+
+// compressed frame layout, the payload of the whole message is compressed
+// id(u64) + len(u64) + algo(u8) + raw_len(u64) + compressed([u8; len - 9])
+
+// checksum frame layout, len includes the checksum
+// id(u64) + len(u64) + payload([u8; len - 4]) + crc32c(u32)
+// the crc32c is calculated over the header and the payload
+
+// compact header layout, negotiated in the handshake
+// flags(u8) + id(varint) + len(varint) + payload([u8; len])
+// the frames are always built with the 16 bytes header, it's converted
+// from and to the compact one only when the frame is on the wire
+// the checksum is calculated over the 16 bytes header
+
+/// the default max payload len of a frame
+pub(crate) const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024;
+/// the default max payload len of a chunked message
+pub(crate) const DEFAULT_MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+/// the default max number of the messages that are reassembled at the same time
+pub(crate) const DEFAULT_MAX_PARTIAL_MESSAGES: usize = 64;
+/// the default max len of all the messages that are reassembled at the same time
+pub(crate) const DEFAULT_MAX_PARTIAL_LEN: usize = 64 * 1024 * 1024;
+/// the payload len of each continuation frame
+/// small chunks make the large messages interleave with other frames
+const CHUNK_LEN: usize = 64 * 1024;
+
+// the frame flags are stored in the highest byte of len
+const FLAGS_SHIFT: u32 = 56;
+const LEN_MASK: u64 = (1 << FLAGS_SHIFT) - 1;
+/// the max len that is read into the decode buffer at once
+const READ_CHUNK_LEN: usize = 16 * 1024;
+/// the max len of the compact header, flags + 2 * varint(u64)
+const MAX_COMPACT_HEADER_LEN: usize = 21;
+
+/// the frame is a control frame that is handled by the framework
+pub(crate) const FLAG_CONTROL: u8 = 0x01;
+/// the frame is followed by a crc32c checksum
+pub(crate) const FLAG_CHECKSUM: u8 = 0x02;
+/// more continuation frames of the same message follow
+pub(crate) const FLAG_MORE: u8 = 0x04;
+/// the frame payload is compressed
+pub(crate) const FLAG_COMPRESSED: u8 = 0x08;
+/// the frame belongs to a streaming call
+pub(crate) const FLAG_STREAM: u8 = 0x10;
+/// the frame belongs to a reverse call from the server
+pub(crate) const FLAG_REVERSE: u8 = 0x20;
+/// the request doesn't expect a response
+pub(crate) const FLAG_ONEWAY: u8 = 0x40;
+/// the request packs a batch of requests
+pub(crate) const FLAG_BATCH: u8 = 0x80;
+
+/// the requests of the batch are dispatched in parallel
+pub(crate) const MODE_PARALLEL: u8 = 0x01;
+/// the batch carries an idempotency key
+pub(crate) const MODE_KEYED: u8 = 0x02;
+/// the batch carries one request that is not packed
+pub(crate) const MODE_SINGLE: u8 = 0x04;
+
+/// control frame kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Control {
+    /// heartbeat request, the peer should reply a `Pong` with the same id
+    Ping = 1,
+    /// heartbeat response
+    Pong = 2,
+    /// the server is going away, the frame id is the last request id that would be processed
+    GoAway = 3,
+    /// the receiver of a stream consumed some items, the body is the number of them(u32)
+    Credit = 4,
+    /// the receiver of a stream is not interested in the rest items
+    Cancel = 5,
+}
+
+impl Control {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(Control::Ping),
+            2 => Some(Control::Pong),
+            3 => Some(Control::GoAway),
+            4 => Some(Control::Credit),
+            5 => Some(Control::Cancel),
+            _ => None,
+        }
+    }
+
+    /// encode a control frame that can be send directly
+    pub(crate) fn encode(self, id: u64, body: &[u8]) -> Vec<u8> {
+        let len = body.len() as u64 + 1;
+        let mut buf = Vec::with_capacity(len as usize + 16);
+        buf.write_u64::<BigEndian>(id).unwrap();
+        buf.write_u64::<BigEndian>((FLAG_CONTROL as u64) << FLAGS_SHIFT | len)
+            .unwrap();
+        buf.push(self as u8);
+        buf.extend_from_slice(body);
+        buf
+    }
+}
 
 /// raw frame wrapper, low level protocol
-/// TODO: add check sum check
 #[derive(Debug)]
 pub struct Frame {
     /// frame id, req and rsp has the same id
     pub id: u64,
+    /// frame flags
+    flags: u8,
     /// payload data
     data: Bytes,
 }
 
 impl Frame {
     /// decode a frame from the reader
+    /// the checksum is verified if the frame carries one
     pub fn decode_from<R: Read>(r: &mut R, buf: &mut BytesMut) -> io::Result<Self> {
-        let id = r.read_u64::<BigEndian>()?;
+        Decoder::new(DEFAULT_MAX_FRAME_LEN, Settings::default()).decode(r, buf)
+    }
+
+    fn decode<R: Read>(
+        r: &mut R,
+        buf: &mut BytesMut,
+        max_len: usize,
+        require_checksum: bool,
+        compact_header: bool,
+    ) -> io::Result<Self> {
+        let (id, raw_len) = if compact_header {
+            read_compact_header(r)?
+        } else {
+            (r.read_u64::<BigEndian>()?, r.read_u64::<BigEndian>()?)
+        };
         info!("decode id = {id:?}");
 
-        let len = r.read_u64::<BigEndian>()? + 16;
-        info!("decode len = {len:?}");
+        let flags = (raw_len >> FLAGS_SHIFT) as u8;
+        let len = (raw_len & LEN_MASK) + 16;
+        info!("decode len = {len:?}, flags = {flags:#x}");
 
-        if len > FRAME_MAX_LEN {
-            let s = format!("decode too big frame length. len={len}");
-            error!("{s}");
-            return Err(io::Error::new(ErrorKind::InvalidInput, s));
+        // the checksum is not counted as payload
+        let payload_len = (raw_len & LEN_MASK).saturating_sub(checksum_len(flags));
+        if payload_len > max_len as u64 {
+            error!("decode too big frame: id={id}, len={payload_len}, max={max_len}");
+            let e = FrameTooLarge {
+                id,
+                control: flags & FLAG_CONTROL != 0,
+                len: raw_len & LEN_MASK,
+                max: max_len,
+            };
+            return Err(e.into());
+        }
+
+        // the buf only grows as the payload arrives, a slow peer can't pin
+        // the max frame len of memory by just sending a header
+        buf.clear();
+        buf.put_u64(id);
+        buf.put_u64(raw_len);
+        read_payload(r, buf, len as usize - 16)?;
+        let mut data = buf.split().freeze();
+
+        if flags & FLAG_CHECKSUM != 0 {
+            verify_checksum(&mut data)?;
+        } else if require_checksum {
+            error!("frame checksum missing: id={id}");
+            return Err(ChecksumMismatch.into());
+        }
+
+        Ok(Frame { id, flags, data })
+    }
+
+    /// decompress the payload if the frame is compressed
+    /// the decompressed payload must not be larger than `max_len`
+    pub(crate) fn decompress(self, max_len: usize) -> io::Result<Frame> {
+        if self.flags & FLAG_COMPRESSED == 0 {
+            return Ok(self);
         }
+        let mut r = Cursor::new(&self.data[16..]);
+        let algo = r.read_u8()?;
+        let len = r.read_u64::<BigEndian>()?;
+        if len > max_len as u64 {
+            error!("decompress too big message: id={}, len={len}", self.id);
+            let e = FrameTooLarge {
+                id: self.id,
+                control: false,
+                len,
+                max: max_len,
+            };
+            return Err(e.into());
+        }
+        let raw = decompress(algo, &self.data[25..], len as usize)?;
 
-        let buf_len = len as usize;
+        let flags = self.flags & !FLAG_COMPRESSED;
+        let mut data = BytesMut::with_capacity(raw.len() + 16);
+        data.put_u64(self.id);
+        data.put_u64(len | (flags as u64) << FLAGS_SHIFT);
+        data.extend_from_slice(&raw);
+        Ok(Frame {
+            id: self.id,
+            flags,
+            data: data.freeze(),
+        })
+    }
 
-        buf.reserve(len as usize);
-        let data: &mut [u8] = unsafe { std::mem::transmute(buf.chunk_mut()) };
+    /// check if this is the last frame of a message
+    pub(crate) fn is_last(&self) -> bool {
+        self.flags & FLAG_MORE == 0
+    }
 
-        r.read_exact(&mut data[16..buf_len])?;
-        unsafe { buf.advance_mut(buf_len) };
-        let mut data = buf.split_to(buf_len);
+    /// check if this frame belongs to a streaming call
+    pub(crate) fn is_stream(&self) -> bool {
+        self.flags & FLAG_STREAM != 0
+    }
 
-        unsafe { data.set_len(0) };
-        data.put_u64(id);
-        data.put_u64(len - 16);
-        unsafe { data.set_len(buf_len) };
+    /// check if this is the frame that half closes a stream
+    pub(crate) fn is_stream_end(&self) -> bool {
+        self.is_stream() && self.data.len() == 16
+    }
 
-        let data = data.freeze();
+    /// check if this frame belongs to a reverse call
+    pub(crate) fn is_reverse(&self) -> bool {
+        self.flags & FLAG_REVERSE != 0
+    }
 
-        Ok(Frame { id, data })
+    /// check if this is a request that doesn't expect a response
+    pub(crate) fn is_oneway(&self) -> bool {
+        self.flags & FLAG_ONEWAY != 0
+    }
+
+    /// check if this request packs a batch of requests
+    pub(crate) fn is_batch(&self) -> bool {
+        self.flags & FLAG_BATCH != 0
+    }
+
+    /// check if this is a control frame that should be handled by the framework
+    pub(crate) fn is_control(&self) -> bool {
+        self.flags & FLAG_CONTROL != 0
+    }
+
+    /// decode the control kind and body from the frame
+    /// return `None` for unknown control frames
+    pub(crate) fn decode_control(&self) -> Option<(Control, &[u8])> {
+        let kind = Control::from_u8(*self.data.get(16)?)?;
+        Some((kind, &self.data[17..]))
     }
 
     // /// convert self into raw buf that can be re-send as a frame
@@ -78,9 +305,28 @@ impl Frame {
         &self.data[16..]
     }
 
+    /// decode the request of a reverse call that is polled by the server
+    pub(crate) fn decode_poll(&self) -> Result<&[u8], Error> {
+        match self.decode_payload()? {
+            (SERVER_POLL_ENCODE, data) => Ok(data),
+            (ty, _) => {
+                let s = format!("invalid poll type. ty={ty}");
+                error!("{s}");
+                Err(Error::ClientDeserialize(s))
+            }
+        }
+    }
+
     /// decode a response from the frame, this would return the rsp raw buffer
     /// you need to deserialized from it into the real type
     pub fn decode_rsp(&self) -> Result<&[u8], Error> {
+        let (ty, data) = self.decode_payload()?;
+        // info!("decode response, ty={}, len={}", ty, len);
+        rsp_result(ty, data)
+    }
+
+    /// decode the type and data of a frame with the rsp layout
+    fn decode_payload(&self) -> Result<(u8, &[u8]), Error> {
         use Error::*;
 
         let mut r = Cursor::new(&self.data[..]);
@@ -88,29 +334,410 @@ impl Frame {
         r.set_position(16);
 
         let ty = r.read_u8()?;
-        // we don't need to check len here, frame is checked already
-        let len = r.read_u64::<BigEndian>()? as usize;
+        let len = r.read_u64::<BigEndian>()?;
 
+        // the len is from the peer, it must match the payload
         let buf = r.into_inner();
-        let data = &buf[25..len + 25];
+        let Some(data) = (len as usize)
+            .checked_add(25)
+            .and_then(|end| buf.get(25..end))
+        else {
+            let s = format!(
+                "invalid response len. len={len}, payload={}",
+                buf.len() - 16
+            );
+            error!("{s}");
+            return Err(ClientDeserialize(s));
+        };
+        Ok((ty, data))
+    }
+}
 
-        // info!("decode response, ty={}, len={}", ty, len);
-        match ty {
-            0 => Ok(data),
-            1 => Err(ServerDeserialize(String::from_utf8(data.into()).unwrap())),
-            2 => Err(ServerSerialize(String::from_utf8(data.into()).unwrap())),
-            3 => Err(Status(String::from_utf8(data.into()).unwrap())),
-            _ => {
-                let s = format!("invalid response type. ty={ty}");
-                error!("{s}");
-                Err(ClientDeserialize(s))
+/// convert the type and data of a response into the result
+pub(crate) fn rsp_result(ty: u8, data: &[u8]) -> Result<&[u8], Error> {
+    use Error::*;
+
+    let msg = || String::from_utf8_lossy(data).into_owned();
+    match ty {
+        0 => Ok(data),
+        1 => Err(ServerDeserialize(msg())),
+        2 => Err(ServerSerialize(msg())),
+        3 => Err(Status(msg())),
+        _ => {
+            let s = format!("invalid response type. ty={ty}");
+            error!("{s}");
+            Err(ClientDeserialize(s))
+        }
+    }
+}
+
+/// frame decoder that keeps the per connection decoding state
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    // the max payload len of a frame
+    max_len: usize,
+    // the frames must carry a checksum
+    require_checksum: bool,
+    // the frames are encoded with the compact header
+    compact_header: bool,
+}
+
+impl Decoder {
+    pub fn new(max_len: usize, settings: Settings) -> Self {
+        Decoder {
+            max_len,
+            require_checksum: settings.checksum,
+            compact_header: settings.compact_header,
+        }
+    }
+
+    /// decode a frame from the reader
+    /// a too large frame is reported as `FrameTooLarge` without reading its payload
+    /// the caller can `skip` it and continue decoding
+    pub fn decode<R: Read>(&mut self, r: &mut R, buf: &mut BytesMut) -> io::Result<Frame> {
+        Frame::decode(
+            r,
+            buf,
+            self.max_len,
+            self.require_checksum,
+            self.compact_header,
+        )
+    }
+
+    /// discard the payload of a too large frame
+    pub fn skip<R: Read>(&self, r: &mut R, e: &FrameTooLarge) -> io::Result<()> {
+        let n = io::copy(&mut r.take(e.len), &mut io::sink())?;
+        if n != e.len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+/// the result of pushing a frame into the `Assembler`
+#[derive(Debug)]
+pub(crate) enum Assembled {
+    /// the whole message is received
+    Done(Frame),
+    /// wait for more continuation frames
+    Partial,
+    /// the message is larger than the limit, the rest of it would be discarded
+    TooLarge { id: u64, len: usize },
+    /// the frame belongs to a discarded message
+    Discarded,
+}
+
+/// reassemble the continuation frames into messages
+#[derive(Debug)]
+pub(crate) struct Assembler {
+    // the max payload len of a message
+    max_len: usize,
+    // the max number and total len of the partial messages
+    max_parts: usize,
+    max_partial_len: usize,
+    // the partial messages, `None` if the message is discarded
+    parts: HashMap<u64, Option<BytesMut>>,
+    // the len of all the buffered partial messages
+    partial_len: usize,
+}
+
+impl Assembler {
+    pub fn new(max_len: usize) -> Self {
+        Self::with_limits(
+            max_len,
+            DEFAULT_MAX_PARTIAL_MESSAGES,
+            DEFAULT_MAX_PARTIAL_LEN,
+        )
+    }
+
+    /// create an assembler that holds at most `max_parts` partial messages
+    /// and `max_partial_len` bytes of them
+    pub fn with_limits(max_len: usize, max_parts: usize, max_partial_len: usize) -> Self {
+        Assembler {
+            max_len,
+            max_parts,
+            max_partial_len,
+            parts: HashMap::new(),
+            partial_len: 0,
+        }
+    }
+
+    // check if one more partial message of `len` bytes exceeds the limits
+    // the peer opens too many messages without finishing them, the connection should be closed
+    fn reserve(&self, len: usize) -> io::Result<()> {
+        let count = self.parts.len() + 1;
+        let total = self.partial_len + len;
+        if count > self.max_parts || total > self.max_partial_len {
+            let s = format!(
+                "too many partial messages: count={count}, len={total}, max_count={}, max_len={}",
+                self.max_parts, self.max_partial_len
+            );
+            return Err(io::Error::new(ErrorKind::InvalidData, s));
+        }
+        Ok(())
+    }
+
+    /// check if the frame is the first one of a message
+    pub fn is_first(&self, frame: &Frame) -> bool {
+        !self.parts.contains_key(&frame.id)
+    }
+
+    /// discard the message that the frame belongs to
+    /// the error means the partial messages exceed the limits
+    pub fn discard(&mut self, frame: &Frame) -> io::Result<()> {
+        if let Some(Some(data)) = self.parts.remove(&frame.id) {
+            self.partial_len -= data.len();
+        }
+        if frame.flags & FLAG_MORE != 0 {
+            self.reserve(0)?;
+            self.parts.insert(frame.id, None);
+        }
+        Ok(())
+    }
+
+    /// push a received frame, return the message once it's complete
+    /// the error means the partial messages exceed the limits, nothing is buffered then
+    pub fn push(&mut self, frame: Frame) -> io::Result<Assembled> {
+        let more = frame.flags & FLAG_MORE != 0;
+        let mut data = match self.parts.remove(&frame.id) {
+            // the common case, a message with a single frame
+            None if !more => return Ok(Assembled::Done(frame)),
+            None => BytesMut::from(&frame.data[..]),
+            Some(Some(mut data)) => {
+                self.partial_len -= data.len();
+                data.extend_from_slice(&frame.data[16..]);
+                data
+            }
+            Some(None) => {
+                self.discard(&frame)?;
+                return Ok(Assembled::Discarded);
             }
+        };
+
+        let len = data.len() - 16;
+        if len > self.max_len {
+            error!("assemble too big message: id={}, len={len}", frame.id);
+            self.discard(&frame)?;
+            return Ok(Assembled::TooLarge { id: frame.id, len });
+        }
+        if more {
+            self.reserve(data.len())?;
+            self.partial_len += data.len();
+            self.parts.insert(frame.id, Some(data));
+            return Ok(Assembled::Partial);
+        }
+
+        let flags = frame.flags & !(FLAG_MORE | FLAG_CHECKSUM);
+        let raw_len = len as u64 | (flags as u64) << FLAGS_SHIFT;
+        data[8..16].copy_from_slice(&raw_len.to_be_bytes());
+        Ok(Assembled::Done(Frame {
+            id: frame.id,
+            flags,
+            data: data.freeze(),
+        }))
+    }
+}
+
+/// split an encoded frame into continuation frames
+/// the frame is returned as is if it's small enough
+pub(crate) fn split_frame(buf: Vec<u8>, max_frame_len: usize) -> Vec<Vec<u8>> {
+    let chunk_len = CHUNK_LEN.min(max_frame_len);
+    if buf.len() - 16 <= chunk_len {
+        return vec![buf];
+    }
+
+    let raw_len = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+    let flags = (raw_len >> FLAGS_SHIFT) as u8;
+    let mut chunks = buf[16..].chunks(chunk_len).peekable();
+    let mut frames = Vec::with_capacity(chunks.len());
+    while let Some(chunk) = chunks.next() {
+        let flags = match chunks.peek() {
+            Some(_) => flags | FLAG_MORE,
+            None => flags,
+        };
+        let mut frame = Vec::with_capacity(chunk.len() + 16);
+        frame.extend_from_slice(&buf[..8]);
+        frame
+            .write_u64::<BigEndian>(chunk.len() as u64 | (flags as u64) << FLAGS_SHIFT)
+            .unwrap();
+        frame.extend_from_slice(chunk);
+        frames.push(frame);
+    }
+    buf_pool::put(buf);
+    frames
+}
+
+/// compress the payload of an encoded frame if it's not smaller than `threshold`
+/// the frame is returned as is if the compression doesn't help or fails
+pub(crate) fn compress_frame(buf: Vec<u8>, compression: Compression, threshold: usize) -> Vec<u8> {
+    let payload = &buf[16..];
+    if payload.len() < threshold {
+        return buf;
+    }
+    let compressed = match compression.compress(payload) {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            error!("compress frame failed, send it uncompressed: err={e}");
+            return buf;
         }
+    };
+    if compressed.len() + 9 >= payload.len() {
+        return buf;
     }
+
+    let raw_len = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+    let flags = (raw_len >> FLAGS_SHIFT) as u8 | FLAG_COMPRESSED;
+    let len = compressed.len() as u64 + 9;
+    let mut frame = Vec::with_capacity(compressed.len() + 25);
+    frame.extend_from_slice(&buf[..8]);
+    frame
+        .write_u64::<BigEndian>(len | (flags as u64) << FLAGS_SHIFT)
+        .unwrap();
+    frame.push(compression.id());
+    frame.write_u64::<BigEndian>(payload.len() as u64).unwrap();
+    frame.extend_from_slice(&compressed);
+    buf_pool::put(buf);
+    frame
+}
+
+/// the len of the checksum trailer
+fn checksum_len(flags: u8) -> u64 {
+    if flags & FLAG_CHECKSUM != 0 {
+        4
+    } else {
+        0
+    }
+}
+
+/// check the payload len of an encoded frame before sending it
+pub(crate) fn check_frame_len(buf: &[u8], max_len: usize) -> Result<(), Error> {
+    let len = buf.len() - 16;
+    if len > max_len {
+        error!("encode too big frame: len={len}, max={max_len}");
+        return Err(Error::FrameTooLarge(len, max_len));
+    }
+    Ok(())
+}
+
+/// update the id of an encoded frame
+pub(crate) fn set_frame_id(buf: &mut [u8], id: u64) {
+    buf[..8].copy_from_slice(&id.to_be_bytes());
+}
+
+/// add the flags to an encoded frame
+pub(crate) fn add_frame_flags(buf: &mut [u8], flags: u8) {
+    buf[8] |= flags;
+}
+
+/// encode the frame that half closes a stream
+pub(crate) fn encode_stream_end(id: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16);
+    buf.write_u64::<BigEndian>(id).unwrap();
+    buf.write_u64::<BigEndian>((FLAG_STREAM as u64) << FLAGS_SHIFT)
+        .unwrap();
+    buf
+}
+
+/// append the crc32c checksum to an encoded frame
+/// this must be called after the frame header is finalized
+pub(crate) fn seal_checksum(buf: &mut Vec<u8>) {
+    let raw_len = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+    let raw_len = (raw_len + 4) | (FLAG_CHECKSUM as u64) << FLAGS_SHIFT;
+    buf[8..16].copy_from_slice(&raw_len.to_be_bytes());
+    let crc = crc32c::crc32c(buf);
+    buf.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// append `len` bytes from the reader to the buf
+/// the buf grows by at most `READ_CHUNK_LEN` ahead of the received data
+fn read_payload<R: Read>(r: &mut R, buf: &mut BytesMut, len: usize) -> io::Result<()> {
+    let end = buf.len() + len;
+    while buf.len() < end {
+        let start = buf.len();
+        buf.resize(start + (end - start).min(READ_CHUNK_LEN), 0);
+        r.read_exact(&mut buf[start..])?;
+    }
+    Ok(())
+}
+
+/// convert the header of an encoded frame into the compact header
+/// this must be called after the checksum is sealed
+pub(crate) fn compact_header(buf: &mut Vec<u8>) {
+    let id = u64::from_be_bytes(buf[..8].try_into().unwrap());
+    let raw_len = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+    let mut header = [0; MAX_COMPACT_HEADER_LEN];
+    header[0] = (raw_len >> FLAGS_SHIFT) as u8;
+    let mut n = 1;
+    n += put_varint(&mut header[n..], id);
+    n += put_varint(&mut header[n..], raw_len & LEN_MASK);
+    buf.splice(..16, header[..n].iter().copied());
+}
+
+/// read the compact header, return the id and the raw len with flags
+fn read_compact_header<R: Read>(r: &mut R) -> io::Result<(u64, u64)> {
+    let flags = r.read_u8()?;
+    let id = read_varint(r)?;
+    let len = read_varint(r)?;
+    if len > LEN_MASK {
+        let s = format!("invalid compact header len: {len}");
+        return Err(io::Error::new(ErrorKind::InvalidData, s));
+    }
+    Ok((id, len | (flags as u64) << FLAGS_SHIFT))
+}
+
+/// write a LEB128 varint into the buf, return the written len
+fn put_varint(buf: &mut [u8], mut v: u64) -> usize {
+    let mut n = 0;
+    while v >= 0x80 {
+        buf[n] = v as u8 | 0x80;
+        v >>= 7;
+        n += 1;
+    }
+    buf[n] = v as u8;
+    n + 1
+}
+
+/// read a LEB128 varint
+fn read_varint<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        let b = r.read_u8()?;
+        // the 10th byte can only hold the highest bit
+        if shift == 63 && b > 1 {
+            break;
+        }
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "invalid varint"))
+}
+
+/// verify and strip the checksum of a decoded frame
+fn verify_checksum(data: &mut Bytes) -> io::Result<()> {
+    let Some(body_len) = data.len().checked_sub(20) else {
+        error!("frame checksum truncated");
+        return Err(ChecksumMismatch.into());
+    };
+    let (frame, crc) = data.split_at(body_len + 16);
+    let crc = u32::from_be_bytes(crc.try_into().unwrap());
+    if crc32c::crc32c(frame) != crc {
+        error!("frame checksum mismatch");
+        return Err(ChecksumMismatch.into());
+    }
+    data.truncate(body_len + 16);
+    Ok(())
 }
 
 /// req frame buffer that can be serialized into
-pub struct ReqBuf(Cursor<Vec<u8>>);
+pub struct ReqBuf {
+    buf: Cursor<Vec<u8>>,
+    // the frame flags of the request
+    flags: u8,
+    // the client id and the idempotency key
+    key: Option<(u64, u64)>,
+}
 
 impl Default for ReqBuf {
     fn default() -> Self {
@@ -119,21 +746,71 @@ impl Default for ReqBuf {
 }
 
 impl ReqBuf {
-    /// crate a new `ReqBuf` instance
+    /// crate a new `ReqBuf` instance, the buffer is taken from the pool
     pub fn new() -> Self {
-        let mut buf = Vec::with_capacity(128);
+        let mut buf = buf_pool::get();
         buf.resize(16, 0);
         let mut cursor = Cursor::new(buf);
         // leave enough space to write id and len
         cursor.set_position(16);
-        ReqBuf(cursor)
+        ReqBuf {
+            buf: cursor,
+            flags: 0,
+            key: None,
+        }
+    }
+
+    /// attach an idempotency key to the request
+    /// a server with the reply cache enabled runs the requests with the same `client` and `key`
+    /// only once, the retried ones get the reply of the first one
+    /// `client` should be unique among all the clients, e.g. a random number
+    pub fn set_idempotency_key(&mut self, client: u64, key: u64) {
+        self.key = Some((client, key));
+    }
+
+    /// check if the request carries an idempotency key
+    pub(crate) fn has_idempotency_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// set the frame flags of the request
+    pub(crate) fn add_flags(&mut self, flags: u8) {
+        self.flags |= flags;
+    }
+
+    /// check if the request packs a batch of requests
+    pub(crate) fn is_batch(&self) -> bool {
+        self.flags & FLAG_BATCH != 0
+    }
+
+    /// the request data that is written so far
+    pub(crate) fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.get_mut()[16..]
     }
 
     /// convert self into raw buf that can be send as a frame
-    pub fn finish(self, id: u64) -> Vec<u8> {
-        let mut cursor = self.0;
+    pub fn finish(mut self, id: u64) -> Vec<u8> {
+        if let Some((client, key)) = self.key {
+            let mut header = Vec::with_capacity(17);
+            let is_batch = self.is_batch();
+            // a request that is not a batch is sent as a single one
+            if !is_batch {
+                self.flags |= FLAG_BATCH;
+                header.push(MODE_KEYED | MODE_SINGLE);
+            }
+            header.write_u64::<BigEndian>(client).unwrap();
+            header.write_u64::<BigEndian>(key).unwrap();
+            let buf = self.buf.get_mut();
+            if is_batch {
+                // insert the key after the mode of the batch
+                buf[16] |= MODE_KEYED;
+                buf.splice(17..17, header);
+            } else {
+                buf.splice(16..16, header);
+            }
+        }
+        let mut cursor = self.buf;
         let len = cursor.get_ref().len() as u64;
-        assert!(len <= FRAME_MAX_LEN);
 
         // write from start
         cursor.set_position(0);
@@ -141,7 +818,8 @@ impl ReqBuf {
         info!("encode id = {id:?}");
 
         // adjust the data length
-        cursor.write_u64::<BigEndian>(len - 16).unwrap();
+        let flags = (self.flags as u64) << FLAGS_SHIFT;
+        cursor.write_u64::<BigEndian>((len - 16) | flags).unwrap();
         info!("encode len = {len:?}");
 
         cursor.into_inner()
@@ -150,7 +828,7 @@ impl ReqBuf {
 
 impl Write for ReqBuf {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        self.buf.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -159,7 +837,13 @@ impl Write for ReqBuf {
 }
 
 /// rsp frame buffer that can be serialized into
-pub struct RspBuf(Cursor<Vec<u8>>);
+pub struct RspBuf {
+    buf: Cursor<Vec<u8>>,
+    // the items that are sent before the response, set by the streaming methods
+    stream: Option<Box<dyn Source + Send>>,
+    // the items that are uploaded by the client of a streaming call
+    upload: Option<Remote>,
+}
 
 impl Default for RspBuf {
     fn default() -> Self {
@@ -169,34 +853,88 @@ impl Default for RspBuf {
 
 pub const SERVER_POLL_ENCODE: u8 = 200;
 impl RspBuf {
-    /// crate a new `RspBuf` instance
+    /// crate a new `RspBuf` instance, the buffer is taken from the pool
     pub fn new() -> Self {
-        let mut buf = Vec::with_capacity(128);
+        let mut buf = buf_pool::get();
         // id + len + ty + len + data
         buf.resize(25, 0);
         let mut cursor = Cursor::new(buf);
         // leave enough space to write id and len
         cursor.set_position(25);
-        RspBuf(cursor)
+        RspBuf {
+            buf: cursor,
+            stream: None,
+            upload: None,
+        }
+    }
+
+    /// send the items of the source before the response
+    pub(crate) fn set_stream(&mut self, source: Box<dyn Source + Send>) {
+        self.stream = Some(source);
+    }
+
+    /// take the items that should be sent before the response
+    pub(crate) fn take_stream(&mut self) -> Option<Box<dyn Source + Send>> {
+        self.stream.take()
+    }
+
+    /// receive the items that are uploaded by the client
+    pub(crate) fn set_upload(&mut self, upload: Remote) {
+        self.upload = Some(upload);
+    }
+
+    /// take the items that are uploaded by the client
+    pub(crate) fn take_upload(&mut self) -> Option<Remote> {
+        self.upload.take()
     }
 
     /// convert self into raw buf that can be send as a frame
+    /// a too large response is replaced by a status error
     pub fn finish(self, id: u64, ret: Result<(), WireError>) -> Vec<u8> {
-        let mut cursor = self.0;
+        self.finish_limited(id, ret, DEFAULT_MAX_FRAME_LEN)
+    }
+
+    /// the len of the data that is serialized into the buf
+    pub(crate) fn data_len(&self) -> usize {
+        self.buf.get_ref().len() - 25
+    }
+
+    /// convert self into raw buf with the given max payload len
+    pub(crate) fn finish_limited(
+        self,
+        id: u64,
+        mut ret: Result<(), WireError>,
+        max_len: usize,
+    ) -> Vec<u8> {
+        let mut cursor = self.buf;
         let dummy = Vec::new();
 
+        // the payload is ty(u8) + len(u64) + data
+        // a poll carries the request of the reverse call as the data
+        let len = match ret {
+            Ok(_) | Err(WireError::Polling) => cursor.get_ref().len() - 16,
+            Err(WireError::ServerDeserialize(ref s))
+            | Err(WireError::ServerSerialize(ref s))
+            | Err(WireError::Status(ref s)) => s.len() + 9,
+        };
+        if len > max_len {
+            error!("encode too big rsp: id={id}, len={len}, max={max_len}");
+            cursor.get_mut().truncate(25);
+            let s = format!("response frame too large: len={len}, max={max_len}");
+            ret = Err(WireError::Status(s));
+        }
+
         let (ty, len, data) = match ret {
             Ok(_) => (0, cursor.get_ref().len() - 25, dummy.as_slice()),
             Err(ref e) => match *e {
                 WireError::ServerDeserialize(ref s) => (1, s.len(), s.as_bytes()),
                 WireError::ServerSerialize(ref s) => (2, s.len(), s.as_bytes()),
                 WireError::Status(ref s) => (3, s.len(), s.as_bytes()),
-                WireError::Polling => (SERVER_POLL_ENCODE, 0, dummy.as_slice()),
+                WireError::Polling => (SERVER_POLL_ENCODE, cursor.get_ref().len() - 25, &[][..]),
             },
         };
 
         let len = len as u64;
-        assert!(len < FRAME_MAX_LEN);
 
         // write from start
         cursor.set_position(0);
@@ -215,7 +953,8 @@ impl RspBuf {
         match ty {
             0 => {} // the normal ret already wrote
             SERVER_POLL_ENCODE => {
-                // the server need to poll the client, will be filtered out by multiplex_client
+                // the server need to poll the client, the request is already wrote
+                // it's handled by the callback service of multiplex_client
             }
             1..=3 => {
                 cursor.get_mut().resize(len as usize + 25, 0);
@@ -230,10 +969,293 @@ impl RspBuf {
 
 impl Write for RspBuf {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        self.buf.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed_req(data: &[u8]) -> Vec<u8> {
+        let mut req = ReqBuf::new();
+        req.write_all(data).unwrap();
+        let mut buf = req.finish(42);
+        seal_checksum(&mut buf);
+        buf
+    }
+
+    fn decode(buf: &[u8], require_checksum: bool) -> io::Result<Frame> {
+        let mut r = Cursor::new(buf);
+        Frame::decode(
+            &mut r,
+            &mut BytesMut::new(),
+            CHUNK_LEN,
+            require_checksum,
+            false,
+        )
+    }
+
+    fn is_checksum_err(e: io::Error) -> bool {
+        matches!(Error::from(e), Error::Checksum)
+    }
+
+    #[test]
+    fn checksum_roundtrip() {
+        let buf = sealed_req(b"hello checksum");
+        let frame = decode(&buf, true).unwrap();
+        assert_eq!(frame.id, 42);
+        assert!(frame.flags & FLAG_CHECKSUM != 0);
+        assert_eq!(frame.decode_req(), b"hello checksum");
+    }
+
+    #[test]
+    fn checksum_detect_bit_flips() {
+        let buf = sealed_req(b"hello checksum");
+        for bit in 0..buf.len() * 8 {
+            let mut corrupted = buf.clone();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            // a flip in the len field may make the frame truncated or too big
+            match decode(&corrupted, true) {
+                Ok(frame) => panic!("bit {bit} flip not detected, frame={frame:?}"),
+                Err(e) if bit / 8 >= 8 && bit / 8 < 16 => assert!(
+                    matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidInput)
+                        || is_checksum_err(e),
+                    "bit {bit} flip in len, unexpected err"
+                ),
+                Err(e) => assert!(is_checksum_err(e), "bit {bit} flip, unexpected err"),
+            }
+        }
+    }
+
+    #[test]
+    fn chunked_roundtrip() {
+        let data: Vec<u8> = (0..CHUNK_LEN * 2 + 100).map(|i| i as u8).collect();
+        let mut req = ReqBuf::new();
+        req.write_all(&data).unwrap();
+        let frames = split_frame(req.finish(7), DEFAULT_MAX_FRAME_LEN);
+        assert_eq!(frames.len(), 3);
+
+        let mut assembler = Assembler::new(data.len());
+        let mut done = None;
+        for mut frame in frames {
+            seal_checksum(&mut frame);
+            match assembler.push(decode(&frame, true).unwrap()).unwrap() {
+                Assembled::Partial => assert!(done.is_none()),
+                Assembled::Done(frame) => done = Some(frame),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        let frame = done.unwrap();
+        assert_eq!(frame.id, 7);
+        assert_eq!(frame.decode_req(), &data[..]);
+
+        let mut req = ReqBuf::new();
+        req.write_all(&data).unwrap();
+        let mut assembler = Assembler::new(CHUNK_LEN);
+        let ret: Vec<_> = split_frame(req.finish(8), DEFAULT_MAX_FRAME_LEN)
+            .iter()
+            .map(|frame| assembler.push(decode(frame, false).unwrap()).unwrap())
+            .collect();
+        assert!(matches!(
+            ret[..],
+            [
+                Assembled::Partial,
+                Assembled::TooLarge { id: 8, .. },
+                Assembled::Discarded
+            ]
+        ));
+        assert!(assembler.parts.is_empty());
+    }
+
+    #[test]
+    fn partial_limits() {
+        let data = vec![0u8; CHUNK_LEN + 1];
+        let first = |id| {
+            let mut req = ReqBuf::new();
+            req.write_all(&data).unwrap();
+            let frames = split_frame(req.finish(id), DEFAULT_MAX_FRAME_LEN);
+            decode(&frames[0], false).unwrap()
+        };
+
+        // too many open ids, the discarded ones are counted too
+        let mut assembler = Assembler::with_limits(data.len(), 2, usize::MAX);
+        assert!(matches!(assembler.push(first(1)), Ok(Assembled::Partial)));
+        assembler.discard(&first(2)).unwrap();
+        assert!(assembler.push(first(3)).is_err());
+        assert!(assembler.discard(&first(3)).is_err());
+        // the frame is refused before it's buffered
+        assert_eq!(assembler.parts.len(), 2);
+
+        // too many buffered bytes
+        let mut assembler = Assembler::with_limits(data.len(), 8, CHUNK_LEN * 2 + 32);
+        assert!(matches!(assembler.push(first(1)), Ok(Assembled::Partial)));
+        assert!(matches!(assembler.push(first(2)), Ok(Assembled::Partial)));
+        assert!(assembler.push(first(3)).is_err());
+        assert_eq!(assembler.parts.len(), 2);
+        assert_eq!(assembler.partial_len, CHUNK_LEN * 2 + 32);
+        // the finished and discarded messages are not counted any more
+        let ping = decode(&Control::Ping.encode(2, &[]), false).unwrap();
+        assembler.discard(&ping).unwrap();
+        assert!(matches!(assembler.push(first(3)), Ok(Assembled::Partial)));
+        assert_eq!(assembler.partial_len, CHUNK_LEN * 2 + 32);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn compressed_roundtrip() {
+        let data = b"repetitive payload ".repeat(100);
+        let mut req = ReqBuf::new();
+        req.write_all(&data).unwrap();
+        let buf = compress_frame(req.finish(3), Compression::Lz4, 64);
+        assert!(buf.len() < data.len());
+
+        let frame = decode(&buf, false).unwrap();
+        assert!(frame.flags & FLAG_COMPRESSED != 0);
+        let err = decode(&buf, false).unwrap().decompress(100).unwrap_err();
+        assert!(matches!(Error::from(err), Error::FrameTooLarge(..)));
+        let frame = frame.decompress(data.len()).unwrap();
+        assert_eq!(frame.id, 3);
+        assert_eq!(frame.decode_req(), &data[..]);
+    }
+
+    #[test]
+    fn compact_header_roundtrip() {
+        for (id, data) in [(0, &b""[..]), (300, b"ack"), (u64::MAX, &[7; 200])] {
+            let mut req = ReqBuf::new();
+            req.write_all(data).unwrap();
+            let mut buf = req.finish(id);
+            seal_checksum(&mut buf);
+            let len = buf.len();
+            compact_header(&mut buf);
+            assert!(buf.len() < len || id == u64::MAX);
+
+            let mut r = Cursor::new(&buf);
+            let frame = Frame::decode(&mut r, &mut BytesMut::new(), CHUNK_LEN, true, true).unwrap();
+            assert_eq!(r.position() as usize, buf.len());
+            assert_eq!(frame.id, id);
+            assert_eq!(frame.decode_req(), data);
+        }
+
+        let mut req = ReqBuf::new();
+        req.write_all(b"ack").unwrap();
+        let mut buf = req.finish(1);
+        compact_header(&mut buf);
+        // flags + id + len
+        assert_eq!(buf.len(), 3 + 3);
+        // a too long varint id, and a len that overflows the flags
+        let mut len = [0; 10];
+        let n = put_varint(&mut len, LEN_MASK + 1);
+        for bad in [&[0xff; 11][..], &[&[0], &len[..n]].concat()] {
+            let mut buf = buf.clone();
+            buf.splice(1..3, bad.iter().copied());
+            let mut r = Cursor::new(&buf);
+            let err = Frame::decode(&mut r, &mut BytesMut::new(), CHUNK_LEN, false, true);
+            assert_eq!(err.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn decode_bounded_by_data() {
+        // a header that claims a large payload, followed by a few bytes
+        let mut buf = ReqBuf::new().finish(1);
+        buf[8..16].copy_from_slice(&(DEFAULT_MAX_FRAME_LEN as u64).to_be_bytes());
+        buf.extend_from_slice(&[1; 100]);
+        let mut r = Cursor::new(&buf);
+        let mut data = BytesMut::new();
+        let err = Frame::decode(&mut r, &mut data, DEFAULT_MAX_FRAME_LEN, false, false);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert!(data.capacity() <= READ_CHUNK_LEN * 2);
+    }
+
+    #[test]
+    fn decode_invalid_rsp() {
+        let mut rsp = RspBuf::new();
+        rsp.write_all(b"rsp").unwrap();
+        let buf = rsp.finish(1, Ok(()));
+        assert_eq!(decode(&buf, false).unwrap().decode_rsp().unwrap(), b"rsp");
+
+        // the inner len doesn't match the payload
+        let mut bad = buf.clone();
+        bad[17..25].copy_from_slice(&u64::MAX.to_be_bytes());
+        let err = decode(&bad, false).unwrap().decode_rsp().unwrap_err();
+        assert!(matches!(err, Error::ClientDeserialize(_)));
+
+        // the status is not utf8
+        let mut bad = buf;
+        bad[16] = 3;
+        bad[25] = 0xff;
+        let err = decode(&bad, false).unwrap().decode_rsp().unwrap_err();
+        assert!(matches!(err, Error::Status(_)));
+    }
+
+    #[test]
+    fn poll_roundtrip() {
+        let mut rsp = RspBuf::new();
+        rsp.write_all(b"req").unwrap();
+        let mut buf = rsp.finish(1, Err(WireError::Polling));
+        add_frame_flags(&mut buf, FLAG_REVERSE);
+        let frame = decode(&buf, false).unwrap();
+        assert!(frame.is_reverse());
+        assert_eq!(frame.decode_poll().unwrap(), b"req");
+        // a poll is never a response
+        assert!(frame.decode_rsp().is_err());
+
+        let buf = RspBuf::new().finish(1, Ok(()));
+        assert!(decode(&buf, false).unwrap().decode_poll().is_err());
+    }
+
+    #[test]
+    fn req_flags_roundtrip() {
+        let mut req = ReqBuf::new();
+        req.add_flags(FLAG_BATCH);
+        req.write_all(b"batch").unwrap();
+        assert!(req.is_batch());
+        let buf = req.finish(7);
+        let frame = decode(&buf, false).unwrap();
+        assert!(frame.is_batch());
+        assert!(!frame.is_oneway());
+        assert_eq!(frame.decode_req(), b"batch");
+    }
+
+    #[test]
+    fn req_idempotency_key() {
+        let mut req = ReqBuf::new();
+        req.write_all(b"req").unwrap();
+        req.set_idempotency_key(1, 2);
+        let frame = decode(&req.finish(7), false).unwrap();
+        assert!(frame.is_batch());
+        let data = frame.decode_req();
+        assert_eq!(data[0], MODE_KEYED | MODE_SINGLE);
+        assert_eq!(
+            &data[1..17],
+            &[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2]
+        );
+        assert_eq!(&data[17..], b"req");
+
+        // the key follows the mode of a batch
+        let mut req = ReqBuf::new();
+        req.add_flags(FLAG_BATCH);
+        req.write_all(&[MODE_PARALLEL, 9]).unwrap();
+        req.set_idempotency_key(1, 2);
+        let frame = decode(&req.finish(7), false).unwrap();
+        let data = frame.decode_req();
+        assert_eq!(data[0], MODE_KEYED | MODE_PARALLEL);
+        assert_eq!(data.len(), 18);
+        assert_eq!(data[17], 9);
+    }
+
+    #[test]
+    fn checksum_required() {
+        let mut req = ReqBuf::new();
+        req.write_all(b"no checksum").unwrap();
+        let buf = req.finish(1);
+        assert!(decode(&buf, false).is_ok());
+        let err = decode(&buf, true).unwrap_err();
+        assert!(matches!(Error::from(err), Error::Checksum));
+    }
+}
@@ -0,0 +1,55 @@
+use std::io::Cursor;
+
+use bytes::BytesMut;
+
+use super::errors::as_too_large;
+use super::frame::{Assembled, Assembler, Decoder};
+use super::handshake::Settings;
+
+/// the max payload len of a frame
+const MAX_FRAME_LEN: usize = 1024;
+/// the max payload len of an assembled message
+const MAX_MESSAGE_LEN: usize = 4096;
+/// the max number and total len of the partial messages
+const MAX_PARTS: usize = 4;
+const MAX_PARTIAL_LEN: usize = 8192;
+
+/// decode the frames of a connection with the negotiated settings
+/// and reassemble the continuation frames into messages like the server does
+pub fn decode_assemble(data: &[u8], checksum: bool, compact_header: bool) {
+    let settings = Settings {
+        checksum,
+        compact_header,
+        ..Settings::default()
+    };
+    let mut decoder = Decoder::new(MAX_FRAME_LEN, settings);
+    let mut assembler = Assembler::with_limits(MAX_MESSAGE_LEN, MAX_PARTS, MAX_PARTIAL_LEN);
+    let mut r = Cursor::new(data);
+    let mut buf = BytesMut::new();
+    loop {
+        let frame = match decoder.decode(&mut r, &mut buf) {
+            Ok(frame) => frame,
+            Err(e) => match as_too_large(&e) {
+                Some(too_large) if decoder.skip(&mut r, too_large).is_ok() => continue,
+                _ => return,
+            },
+        };
+        if frame.is_control() {
+            let _ = frame.decode_control();
+            continue;
+        }
+        match assembler.push(frame) {
+            Ok(Assembled::Done(msg)) => {
+                assert!(msg.decode_req().len() <= MAX_MESSAGE_LEN);
+                if let Ok(msg) = msg.decompress(MAX_MESSAGE_LEN) {
+                    let _ = msg.decode_req();
+                    let _ = msg.decode_rsp();
+                }
+            }
+            Ok(Assembled::TooLarge { len, .. }) => assert!(len > MAX_MESSAGE_LEN),
+            Ok(Assembled::Partial | Assembled::Discarded) => {}
+            // the peer exceeds the partial message limits, the connection is closed
+            Err(_) => return,
+        }
+    }
+}
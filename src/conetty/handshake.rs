@@ -0,0 +1,221 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::compress::Compression;
+use super::config::ServerConfig;
+use super::stream_ext::StreamExt;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use may::{coroutine, go};
+
+// preface layout, exchanged once the connection is established
+// magic([u8; 6]) + version(u16) + caps(u32)
+// the client sends its preface first, then the server replies with the accepted caps
+// the caps bits that are unknown to the peer are ignored, so new capabilities
+// can be added without bumping the version
+
+/// the magic bytes at the beginning of a connection
+const MAGIC: &[u8; 6] = b"MAYRPC";
+/// the wire protocol version
+pub(crate) const PROTOCOL_VERSION: u16 = 1;
+/// the default max time to wait for the peer preface
+pub(crate) const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the peer supports crc32c checksum
+pub(crate) const CAP_CHECKSUM: u32 = 0x01;
+/// the peer supports lz4 compression
+#[cfg(feature = "lz4")]
+pub(crate) const CAP_LZ4: u32 = 0x02;
+/// the peer supports zstd compression
+#[cfg(feature = "zstd")]
+pub(crate) const CAP_ZSTD: u32 = 0x04;
+/// the peer supports the compact frame header
+pub(crate) const CAP_COMPACT_HEADER: u32 = 0x08;
+/// the client serves the reverse calls from the server by its callback service
+pub(crate) const CAP_REVERSE: u32 = 0x10;
+/// reserved, the payloads can be encoded by a codec other than bincode
+const CAP_CODEC: u32 = 0x20;
+/// reserved, the requests can carry metadata besides the payload
+const CAP_METADATA: u32 = 0x40;
+
+/// the connection settings that both peers agree on
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Settings {
+    /// all the frames are protected by crc32c checksum
+    pub checksum: bool,
+    /// the payloads are compressed by the algorithm
+    pub compression: Option<Compression>,
+    /// the frame headers are encoded with varint id and len
+    pub compact_header: bool,
+    /// the server can call the callback service of the client
+    pub reverse: bool,
+}
+
+impl Settings {
+    /// the settings that the caps represent
+    fn from_caps(caps: u32) -> Self {
+        Settings {
+            checksum: caps & CAP_CHECKSUM != 0,
+            compression: Compression::from_caps(caps),
+            compact_header: caps & CAP_COMPACT_HEADER != 0,
+            reverse: caps & CAP_REVERSE != 0,
+        }
+    }
+}
+
+/// the preface of a connection
+#[derive(Debug)]
+struct Preface {
+    version: u16,
+    caps: u32,
+}
+
+impl Preface {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(12);
+        buf.extend_from_slice(MAGIC);
+        buf.write_u16::<BigEndian>(self.version)?;
+        buf.write_u32::<BigEndian>(self.caps)?;
+        w.write_all(&buf)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 6];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            let s = format!("unexpected magic bytes {magic:?}, the peer is not a may_rpc endpoint");
+            return Err(handshake_err(s));
+        }
+        let version = r.read_u16::<BigEndian>()?;
+        let caps = r.read_u32::<BigEndian>()?;
+        Ok(Preface { version, caps })
+    }
+}
+
+fn handshake_err(s: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("handshake failed: {s}"))
+}
+
+/// run the handshake, the stream is shutdown if the peer doesn't answer in time
+fn with_timeout<S, T, F>(ctrl: S, timeout: Duration, f: F) -> io::Result<T>
+where
+    S: StreamExt,
+    F: FnOnce() -> io::Result<T>,
+{
+    // set by whoever finishes first, the handshake or the watchdog
+    let done = Arc::new(AtomicBool::new(false));
+    let expired = done.clone();
+    // the watchdog must be cancelled explicitly, a managed coroutine that is
+    // not started yet would not be cancelled and shutdown the stream later
+    let watchdog = go!(move || {
+        coroutine::sleep(timeout);
+        if !expired.swap(true, Ordering::AcqRel) {
+            ctrl.shutdown().ok();
+        }
+    });
+    let ret = f();
+    let expired = done.swap(true, Ordering::AcqRel);
+    unsafe { watchdog.coroutine().cancel() };
+    watchdog.join().ok();
+    match ret {
+        Err(_) if expired => {
+            let s = "no preface from the peer".to_owned();
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("handshake failed: {s}"),
+            ))
+        }
+        ret => ret,
+    }
+}
+
+/// send the client preface and wait for the server preface
+/// return the settings that the server accepts
+pub(crate) fn client_handshake<S: StreamExt>(
+    stream: &mut S,
+    caps: u32,
+    timeout: Duration,
+) -> io::Result<Settings> {
+    with_timeout(stream.try_clone()?, timeout, || {
+        let version = PROTOCOL_VERSION;
+        Preface { version, caps }.write_to(stream)?;
+        let preface = Preface::read_from(stream)?;
+        if preface.version != version {
+            let s = format!(
+                "unsupported protocol version, server={}, client={version}",
+                preface.version
+            );
+            return Err(handshake_err(s));
+        }
+        info!(
+            "client handshake: caps={caps:#x}, accepted={:#x}",
+            preface.caps
+        );
+        // the server should never accept what is not asked
+        Ok(Settings::from_caps(preface.caps & caps))
+    })
+}
+
+/// wait for the client preface and reply with the accepted caps
+/// `r` and `w` are the read and write half of `stream`
+pub(crate) fn server_handshake<S, R, W>(
+    stream: &S,
+    r: &mut R,
+    w: &mut W,
+    config: &ServerConfig,
+) -> io::Result<Settings>
+where
+    S: StreamExt,
+    R: Read,
+    W: Write,
+{
+    with_timeout(stream.try_clone()?, config.handshake_timeout(), || {
+        let preface = Preface::read_from(r)?;
+        let version = PROTOCOL_VERSION;
+        if preface.version != version {
+            // reply our version so that the client can report a clear error
+            Preface { version, caps: 0 }.write_to(w)?;
+            let s = format!(
+                "unsupported protocol version, server={version}, client={}",
+                preface.version
+            );
+            return Err(handshake_err(s));
+        }
+
+        // the codec and metadata are not supported by this version yet
+        let reserved = preface.caps & (CAP_CODEC | CAP_METADATA);
+        if reserved != 0 {
+            info!("server handshake: ignore the reserved caps {reserved:#x}");
+        }
+        // the reverse calls are always supported
+        let mut supported = CAP_REVERSE;
+        if config.checksum() {
+            supported |= CAP_CHECKSUM;
+        }
+        if config.compression() {
+            supported |= Compression::supported_caps();
+        }
+        if config.compact_header() {
+            supported |= CAP_COMPACT_HEADER;
+        }
+        let mut accepted = preface.caps & supported;
+        // accept only one compression algorithm
+        let settings = Settings::from_caps(accepted);
+        accepted &= CAP_CHECKSUM
+            | CAP_COMPACT_HEADER
+            | CAP_REVERSE
+            | settings.compression.map_or(0, Compression::cap);
+        info!(
+            "server handshake: caps={:#x}, accepted={accepted:#x}",
+            preface.caps
+        );
+        Preface {
+            version,
+            caps: accepted,
+        }
+        .write_to(w)?;
+        Ok(settings)
+    })
+}
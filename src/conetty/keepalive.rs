@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use may::coroutine;
+
+/// heartbeat and idle timeout settings of a connection
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct KeepAlive {
+    // send a ping frame every interval
+    pub heartbeat: Option<Duration>,
+    // close the connection after missing so many pongs
+    pub max_missed: usize,
+    // close the connection if it's idle longer than this
+    pub idle_timeout: Option<Duration>,
+}
+
+/// the reason why the keepalive check expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Expired {
+    /// no traffic for longer than the idle timeout
+    Idle,
+    /// missed too many heartbeats
+    Heartbeat,
+}
+
+/// the liveness state of a connection
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    start: Instant,
+    // the last time in ms that there is traffic from the start
+    last_active: AtomicU64,
+    // number of the outstanding requests
+    inflight: AtomicUsize,
+    // number of pings that not get a pong
+    missed: AtomicUsize,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Liveness {
+            start: Instant::now(),
+            last_active: AtomicU64::new(0),
+            inflight: AtomicUsize::new(0),
+            missed: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Liveness {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    /// a request starts on the connection
+    pub fn begin(&self) {
+        self.inflight.fetch_add(1, Ordering::AcqRel);
+        self.last_active.store(self.now(), Ordering::Release);
+    }
+
+    /// a request is done on the connection
+    pub fn end(&self) {
+        self.last_active.store(self.now(), Ordering::Release);
+        self.inflight.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// number of the outstanding requests
+    pub fn inflight(&self) -> usize {
+        self.inflight.load(Ordering::Acquire)
+    }
+
+    /// got a pong from the peer
+    pub fn pong(&self) {
+        self.missed.store(0, Ordering::Release);
+    }
+
+    fn idle_for(&self) -> Duration {
+        if self.inflight.load(Ordering::Acquire) > 0 {
+            return Duration::ZERO;
+        }
+        let last = self.last_active.load(Ordering::Acquire);
+        Duration::from_millis(self.now().saturating_sub(last))
+    }
+}
+
+impl KeepAlive {
+    /// check if need to run the keepalive loop
+    pub fn enabled(&self) -> bool {
+        self.heartbeat.is_some() || self.idle_timeout.is_some()
+    }
+
+    /// run the keepalive check until it expired
+    /// `ping` is used to send a ping frame with the given id to the peer
+    pub fn run(&self, live: &Liveness, ping: impl Fn(u64)) -> Expired {
+        // check the idle state more frequently than the timeout
+        let tick = match (self.heartbeat, self.idle_timeout.map(|d| d / 2)) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => unreachable!("keepalive is not enabled"),
+        };
+        let mut next_ping = Instant::now() + self.heartbeat.unwrap_or(tick);
+        let mut seq = 0;
+        loop {
+            coroutine::sleep(tick);
+            if let Some(idle) = self.idle_timeout {
+                if live.idle_for() >= idle {
+                    return Expired::Idle;
+                }
+            }
+            if let Some(interval) = self.heartbeat {
+                let now = Instant::now();
+                if now < next_ping {
+                    continue;
+                }
+                next_ping = now + interval;
+                if live.missed.fetch_add(1, Ordering::AcqRel) >= self.max_missed {
+                    return Expired::Heartbeat;
+                }
+                seq += 1;
+                ping(seq);
+            }
+        }
+    }
+}
@@ -11,16 +11,28 @@
 //! data `Vec<u8>`. you need to prepare and parsing it in the actual process functions that passed into
 //! the framework
 //!
+pub use batch::{Batch, BatchItem, BatchResults};
+pub use buf_pool::BufPoolStats;
+pub use circuit::{CircuitBreaker, CircuitPolicy, CircuitState, CircuitStats};
+pub use compress::{Compression, CompressionStats};
+pub use config::{ClientConfig, ServerConfig};
+pub use connection::OnewayStats;
+pub use context::{ConnState, Reject, ReqContext};
 pub use errors::{Error, WireError};
 pub use frame::{Frame, ReqBuf, RspBuf};
-pub use multiplex_client::MultiplexClient;
-pub use server::{ServerInstance, TcpServer, UdpServer};
+pub use multiplex_client::{ClientStats, MultiplexClient};
+pub use pending::{join_all, PendingCall};
+pub use reply_cache::ReplyCacheStats;
+pub use retry::{RetryBudget, RetryCode, RetryPolicy};
+pub use reverse::ReverseClient;
+pub use server::{DisconnectReason, Peer, ServerInstance, TcpServer, TcpSessionServer, UdpServer};
 pub use stream_client::StreamClient;
 pub use stream_ext::StreamExt;
+pub use streaming::{Sender, Stream};
 pub use udp_client::UdpClient;
 
 #[cfg(unix)]
-pub use server::UdsServer;
+pub use server::{UdsServer, UdsSessionServer};
 
 /// rpc client trait
 pub trait Client {
@@ -28,6 +40,18 @@ pub trait Client {
     /// the request must be encoded into the ReqBuf
     /// the response is the raw frame, you should parsing it into final response
     fn call_service(&self, req: ReqBuf) -> Result<Frame, Error>;
+
+    /// call an idempotent method, it may be retried by the retry policy of the client
+    /// the default impl calls it only once
+    fn call_idempotent(&self, req: ReqBuf) -> Result<Frame, Error> {
+        self.call_service(req)
+    }
+
+    /// call the server without waiting for the response
+    /// the default impl waits for the response and drops it
+    fn call_oneway(&self, req: ReqBuf) -> Result<(), Error> {
+        self.call_service(req).map(drop)
+    }
 }
 
 /// must impl this trait for your server
@@ -41,14 +65,55 @@ pub trait Server: Send + Sync + Sized + 'static {
     fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError>;
 }
 
+/// create a service instance for each accepted stream connection
+/// the session serves all the requests of the connection and is dropped on disconnect
+/// this is useful for session style protocols, e.g. login once and then do authorized calls
+pub trait ServiceFactory: Send + Sync + Sized + 'static {
+    /// the service type of the session
+    type Session: Server;
+    /// create a new session for the connection from the peer
+    fn new_session(&self, peer: &Peer) -> Self::Session;
+}
+
+/// Provides the batch calls
+mod batch;
+/// reusable frame buffers
+mod buf_pool;
+/// Provides the circuit breaker of the clients
+mod circuit;
+/// payload compression
+mod compress;
+/// Provides server and client configurations
+mod config;
+/// Provides the stream server connection
+mod connection;
+/// Provides the per request context
+mod context;
 /// Provides a few different error types
 mod errors;
 /// raw frame protocol
 mod frame;
+/// the entry points of the fuzz targets
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+/// connection preface and capability negotiation
+mod handshake;
+/// heartbeat and idle timeout
+mod keepalive;
 mod multiplex_client;
+/// Provides the handles of the calls that are not waited yet
+mod pending;
 mod queued_writer;
+/// Provides the reply cache of the keyed requests
+mod reply_cache;
+/// Provides the retry policy of the clients
+mod retry;
+/// Provides the reverse calls from the server to the client
+mod reverse;
 /// Provides server framework
 mod server;
+/// Provides the streaming calls
+mod streaming;
 
 /// Provide stream client
 mod stream_client;
@@ -1,105 +1,1062 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::io::{self, BufReader};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
+use super::compress::Compression;
+use super::config::ClientConfig;
 use super::errors::Error;
-use super::frame::{Frame, ReqBuf};
+use super::errors::{as_too_large, is_checksum_err, ChecksumMismatch, FrameTooLarge};
+use super::frame::{
+    add_frame_flags, check_frame_len, compact_header, compress_frame, encode_stream_end,
+    seal_checksum, set_frame_id, split_frame,
+};
+use super::frame::{Assembled, Assembler, Control, Decoder, Frame, ReqBuf, RspBuf};
+use super::frame::{FLAG_ONEWAY, FLAG_REVERSE, FLAG_STREAM};
+use super::handshake::{client_handshake, CAP_CHECKSUM, CAP_COMPACT_HEADER, CAP_REVERSE};
+use super::keepalive::{Expired, Liveness};
+use super::pending::PendingCall;
 use super::queued_writer::QueuedWriter;
-use super::stream_ext::StreamExt;
-use super::Client;
+use super::retry::is_unsent;
+use super::stream_ext::{StreamExt, VectoredWriter};
+use super::streaming::{
+    send_items, Items, Remote, Sender, Sent, SinkConn, Source, Stream, StreamConn, Upload, Window,
+};
+use super::{Client, WireError};
 
 use bytes::BytesMut;
-use may::io::SplitWriter;
+use co_managed::Manager;
+use may::sync::{mpsc, Mutex, RwLock};
 use may::{coroutine, go};
 use may_waiter::TokenWaiter;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
-/// Multiplexed Client
-pub struct MultiplexClient<S: StreamExt> {
-    // default timeout is 10s
-    timeout: Option<Duration>,
+type RspWaiter = TokenWaiter<io::Result<Frame>>;
+
+/// max times to resend a request that is refused by a going away server
+const MAX_RESEND: usize = 3;
+
+/// the waiter of an outstanding request
+enum Waiter {
+    /// a unary call that waits for the response
+    Call(may_waiter::ID),
+    /// a streaming call that receives the item frames and the response
+    /// the window is used to upload the items of the call
+    Stream(mpsc::Sender<io::Result<Frame>>, Arc<Window>),
+    /// a started call whose response is received by the `PendingCall` handle
+    Pending(mpsc::Sender<io::Result<Frame>>),
+}
+
+impl Waiter {
+    /// deliver the response to the waiter
+    fn set_rsp(self, rsp: io::Result<Frame>) {
+        match self {
+            Waiter::Call(id) => RspWaiter::set_rsp(id, rsp),
+            Waiter::Stream(tx, window) => {
+                // the call is closed, no more items need to be uploaded
+                window.cancel();
+                tx.send(rsp).unwrap_or(())
+            }
+            Waiter::Pending(tx) => tx.send(rsp).unwrap_or(()),
+        }
+    }
+}
+
+/// the outstanding requests of a connection
+#[derive(Default)]
+struct Pending {
+    // the id of the next request, ids are increasing within a connection
+    next_id: u64,
+    // the waiters of the outstanding requests
+    waiters: HashMap<u64, Waiter>,
+    // the server sent GOAWAY, no new requests are allowed
+    going_away: bool,
+    // the connection is closed
+    closed: bool,
+}
+
+/// the counters of a client, they are kept across reconnections
+#[derive(Debug, Default)]
+struct Counters {
+    unknown_rsps: AtomicU64,
+    stale_rsps: AtomicU64,
+}
+
+/// a snapshot of the client counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientStats {
+    /// dropped responses with an id that was never sent
+    pub unknown_rsps: u64,
+    /// dropped responses of the requests that are already answered or timed out
+    pub stale_rsps: u64,
+}
+
+/// the state shared by the client and the background coroutines
+struct Inner<S: StreamExt> {
     // the connection
-    sock: QueuedWriter<SplitWriter<S>>,
-    // the listening coroutine
-    listener: Option<coroutine::JoinHandle<()>>,
+    sock: QueuedWriter<VectoredWriter<S>>,
+    // used to shutdown the connection
+    ctrl: Mutex<S>,
+    // the outstanding requests
+    pending: Mutex<Pending>,
+    // used for heartbeat and idle check
+    live: Liveness,
+    // the counters of the client
+    counters: Arc<Counters>,
+    // protect the frames by checksum, negotiated in the handshake
+    checksum: bool,
+    // encode the frames with the compact header, negotiated in the handshake
+    compact_header: bool,
+    // the max payload len of a frame, large requests are split by it
+    max_frame_len: usize,
+    // compress the requests with the threshold, negotiated in the handshake
+    compression: Option<(Compression, usize)>,
 }
 
-impl<S: StreamExt> fmt::Debug for MultiplexClient<S> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("MultiplexClient")
-            .field("timeout", &self.timeout)
-            .field("listener", &self.listener)
-            .finish()
+impl<S: StreamExt> Inner<S> {
+    /// write a frame that is not tracked by the pending table
+    fn write(&self, mut buf: Vec<u8>) -> io::Result<()> {
+        if self.checksum {
+            seal_checksum(&mut buf);
+        }
+        if self.compact_header {
+            compact_header(&mut buf);
+        }
+        self.sock.write(buf)
+    }
+
+    /// check if new requests can be sent on the connection
+    fn is_usable(&self) -> bool {
+        let pending = self.pending.lock().unwrap();
+        !pending.closed && !pending.going_away
+    }
+
+    /// assign an id to the request and send it
+    /// the response is dropped if there is no waiter
+    fn send(&self, buf: Vec<u8>, waiter: Option<Waiter>) -> io::Result<u64> {
+        let mut buf = match self.compression {
+            Some((c, threshold)) => compress_frame(buf, c, threshold),
+            None => buf,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        if pending.closed || pending.going_away {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "connection closed",
+            ));
+        }
+        pending.next_id += 1;
+        let id = pending.next_id;
+        set_frame_id(&mut buf, id);
+        let mut frames = split_frame(buf, self.max_frame_len);
+        if self.checksum {
+            frames.iter_mut().for_each(seal_checksum);
+        }
+        if self.compact_header {
+            frames.iter_mut().for_each(compact_header);
+        }
+        let mut frames = frames.into_iter();
+        if let Some(waiter) = waiter {
+            pending.waiters.insert(id, waiter);
+        }
+        // the requests must be queued in the id order, so that GOAWAY can tell
+        // which of them are processed by the server
+        let need_flush = self.sock.push(frames.next().unwrap());
+        drop(pending);
+
+        let ret = if need_flush {
+            self.sock.flush()
+        } else {
+            Ok(())
+        };
+        // the continuation frames interleave with the frames of other requests
+        let ret = ret.and_then(|_| {
+            frames.try_for_each(|frame| {
+                coroutine::yield_now();
+                self.sock.write(frame)
+            })
+        });
+        if let Err(e) = ret {
+            self.close(&format!("write failed: {e}"));
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// write a message that is not tracked by the pending table
+    /// a large one is split into continuation frames
+    fn write_msg(&self, buf: Vec<u8>) -> io::Result<()> {
+        let buf = match self.compression {
+            Some((c, threshold)) => compress_frame(buf, c, threshold),
+            None => buf,
+        };
+        let frames = split_frame(buf, self.max_frame_len);
+        let last = frames.len() - 1;
+        for (i, frame) in frames.into_iter().enumerate() {
+            if let Err(e) = self.write(frame) {
+                self.close(&format!("write failed: {e}"));
+                return Err(e);
+            }
+            if i < last {
+                coroutine::yield_now();
+            }
+        }
+        Ok(())
+    }
+
+    /// get the upload window of a streaming call
+    fn stream_window(&self, id: u64) -> Option<Arc<Window>> {
+        match self.pending.lock().unwrap().waiters.get(&id) {
+            Some(Waiter::Stream(_, window)) => Some(window.clone()),
+            _ => None,
+        }
+    }
+
+    fn remove_pending(&self, id: u64) -> Option<Waiter> {
+        self.pending.lock().unwrap().waiters.remove(&id)
+    }
+
+    /// wake up the waiter of the response
+    /// only the ids in the pending table are trusted, other responses are dropped
+    fn set_rsp(&self, id: u64, rsp: io::Result<Frame>) {
+        // the waiter must be triggered within the lock, or it may be already dropped
+        let mut pending = self.pending.lock().unwrap();
+        match pending.waiters.get(&id) {
+            // the stream is open until the response is received
+            Some(Waiter::Stream(tx, _)) if rsp.as_ref().is_ok_and(Frame::is_stream) => {
+                tx.send(rsp).unwrap_or(())
+            }
+            Some(_) => pending.waiters.remove(&id).unwrap().set_rsp(rsp),
+            None => self.count_dropped(&pending, id),
+        }
+    }
+
+    /// check if the response is expected
+    fn is_pending(&self, id: u64) -> bool {
+        self.pending.lock().unwrap().waiters.contains_key(&id)
+    }
+
+    /// drop a response that is not expected
+    fn drop_rsp(&self, id: u64) {
+        let pending = self.pending.lock().unwrap();
+        self.count_dropped(&pending, id);
+    }
+
+    fn count_dropped(&self, pending: &Pending, id: u64) {
+        if id == 0 || id > pending.next_id {
+            error!("drop rsp with unknown id={id}");
+            self.counters.unknown_rsps.fetch_add(1, Ordering::Relaxed);
+        } else {
+            info!("drop stale rsp, id={id}");
+            self.counters.stale_rsps.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// the server would not process requests after `last_id`, fail them
+    fn go_away(&self, last_id: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        info!("multiplex_client got GOAWAY: last_id={last_id}");
+        pending.going_away = true;
+        let refused: Vec<_> = pending
+            .waiters
+            .keys()
+            .filter(|id| **id > last_id)
+            .copied()
+            .collect();
+        for id in refused {
+            let waiter = pending.waiters.remove(&id).unwrap();
+            let err = io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "refused by going away server",
+            );
+            waiter.set_rsp(Err(err));
+        }
+    }
+
+    /// close the connection and fail all the outstanding requests
+    fn close(&self, reason: &str) {
+        self.abort(reason, || {
+            io::Error::new(io::ErrorKind::ConnectionAborted, reason.to_owned())
+        });
+    }
+
+    /// close the connection and fail all the outstanding requests with the given error
+    fn abort(&self, reason: &str, err: impl Fn() -> io::Error) {
+        // the waiters must be triggered within the lock, or they may be already dropped
+        let mut pending = self.pending.lock().unwrap();
+        // new requests would fail once the connection is marked closed
+        if pending.closed {
+            return;
+        }
+        pending.closed = true;
+        info!("multiplex_client connection closed: {reason}");
+        for (_, waiter) in pending.waiters.drain() {
+            waiter.set_rsp(Err(err()));
+        }
+        drop(pending);
+        self.ctrl.lock().unwrap().shutdown().ok();
     }
 }
 
-impl<S: StreamExt> Drop for MultiplexClient<S> {
+/// a single connection and its background coroutines
+struct Conn<S: StreamExt> {
+    // the shared state
+    inner: Arc<Inner<S>>,
+    // the listening coroutine
+    listener: Option<coroutine::JoinHandle<()>>,
+    // the heartbeat coroutine
+    keepalive: Option<coroutine::JoinHandle<()>>,
+}
+
+impl<S: StreamExt> Drop for Conn<S> {
     fn drop(&mut self) {
-        if let Some(h) = self.listener.take() {
+        for h in [self.keepalive.take(), self.listener.take()]
+            .into_iter()
+            .flatten()
+        {
             unsafe { h.coroutine().cancel() };
             h.join().ok();
         }
     }
 }
 
-impl<S: StreamExt> MultiplexClient<S> {
-    /// connect to the server address
-    pub fn new(stream: S) -> io::Result<Self> {
+impl<S: StreamExt> Conn<S> {
+    fn new(mut stream: S, config: &ClientConfig, counters: Arc<Counters>) -> io::Result<Self> {
+        let mut caps = 0;
+        if config.checksum() {
+            caps |= CAP_CHECKSUM;
+        }
+        if config.compact_header() {
+            caps |= CAP_COMPACT_HEADER;
+        }
+        if let Some((compression, _)) = config.compression() {
+            caps |= compression.cap();
+        }
+        if config.callback().is_some() {
+            caps |= CAP_REVERSE;
+        }
+        // negotiate the connection capabilities before any request
+        let settings = client_handshake(&mut stream, caps, config.handshake_timeout())?;
+        let threshold = config.compression().map_or(0, |(_, threshold)| threshold);
+
+        let ctrl = stream.try_clone()?;
         // here we must clone the socket for read
         // we can't share it between coroutines
         let (reader, writer) = stream.split()?;
+        let inner = Arc::new(Inner {
+            sock: QueuedWriter::new(VectoredWriter(writer)),
+            ctrl: Mutex::new(ctrl),
+            pending: Mutex::new(Pending::default()),
+            live: Liveness::default(),
+            counters,
+            checksum: settings.checksum,
+            compact_header: settings.compact_header,
+            max_frame_len: config.max_frame_len(),
+            compression: settings.compression.map(|c| (c, threshold)),
+        });
+
+        let max_len = config.max_frame_len();
+        let max_msg_len = config.max_message_len();
+        let (max_parts, max_partial_len) = config.max_partial_messages();
         let mut r_stream = BufReader::new(reader);
+        let listener_inner = inner.clone();
+        // the server never sends reverse calls if they are not accepted
+        let callback = config.callback().filter(|_| settings.reverse).cloned();
         let listener = go!(
             coroutine::Builder::new().name("MultiPlexClientListener".to_owned()),
             move || {
+                let inner = listener_inner;
                 let mut buf = BytesMut::with_capacity(1024 * 32);
+                let mut decoder = Decoder::new(max_len, settings);
+                let new_assembler =
+                    || Assembler::with_limits(max_msg_len, max_parts, max_partial_len);
+                let mut assembler = new_assembler();
+                // the requests of the reverse calls, their ids are assigned by the server
+                let mut reverse_assembler = new_assembler();
+                // the outstanding reverse calls, they are cancelled with the listener
+                let calls = Manager::new();
                 loop {
-                    let rsp_frame = match Frame::decode_from(&mut r_stream, &mut buf) {
+                    let rsp_frame = match decoder.decode(&mut r_stream, &mut buf) {
                         Ok(r) => r,
+                        Err(e) if as_too_large(&e).is_some() => {
+                            let too_large = as_too_large(&e).unwrap();
+                            if let Err(e) = decoder.skip(&mut r_stream, too_large) {
+                                error!("tcp multiplex_client skip rsp: err = {e:?}");
+                                inner.close(&format!("connection closed: {e}"));
+                                break;
+                            }
+                            if !too_large.control {
+                                inner.set_rsp(too_large.id, Err(e));
+                            }
+                            continue;
+                        }
+                        Err(ref e) if is_checksum_err(e) => {
+                            error!("tcp multiplex_client decode rsp: checksum failed");
+                            inner.abort("frame checksum mismatch", || {
+                                io::Error::from(ChecksumMismatch)
+                            });
+                            break;
+                        }
                         Err(ref e) => {
                             if e.kind() == io::ErrorKind::UnexpectedEof {
                                 info!("tcp multiplex_client decode rsp: connection closed");
                             } else {
                                 error!("tcp multiplex_client decode rsp: err = {e:?}");
                             }
+                            inner.close(&format!("connection closed: {e}"));
+                            break;
+                        }
+                    };
+
+                    if rsp_frame.is_control() {
+                        match rsp_frame.decode_control() {
+                            Some((Control::Ping, body)) => {
+                                let pong = Control::Pong.encode(rsp_frame.id, body);
+                                inner.write(pong).ok();
+                            }
+                            Some((Control::Pong, _)) => inner.live.pong(),
+                            Some((Control::GoAway, _)) => inner.go_away(rsp_frame.id),
+                            Some((Control::Credit, body)) => {
+                                match (inner.stream_window(rsp_frame.id), body.try_into()) {
+                                    (Some(window), Ok(n)) => window.grant(u32::from_be_bytes(n)),
+                                    _ => info!("ignore stream credit: id={}", rsp_frame.id),
+                                }
+                            }
+                            Some((Control::Cancel, _)) => {
+                                if let Some(window) = inner.stream_window(rsp_frame.id) {
+                                    info!("upload cancelled by the server: id={}", rsp_frame.id);
+                                    window.cancel();
+                                }
+                            }
+                            _ => info!("ignore unexpected control frame: id={}", rsp_frame.id),
+                        }
+                        continue;
+                    }
+                    if rsp_frame.is_reverse() {
+                        let Some(ref callback) = callback else {
+                            info!("ignore unexpected reverse call: id={}", rsp_frame.id);
+                            continue;
+                        };
+                        let (id, req) = match reverse_assembler.push(rsp_frame) {
+                            Ok(Assembled::Done(req)) => (req.id, req.decompress(max_msg_len)),
+                            Ok(Assembled::Partial | Assembled::Discarded) => continue,
+                            Ok(Assembled::TooLarge { id, len }) => {
+                                let s = format!(
+                                    "request message too large: len={len}, max={max_msg_len}"
+                                );
+                                (id, Err(io::Error::new(io::ErrorKind::InvalidData, s)))
+                            }
+                            Err(e) => {
+                                error!("tcp multiplex_client assemble req: err = {e}");
+                                inner.close(&format!("connection closed: {e}"));
+                                break;
+                            }
+                        };
+                        let inner = inner.clone();
+                        let callback = callback.clone();
+                        calls.add(move || {
+                            let mut rsp = RspBuf::new();
+                            let status =
+                                |e: &dyn std::error::Error| WireError::Status(e.to_string());
+                            let ret = match req {
+                                Ok(ref req) => match req.decode_poll() {
+                                    Ok(req) => callback(req, &mut rsp),
+                                    Err(e) => Err(status(&e)),
+                                },
+                                Err(e) => Err(status(&e)),
+                            };
+                            let ret = match rsp.take_stream() {
+                                Some(_) => {
+                                    let s = "streaming is not supported by reverse calls";
+                                    Err(WireError::Status(s.to_owned()))
+                                }
+                                None => ret,
+                            };
+                            let mut data = rsp.finish_limited(id, ret, max_msg_len);
+                            add_frame_flags(&mut data, FLAG_REVERSE);
+                            info!("send reverse rsp: id={id}");
+                            inner.write_msg(data).ok();
+                        });
+                        continue;
+                    }
+                    // the frames of unexpected responses are dropped before they are buffered
+                    if assembler.is_first(&rsp_frame) && !inner.is_pending(rsp_frame.id) {
+                        if rsp_frame.is_last() {
+                            inner.drop_rsp(rsp_frame.id);
+                        }
+                        continue;
+                    }
+                    let rsp_frame = match assembler.push(rsp_frame) {
+                        Ok(Assembled::Done(frame)) => frame,
+                        Ok(Assembled::Partial | Assembled::Discarded) => continue,
+                        Ok(Assembled::TooLarge { id, len }) => {
+                            let len = len as u64;
+                            let max = max_msg_len;
+                            let e = FrameTooLarge {
+                                id,
+                                control: false,
+                                len,
+                                max,
+                            };
+                            inner.set_rsp(id, Err(e.into()));
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("tcp multiplex_client assemble rsp: err = {e}");
+                            inner.close(&format!("connection closed: {e}"));
                             break;
                         }
                     };
-                    info!("receive rsp, id={}", rsp_frame.id);
+                    let id = rsp_frame.id;
+                    info!("receive rsp, id={id}");
+                    let rsp_frame = rsp_frame.decompress(max_msg_len);
 
                     // set the wait req
-                    let id = unsafe { may_waiter::ID::from_usize(rsp_frame.id as usize) };
-                    TokenWaiter::set_rsp(id, rsp_frame);
+                    inner.set_rsp(id, rsp_frame);
                 }
             }
         )?;
 
-        Ok(MultiplexClient {
-            timeout: None,
-            sock: QueuedWriter::new(writer),
+        let keepalive = *config.keepalive();
+        let keepalive = if keepalive.enabled() {
+            let inner = inner.clone();
+            let h = go!(
+                coroutine::Builder::new().name("MultiPlexClientKeepAlive".to_owned()),
+                move || {
+                    let expired = keepalive.run(&inner.live, |id| {
+                        inner.write(Control::Ping.encode(id, &[])).ok();
+                    });
+                    inner.close(match expired {
+                        Expired::Idle => "idle timeout",
+                        Expired::Heartbeat => "heartbeat timeout",
+                    });
+                }
+            )?;
+            Some(h)
+        } else {
+            None
+        };
+
+        Ok(Conn {
+            inner,
             listener: Some(listener),
+            keepalive,
         })
     }
 
+    /// send the request and wait for the response
+    fn call(&self, buf: Vec<u8>, timeout: Option<Duration>) -> io::Result<Frame> {
+        let waiter = RspWaiter::new();
+        let id = self
+            .inner
+            .send(buf, Some(Waiter::Call(waiter.id().unwrap())))?;
+        info!("request id = {id:?}");
+        self.inner.live.begin();
+        let ret = waiter.wait_rsp(timeout).and_then(|rsp| rsp);
+        self.inner.remove_pending(id);
+        self.inner.live.end();
+        ret
+    }
+
+    /// send the request without waiting, the response is received by the returned receiver
+    fn start(&self, buf: Vec<u8>) -> io::Result<(u64, mpsc::Receiver<io::Result<Frame>>)> {
+        let (tx, rx) = mpsc::channel();
+        let id = self.inner.send(buf, Some(Waiter::Pending(tx)))?;
+        info!("pending request id = {id:?}");
+        self.inner.live.begin();
+        Ok((id, rx))
+    }
+
+    /// send the request of a streaming call, the items are received by the stream
+    /// return the window that is used to upload the items
+    fn call_stream(
+        self: Arc<Self>,
+        mut buf: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> io::Result<(Remote, Arc<Window>)> {
+        add_frame_flags(&mut buf, FLAG_STREAM);
+        let (tx, rx) = mpsc::channel();
+        let window = Arc::new(Window::new());
+        let waiter = Waiter::Stream(tx, window.clone());
+        let id = self.inner.send(buf, Some(waiter))?;
+        info!("stream request id = {id:?}");
+        self.inner.live.begin();
+        Ok((Remote::new(id, self, rx, timeout), window))
+    }
+
+    /// upload the items of a streaming call, then half close it
+    fn upload(
+        &self,
+        id: u64,
+        window: &Window,
+        source: &mut dyn Source,
+        max_len: usize,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        let sent = send_items(id, window, source, max_len, timeout, |data| {
+            self.inner.write_msg(data).is_ok()
+        });
+        match sent {
+            Sent::Done(Ok(())) => Ok(self.inner.write(encode_stream_end(id))?),
+            Sent::Done(Err(
+                WireError::ServerSerialize(s)
+                | WireError::ServerDeserialize(s)
+                | WireError::Status(s),
+            )) => Err(Error::ClientSerialize(s)),
+            Sent::Done(Err(WireError::Polling)) => unreachable!("items never poll"),
+            // the server doesn't need more items, or the connection is closed
+            // both would be reported by the response
+            Sent::Cancelled => Ok(()),
+            Sent::TimedOut => Err(Error::Timeout),
+        }
+    }
+}
+
+impl<S: StreamExt> StreamConn for Conn<S> {
+    fn credit(&self, id: u64, n: u32) {
+        self.inner
+            .write(Control::Credit.encode(id, &n.to_be_bytes()))
+            .ok();
+    }
+
+    fn finish(&self, id: u64, cancel: bool) {
+        // the call is closed, stop the sender of it
+        if let Some(Waiter::Stream(_, window)) = self.inner.remove_pending(id) {
+            window.cancel();
+        }
+        if cancel {
+            self.inner.write(Control::Cancel.encode(id, &[])).ok();
+        }
+        self.inner.live.end();
+    }
+}
+
+impl<S: StreamExt> SinkConn for Conn<S> {
+    fn write_item(&self, data: Vec<u8>) -> io::Result<()> {
+        self.inner.write_msg(data)
+    }
+}
+
+type Connector<S> = Box<dyn Fn() -> io::Result<S> + Send + Sync>;
+
+/// Multiplexed Client
+pub struct MultiplexClient<S: StreamExt> {
+    // default timeout is 10s
+    timeout: Option<Duration>,
+    // used to create new connections
+    config: ClientConfig,
+    // used to reconnect when the connection is closed or going away
+    connector: Option<Connector<S>>,
+    // the current connection
+    conn: RwLock<Arc<Conn<S>>>,
+    // shared by all the connections
+    counters: Arc<Counters>,
+    // the random id of the client for the idempotency keys
+    client_id: u64,
+    // the next idempotency key
+    next_key: AtomicU64,
+}
+
+impl<S: StreamExt> fmt::Debug for MultiplexClient<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiplexClient")
+            .field("timeout", &self.timeout)
+            .field("config", &self.config)
+            .field("reconnect", &self.connector.is_some())
+            .finish()
+    }
+}
+
+/// a random id that is unlikely to be used by other clients
+pub(crate) fn random_id() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::SystemTime;
+
+    // the std hasher is seeded randomly
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    if let Ok(t) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(t.as_nanos());
+    }
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
+
+impl<S: StreamExt> MultiplexClient<S> {
+    /// connect to the server address
+    pub fn new(stream: S) -> io::Result<Self> {
+        Self::with_config(stream, ClientConfig::default())
+    }
+
+    /// connect to the server address with the given config
+    pub fn with_config(stream: S, config: ClientConfig) -> io::Result<Self> {
+        let counters = Arc::new(Counters::default());
+        let conn = Conn::new(stream, &config, counters.clone())?;
+        Ok(MultiplexClient {
+            timeout: config.timeout(),
+            config,
+            connector: None,
+            conn: RwLock::new(Arc::new(conn)),
+            counters,
+            client_id: random_id(),
+            next_key: AtomicU64::new(0),
+        })
+    }
+
+    /// connect to the server with the given connector
+    /// the connector is called again when the connection is closed or the server sent GOAWAY
+    /// requests that are refused by a going away server are resent on the new connection
+    pub fn with_connector<F>(connector: F, config: ClientConfig) -> io::Result<Self>
+    where
+        F: Fn() -> io::Result<S> + Send + Sync + 'static,
+    {
+        let mut client = Self::with_config(connector()?, config)?;
+        client.connector = Some(Box::new(connector));
+        Ok(client)
+    }
+
     /// set the default timeout value
     /// the initial timeout is 10 seconds
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.timeout = Some(timeout);
     }
+
+    /// get the current counters of the client
+    pub fn stats(&self) -> ClientStats {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        ClientStats {
+            unknown_rsps: load(&self.counters.unknown_rsps),
+            stale_rsps: load(&self.counters.stale_rsps),
+        }
+    }
+
+    /// send the request without waiting, the response is received by the returned handle
+    /// the request is sent only once, it's not retried by the retry policy, doesn't carry
+    /// an idempotency key and is not resent after GOAWAY, the caller can retry it instead
+    /// used by the generated client code
+    #[doc(hidden)]
+    pub fn call_start<T: DeserializeOwned>(&self, req: ReqBuf) -> Result<PendingCall<T>, Error> {
+        let buf = req.finish(0);
+        check_frame_len(&buf, self.config.max_message_len())?;
+        let conn = self.conn()?;
+        let (id, rx) = conn.start(buf)?;
+        Ok(PendingCall::new(id, conn, rx, self.timeout))
+    }
+
+    /// start a streaming call, the items of the response are received by the stream
+    /// used by the generated client code
+    #[doc(hidden)]
+    pub fn call_stream<T>(&self, req: ReqBuf) -> Result<Stream<T>, Error> {
+        // the id is assigned when the request is sent
+        let buf = req.finish(0);
+        check_frame_len(&buf, self.config.max_message_len())?;
+        let (remote, _) = self.conn()?.call_stream(buf, self.timeout)?;
+        Ok(Stream::remote(remote))
+    }
+
+    /// start a bidirectional streaming call
+    /// the items are sent by the sender and received by the stream on the same request
+    /// used by the generated client code
+    #[doc(hidden)]
+    pub fn call_bidi<T, U>(&self, req: ReqBuf) -> Result<(Sender<T>, Stream<U>), Error> {
+        let buf = req.finish(0);
+        let max_len = self.config.max_message_len();
+        check_frame_len(&buf, max_len)?;
+        let conn = self.conn()?;
+        let (remote, window) = conn.clone().call_stream(buf, self.timeout)?;
+        let upload = Upload::new(remote.id(), window, conn, max_len, self.timeout);
+        Ok((Sender::remote(upload), Stream::remote(remote)))
+    }
+
+    /// start a streaming call that uploads the items, then wait for the response
+    /// used by the generated client code
+    #[doc(hidden)]
+    pub fn call_upload<I>(&self, req: ReqBuf, items: I) -> Result<Frame, Error>
+    where
+        I: IntoIterator,
+        I::Item: Serialize,
+    {
+        let buf = req.finish(0);
+        let max_len = self.config.max_message_len();
+        check_frame_len(&buf, max_len)?;
+        let conn = self.conn()?;
+        let (remote, window) = conn.clone().call_stream(buf, self.timeout)?;
+        let mut items = Items(items.into_iter());
+        // the call is cancelled if failed, by dropping the remote
+        conn.upload(remote.id(), &window, &mut items, max_len, self.timeout)?;
+        remote.wait_rsp()
+    }
+
+    /// get the current connection, reconnect if it's not usable any more
+    fn conn(&self) -> io::Result<Arc<Conn<S>>> {
+        let conn = self.conn.read().unwrap().clone();
+        let Some(connector) = self.connector.as_ref() else {
+            return Ok(conn);
+        };
+        if conn.inner.is_usable() {
+            return Ok(conn);
+        }
+
+        let mut conn = self.conn.write().unwrap();
+        // others may already reconnected
+        if !conn.inner.is_usable() {
+            info!("multiplex_client reconnecting");
+            let counters = self.counters.clone();
+            *conn = Arc::new(Conn::new(connector()?, &self.config, counters)?);
+        }
+        Ok(conn.clone())
+    }
+}
+
+impl<S: StreamExt> MultiplexClient<S> {
+    /// call the server, the failed call is retried by the retry policy
+    fn call_retry(&self, mut req: ReqBuf, mut idempotent: bool) -> Result<Frame, Error> {
+        if !idempotent && self.config.idempotency_key() {
+            let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+            req.set_idempotency_key(self.client_id, key);
+            // the retried requests carry the same key, the server runs it only once
+            idempotent = true;
+        }
+        // the id is assigned when the request is sent
+        let buf = req.finish(0);
+        check_frame_len(&buf, self.config.max_message_len())?;
+        let policy = self.config.retry_policy();
+        if self.connector.is_none() && policy.is_none() {
+            return Ok(self.conn()?.call(buf, self.timeout)?);
+        }
+
+        let mut resend = 0;
+        let mut attempt = 1;
+        loop {
+            let ret = self
+                .conn()
+                .and_then(|conn| conn.call(buf.clone(), self.timeout))
+                .map_err(Error::from);
+            // the request is not processed by the server, it's safe to resend
+            // and reconnecting is not counted as an attempt
+            if let Err(e) = &ret {
+                // without a connector the closed connection never comes back
+                if self.connector.is_none() && is_unsent(e) {
+                    return ret;
+                }
+                if resend < MAX_RESEND && is_unsent(e) {
+                    info!("resend request: err = {e:?}");
+                    resend += 1;
+                    continue;
+                }
+            }
+            let Some(policy) = policy else {
+                return ret;
+            };
+            // the status error is only known after decoding the response
+            let status;
+            let err = match &ret {
+                Ok(frame) => match frame.decode_rsp() {
+                    Err(e @ Error::Status(_)) => {
+                        status = e;
+                        &status
+                    }
+                    _ => {
+                        policy.budget().deposit();
+                        return ret;
+                    }
+                },
+                Err(e) => e,
+            };
+            if !policy.should_retry(err, attempt, idempotent) {
+                return ret;
+            }
+            let backoff = policy.backoff(attempt);
+            info!("retry request: attempt = {attempt}, backoff = {backoff:?}, err = {err:?}");
+            coroutine::sleep(backoff);
+            attempt += 1;
+        }
+    }
 }
 
 impl<S: StreamExt> Client for MultiplexClient<S> {
     fn call_service(&self, req: ReqBuf) -> Result<Frame, Error> {
-        let waiter = TokenWaiter::new();
-        let id = waiter.id().unwrap();
-        info!("request id = {id:?}");
+        self.call_retry(req, false)
+    }
+
+    fn call_idempotent(&self, req: ReqBuf) -> Result<Frame, Error> {
+        self.call_retry(req, true)
+    }
+
+    fn call_oneway(&self, req: ReqBuf) -> Result<(), Error> {
+        let mut buf = req.finish(0);
+        check_frame_len(&buf, self.config.max_message_len())?;
+        add_frame_flags(&mut buf, FLAG_ONEWAY);
+        // return once the request is queued, the server would not respond
+        let id = self.conn()?.inner.send(buf, None)?;
+        info!("oneway request id = {id:?}");
+        Ok(())
+    }
+}
 
-        // send the request
-        let id: usize = id.into();
-        let buf = req.finish(id as u64);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conetty::{RetryBudget, RetryCode, RetryPolicy, Server, TcpServer};
+    use may::net::TcpStream;
+    use std::sync::atomic::AtomicUsize;
 
-        self.sock.write(buf)?;
+    /// fail all the calls with a status error
+    #[derive(Clone, Default)]
+    struct Busy(Arc<AtomicUsize>);
+
+    impl Server for Busy {
+        fn service(&self, _req: &[u8], _rsp: &mut RspBuf) -> Result<(), WireError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Err(WireError::Status("busy".to_owned()))
+        }
+    }
+
+    impl Busy {
+        fn calls(&self) -> usize {
+            self.0.swap(0, Ordering::Relaxed)
+        }
+    }
+
+    fn connect(port: u16, budget: &RetryBudget) -> MultiplexClient<TcpStream> {
+        let mut policy = RetryPolicy::new();
+        policy.set_max_attempts(3);
+        policy.set_backoff(Duration::from_millis(1), Duration::from_millis(1));
+        policy.set_retry_on(&[RetryCode::Status, RetryCode::Unavailable]);
+        policy.set_budget(budget.clone());
+        let mut config = ClientConfig::new();
+        config.set_retry_policy(policy);
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        MultiplexClient::with_config(stream, config).unwrap()
+    }
+
+    #[test]
+    fn retry_idempotent_only() {
+        let busy = Busy::default();
+        let _server = busy.clone().start(("127.0.0.1", 42311)).unwrap();
+        let budget = RetryBudget::new(100, 0.0);
+        let client = connect(42311, &budget);
+
+        // the request may have reached the server, it's not retried
+        let ret = client.call_service(ReqBuf::new()).unwrap();
+        assert!(matches!(ret.decode_rsp(), Err(Error::Status(_))));
+        assert_eq!(busy.calls(), 1);
+
+        let ret = client.call_idempotent(ReqBuf::new()).unwrap();
+        assert!(matches!(ret.decode_rsp(), Err(Error::Status(_))));
+        assert_eq!(busy.calls(), 3);
+        assert_eq!(budget.available(), 98);
+    }
+
+    #[test]
+    fn retry_unsent() {
+        let busy = Busy::default();
+        let server = busy.clone().start(("127.0.0.1", 42312)).unwrap();
+        let budget = RetryBudget::new(100, 0.0);
+        let client = connect(42312, &budget);
+        assert!(client.call_service(ReqBuf::new()).is_ok());
+        assert_eq!(busy.calls(), 1);
+
+        // the closed connection is never reconnected without a connector,
+        // the unsent request fails at once without taking the budget
+        server.shutdown();
+        let err = client.call_idempotent(ReqBuf::new()).unwrap_err();
+        assert!(is_unsent(&err), "{err:?}");
+        assert_eq!(budget.available(), 100);
+        assert_eq!(busy.calls(), 0);
+    }
+
+    #[test]
+    fn retry_budget_shared() {
+        let busy = Busy::default();
+        let _server = busy.clone().start(("127.0.0.1", 42313)).unwrap();
+        // the clients share the budget of one retry
+        let budget = RetryBudget::new(1, 0.0);
+        let a = connect(42313, &budget);
+        let b = connect(42313, &budget);
+
+        assert!(a.call_idempotent(ReqBuf::new()).is_ok());
+        assert_eq!(busy.calls(), 2);
+        // the retries stop when the budget is exhausted
+        assert!(b.call_idempotent(ReqBuf::new()).is_ok());
+        assert_eq!(busy.calls(), 1);
+        assert!(a.call_idempotent(ReqBuf::new()).is_ok());
+        assert_eq!(busy.calls(), 1);
+    }
+
+    /// accept a raw connection and answer the preface without any caps
+    fn accept_raw(listener: &std::net::TcpListener) -> std::net::TcpStream {
+        use std::io::{Read, Write};
+        let (mut s, _) = listener.accept().unwrap();
+        let mut preface = [0; 12];
+        s.read_exact(&mut preface).unwrap();
+        s.write_all(b"MAYRPC\x00\x01\x00\x00\x00\x00").unwrap();
+        s
+    }
+
+    /// echo the request back on the raw connection
+    fn reply_raw(s: &mut std::net::TcpStream, req: &Frame) {
+        use std::io::Write;
+        let mut rsp = RspBuf::new();
+        rsp.write_all(req.decode_req()).unwrap();
+        s.write_all(&rsp.finish(req.id, Ok(()))).unwrap();
+    }
+
+    #[test]
+    fn goaway_resend() {
+        use std::io::Write;
+        let listener = std::net::TcpListener::bind("127.0.0.1:42314").unwrap();
+        let server = std::thread::spawn(move || {
+            let mut s = accept_raw(&listener);
+            let mut buf = BytesMut::new();
+            let a = Frame::decode_from(&mut s, &mut buf).unwrap();
+            let b = Frame::decode_from(&mut s, &mut buf).unwrap();
+            let (first, second) = if a.id < b.id { (a, b) } else { (b, a) };
+            // only the first request is processed, the other one is refused
+            s.write_all(&Control::GoAway.encode(first.id, &[])).unwrap();
+            reply_raw(&mut s, &first);
+            // the refused request is resent on a new connection
+            let mut s2 = accept_raw(&listener);
+            let resent = Frame::decode_from(&mut s2, &mut BytesMut::new()).unwrap();
+            assert_eq!(resent.decode_req(), second.decode_req());
+            reply_raw(&mut s2, &resent);
+            (s, s2)
+        });
+
+        let connect = || TcpStream::connect(("127.0.0.1", 42314));
+        let client = MultiplexClient::with_connector(connect, ClientConfig::new()).unwrap();
+        let client = Arc::new(client);
+        let calls: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|data| {
+                let client = client.clone();
+                go!(move || {
+                    let mut req = ReqBuf::new();
+                    req.write_all(data.as_bytes()).unwrap();
+                    let rsp = client.call_service(req).unwrap();
+                    assert_eq!(rsp.decode_rsp().unwrap(), data.as_bytes());
+                })
+            })
+            .collect();
+        for call in calls {
+            call.join().unwrap();
+        }
+        server.join().unwrap();
+    }
 
-        // wait for the rsp
-        Ok(waiter.wait_rsp(self.timeout)?)
+    #[test]
+    fn handshake_timeout() {
+        // the server accepts the connection but never sends the preface
+        let listener = std::net::TcpListener::bind("127.0.0.1:42315").unwrap();
+        let mut config = ClientConfig::new();
+        config.set_handshake_timeout(Duration::from_millis(100));
+        let stream = TcpStream::connect(("127.0.0.1", 42315)).unwrap();
+        let start = std::time::Instant::now();
+        let err = MultiplexClient::with_config(stream, config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        drop(listener);
     }
 }
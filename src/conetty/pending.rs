@@ -0,0 +1,122 @@
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::errors::Error;
+use super::frame::Frame;
+use super::streaming::StreamConn;
+
+use may::sync::mpsc;
+use serde::de::DeserializeOwned;
+
+/// the handle of a call that is sent without waiting for the response
+///
+/// it's returned by the generated `call_xxx_start` methods of a multiplexed client,
+/// so that many calls can be pipelined from one coroutine
+/// the call is abandoned if the handle is dropped before the response is received
+pub struct PendingCall<T> {
+    id: u64,
+    conn: Arc<dyn StreamConn>,
+    rx: mpsc::Receiver<io::Result<Frame>>,
+    // the client timeout is counted from when the request is sent
+    deadline: Option<Instant>,
+    // the response that is received by `is_ready`
+    rsp: Option<io::Result<Frame>>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for PendingCall<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingCall")
+            .field("id", &self.id)
+            .field("ready", &self.rsp.is_some())
+            .finish()
+    }
+}
+
+impl<T: DeserializeOwned> PendingCall<T> {
+    pub(crate) fn new(
+        id: u64,
+        conn: Arc<dyn StreamConn>,
+        rx: mpsc::Receiver<io::Result<Frame>>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        PendingCall {
+            id,
+            conn,
+            rx,
+            deadline: timeout.map(|t| Instant::now() + t),
+            rsp: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// check if the response is received, `wait` would not block if it's ready
+    pub fn is_ready(&mut self) -> bool {
+        if self.rsp.is_none() {
+            match self.rx.try_recv() {
+                Ok(rsp) => self.rsp = Some(rsp),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => {
+                    self.rsp = Some(Err(io::ErrorKind::NotConnected.into()));
+                }
+            }
+        }
+        self.rsp.is_some()
+    }
+
+    /// wait for the response until the client timeout expires
+    pub fn wait(self) -> Result<T, Error> {
+        let timeout = self
+            .deadline
+            .map(|d| d.saturating_duration_since(Instant::now()));
+        self.recv(timeout)
+    }
+
+    /// wait for the response for at most `timeout`, the client timeout is ignored
+    pub fn wait_timeout(self, timeout: Duration) -> Result<T, Error> {
+        self.recv(Some(timeout))
+    }
+
+    fn recv(mut self, timeout: Option<Duration>) -> Result<T, Error> {
+        let rsp = match self.rsp.take() {
+            Some(rsp) => rsp,
+            None => match timeout {
+                Some(timeout) => self.rx.recv_timeout(timeout).map_err(|e| match e {
+                    RecvTimeoutError::Timeout => Error::Timeout,
+                    RecvTimeoutError::Disconnected => {
+                        io::Error::from(io::ErrorKind::NotConnected).into()
+                    }
+                })?,
+                None => self
+                    .rx
+                    .recv()
+                    .map_err(|_| Error::from(io::Error::from(io::ErrorKind::NotConnected)))?,
+            },
+        };
+        let frame = rsp?;
+        let data = frame.decode_rsp()?;
+        bincode::deserialize(data).map_err(|e| Error::ClientDeserialize(e.to_string()))
+    }
+}
+
+impl<T> Drop for PendingCall<T> {
+    fn drop(&mut self) {
+        // a late response of the call is dropped by the client
+        self.conn.finish(self.id, false);
+    }
+}
+
+/// wait for all the calls, the results are in the same order as the calls
+///
+/// the calls are already sent, so the total wait time is about the slowest call
+pub fn join_all<T, I>(calls: I) -> Vec<Result<T, Error>>
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = PendingCall<T>>,
+{
+    calls.into_iter().map(PendingCall::wait).collect()
+}
@@ -1,14 +1,25 @@
-use std::io::Write;
+use std::io::{self, IoSlice, Write};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use may::queue::mpsc::Queue;
 use may::sync::Mutex;
 
+use super::buf_pool;
+
+/// frames smaller than this are copied into the write buffer
+/// the larger ones are written directly without copy
+const COALESCE_LEN: usize = 4 * 1024;
+/// the max number of buffers in a single vectored write
+const MAX_IOV: usize = 64;
+
 #[derive(Debug)]
 struct BufWriter<W: Write> {
     writer: W,
+    // the coalesced small frames
     buf: BytesMut,
+    // the buffers to write in order
+    bufs: Vec<Bytes>,
 }
 
 impl<W: Write> BufWriter<W> {
@@ -16,6 +27,7 @@ impl<W: Write> BufWriter<W> {
         BufWriter {
             writer,
             buf: BytesMut::with_capacity(1024 * 32),
+            bufs: Vec::new(),
         }
     }
     #[inline]
@@ -27,22 +39,54 @@ impl<W: Write> BufWriter<W> {
     }
 
     #[inline]
-    fn put_data(&mut self, data: &[u8]) {
-        self.buf.extend_from_slice(data)
+    fn put_data(&mut self, data: Bytes) {
+        if data.len() < COALESCE_LEN {
+            self.buf.extend_from_slice(&data);
+            buf_pool::recycle(data);
+        } else {
+            self.seal_buf();
+            self.bufs.push(data);
+        }
+    }
+
+    /// move the coalesced frames into the buffers
+    #[inline]
+    fn seal_buf(&mut self) {
+        if !self.buf.is_empty() {
+            self.bufs.push(self.buf.split().freeze());
+        }
     }
 
     #[inline]
-    fn write_all(&mut self) -> std::io::Result<()> {
-        let ret = self.writer.write_all(&self.buf);
-        self.buf.clear();
+    fn write_all(&mut self) -> io::Result<()> {
+        self.seal_buf();
+        let ret = write_all_vectored(&mut self.writer, &self.bufs);
+        // the coalesced buffer is shared with `buf` and would not be recycled
+        self.bufs.drain(..).for_each(buf_pool::recycle);
         ret
     }
 }
 
+/// write all the buffers with vectored writes
+fn write_all_vectored<W: Write>(writer: &mut W, bufs: &[Bytes]) -> io::Result<()> {
+    let mut slices: Vec<_> = bufs.iter().map(|b| IoSlice::new(b)).collect();
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        let n = slices.len().min(MAX_IOV);
+        match writer.write_vectored(&slices[..n]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct QueuedWriter<W: Write> {
     data_count: AtomicUsize,
-    data_queue: Queue<Vec<u8>>,
+    data_queue: Queue<Bytes>,
     writer: Mutex<BufWriter<W>>,
 }
 
@@ -56,33 +100,55 @@ impl<W: Write> QueuedWriter<W> {
     }
 
     /// it's safe and efficient to call this API concurrently
-    pub fn write(&self, data: Vec<u8>) -> std::io::Result<()> {
-        self.data_queue.push(data);
+    /// the data is shared instead of copied if it's a `Bytes`
+    pub fn write(&self, data: impl Into<Bytes>) -> io::Result<()> {
+        if self.push(data) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// queue the data without writing it
+    /// return true if the caller is responsible to `flush` the queue
+    /// this is useful when the caller need to keep the queue order within its own lock
+    pub fn push(&self, data: impl Into<Bytes>) -> bool {
+        self.data_queue.push(data.into());
         // only allow the first writer perform the write operation
         // other concurrent writers would just push the data
-        if self.data_count.fetch_add(1, Ordering::AcqRel) == 0 {
-            // it's possible that other writer is blocked by the lock
-            // e.g. the `write_all()` is blocked and data_count is 0
-            // and the next writer would try to acquire the lock
-            // this only relax the write lock contention
-            let mut writer = self.writer.lock().unwrap();
-            writer.reserve_buf();
-
-            loop {
-                let mut cnt = 0;
-                while let Some(data) = self.data_queue.pop() {
-                    writer.put_data(&data);
-                    cnt += 1;
-                }
-
-                // detect if there are more packet need to deal with
-                if self.data_count.fetch_sub(cnt, Ordering::AcqRel) == cnt {
-                    break;
-                }
+        self.data_count.fetch_add(1, Ordering::AcqRel) == 0
+    }
+
+    /// write all the queued data, must only be called when `push` returns true
+    pub fn flush(&self) -> io::Result<()> {
+        // it's possible that other writer is blocked by the lock
+        // e.g. the `write_all()` is blocked and data_count is 0
+        // and the next writer would try to acquire the lock
+        // this only relax the write lock contention
+        let mut writer = self.writer.lock().unwrap();
+        writer.reserve_buf();
+
+        loop {
+            let mut cnt = 0;
+            while let Some(data) = self.data_queue.pop() {
+                writer.put_data(data);
+                cnt += 1;
             }
 
-            writer.write_all()?;
+            // detect if there are more packet need to deal with
+            if self.data_count.fetch_sub(cnt, Ordering::AcqRel) == cnt {
+                break;
+            }
         }
-        Ok(())
+
+        writer.write_all()
+    }
+
+    /// wait until all the queued data are written
+    pub fn sync(&self) {
+        while self.data_count.load(Ordering::Acquire) != 0 {
+            may::coroutine::yield_now();
+        }
+        // the last flush is still in progress if the lock is held
+        drop(self.writer.lock().unwrap());
     }
 }
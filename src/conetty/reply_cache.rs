@@ -0,0 +1,281 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use may::sync::{Mutex, SyncFlag};
+
+/// the client id and the idempotency key of a request
+type Key = (u64, u64);
+
+/// the memory that an entry takes besides its reply
+const ENTRY_OVERHEAD: usize = 64;
+
+struct ReplyCacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evicted: AtomicU64,
+}
+
+static REPLY_CACHE: ReplyCacheCounters = ReplyCacheCounters {
+    hits: AtomicU64::new(0),
+    misses: AtomicU64::new(0),
+    evicted: AtomicU64::new(0),
+};
+
+/// a snapshot of the process wide reply cache counters of the servers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplyCacheStats {
+    /// number of the keyed requests that are answered by a cached reply
+    pub hits: u64,
+    /// number of the keyed requests that are dispatched to the service
+    pub misses: u64,
+    /// number of the replies that are evicted before they expire
+    pub evicted: u64,
+}
+
+impl ReplyCacheStats {
+    /// get the current counters
+    pub fn get() -> Self {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        ReplyCacheStats {
+            hits: load(&REPLY_CACHE.hits),
+            misses: load(&REPLY_CACHE.misses),
+            evicted: load(&REPLY_CACHE.evicted),
+        }
+    }
+}
+
+enum Entry {
+    // the first request is still running, the retried ones wait for it
+    Running(Arc<SyncFlag>),
+    Done {
+        reply: Arc<Vec<u8>>,
+        expire: Instant,
+    },
+}
+
+struct Inner {
+    map: HashMap<Key, Entry>,
+    // the done entries in the order of expiry
+    order: VecDeque<(Key, Instant)>,
+    // the memory taken by the done entries
+    bytes: usize,
+}
+
+impl Inner {
+    /// remove the expired entries and the oldest ones that exceed the memory limit
+    fn evict(&mut self, now: Instant, max_bytes: usize) {
+        while let Some(&(key, expire)) = self.order.front() {
+            if expire > now && self.bytes <= max_bytes {
+                break;
+            }
+            self.order.pop_front();
+            // the entry may be replaced after it's expired
+            if let Some(Entry::Done { reply, expire: e }) = self.map.get(&key) {
+                if *e == expire {
+                    self.bytes -= reply.len() + ENTRY_OVERHEAD;
+                    self.map.remove(&key);
+                    if expire > now {
+                        REPLY_CACHE.evicted.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// the replies of the keyed requests, shared by all the connections of a server
+///
+/// a request that is retried with the same key gets the reply of the first one
+/// instead of running again, the replies are kept for `ttl` and take at most `max_bytes`
+pub(crate) struct ReplyCache {
+    ttl: Duration,
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+impl fmt::Debug for ReplyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplyCache")
+            .field("ttl", &self.ttl)
+            .field("max_bytes", &self.max_bytes)
+            .finish()
+    }
+}
+
+impl ReplyCache {
+    pub fn new(ttl: Duration, max_bytes: usize) -> Self {
+        ReplyCache {
+            ttl,
+            max_bytes,
+            inner: Mutex::new(Inner {
+                map: HashMap::new(),
+                order: VecDeque::new(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// get the cached reply of the key, or run `f` to make it
+    /// a request with the same key that is still running is waited for
+    pub fn get_or_run<F: FnOnce() -> Vec<u8>>(&self, key: Key, f: F) -> Arc<Vec<u8>> {
+        let flag = loop {
+            let mut inner = self.inner.lock().unwrap();
+            inner.evict(Instant::now(), self.max_bytes);
+            match inner.map.get(&key) {
+                Some(Entry::Done { reply, .. }) => {
+                    REPLY_CACHE.hits.fetch_add(1, Ordering::Relaxed);
+                    return reply.clone();
+                }
+                Some(Entry::Running(flag)) => {
+                    let flag = flag.clone();
+                    drop(inner);
+                    info!("wait for the running request: key={key:?}");
+                    flag.wait();
+                }
+                None => {
+                    let flag = Arc::new(SyncFlag::new());
+                    inner.map.insert(key, Entry::Running(flag.clone()));
+                    break flag;
+                }
+            }
+        };
+        REPLY_CACHE.misses.fetch_add(1, Ordering::Relaxed);
+
+        let mut running = Running {
+            cache: self,
+            key,
+            flag,
+            reply: None,
+        };
+        let reply = Arc::new(f());
+        running.reply = Some(reply.clone());
+        reply
+    }
+}
+
+/// the guard of a running request, it stores the reply and wakes up the waiting ones
+/// the entry is removed if the request is cancelled or panicked
+struct Running<'a> {
+    cache: &'a ReplyCache,
+    key: Key,
+    flag: Arc<SyncFlag>,
+    reply: Option<Arc<Vec<u8>>>,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let cache = self.cache;
+        let mut inner = cache.inner.lock().unwrap();
+        match self.reply.take() {
+            // a reply that exceeds the limit is never cached
+            Some(reply) if reply.len() + ENTRY_OVERHEAD <= cache.max_bytes => {
+                let expire = Instant::now() + cache.ttl;
+                inner.bytes += reply.len() + ENTRY_OVERHEAD;
+                inner.order.push_back((self.key, expire));
+                inner.map.insert(self.key, Entry::Done { reply, expire });
+                inner.evict(Instant::now(), cache.max_bytes);
+            }
+            _ => {
+                inner.map.remove(&self.key);
+            }
+        }
+        drop(inner);
+        self.flag.fire();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::coroutine;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::AtomicUsize;
+
+    fn reply(len: usize) -> Vec<u8> {
+        vec![0; len]
+    }
+
+    #[test]
+    fn hit_after_run() {
+        let cache = ReplyCache::new(Duration::from_secs(60), 1024);
+        let runs = AtomicUsize::new(0);
+        let run = || {
+            runs.fetch_add(1, Ordering::Relaxed);
+            b"reply".to_vec()
+        };
+        assert_eq!(*cache.get_or_run((1, 1), run), b"reply");
+        assert_eq!(*cache.get_or_run((1, 1), run), b"reply");
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        // the keys of other clients are not shared
+        cache.get_or_run((2, 1), run);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn wait_for_running() {
+        let cache = Arc::new(ReplyCache::new(Duration::from_secs(60), 1024));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let (cache, runs) = (cache.clone(), runs.clone());
+                may::go!(move || cache.get_or_run((1, 1), || {
+                    runs.fetch_add(1, Ordering::Relaxed);
+                    coroutine::sleep(Duration::from_millis(50));
+                    b"reply".to_vec()
+                }))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(*handle.join().unwrap(), b"reply");
+        }
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn expire_after_ttl() {
+        let cache = ReplyCache::new(Duration::from_millis(20), 1024);
+        cache.get_or_run((1, 1), || b"first".to_vec());
+        assert_eq!(*cache.get_or_run((1, 1), || b"second".to_vec()), b"first");
+        coroutine::sleep(Duration::from_millis(40));
+        assert_eq!(*cache.get_or_run((1, 1), || b"second".to_vec()), b"second");
+    }
+
+    #[test]
+    fn evict_by_max_bytes() {
+        // room for two entries
+        let cache = ReplyCache::new(Duration::from_secs(60), 2 * (100 + ENTRY_OVERHEAD));
+        cache.get_or_run((1, 1), || reply(100));
+        cache.get_or_run((1, 2), || reply(100));
+        let before = ReplyCacheStats::get().evicted;
+        cache.get_or_run((1, 3), || reply(100));
+        assert!(ReplyCacheStats::get().evicted > before);
+
+        // the oldest one is evicted
+        assert_eq!(cache.get_or_run((1, 1), || reply(1)).len(), 1);
+        assert_eq!(cache.get_or_run((1, 3), || reply(1)).len(), 100);
+        assert!(cache.inner.lock().unwrap().bytes <= cache.max_bytes);
+    }
+
+    #[test]
+    fn oversize_not_cached() {
+        let cache = ReplyCache::new(Duration::from_secs(60), 100);
+        assert_eq!(cache.get_or_run((1, 1), || reply(100)).len(), 100);
+        assert_eq!(cache.get_or_run((1, 1), || reply(1)).len(), 1);
+        assert_eq!(cache.inner.lock().unwrap().bytes, 1 + ENTRY_OVERHEAD);
+    }
+
+    #[test]
+    fn remove_on_panic() {
+        let cache = ReplyCache::new(Duration::from_secs(60), 1024);
+        let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+            cache.get_or_run((1, 1), || panic!("runner panicked"))
+        }));
+        assert!(ret.is_err());
+        assert!(cache.inner.lock().unwrap().map.is_empty());
+        // the retried request runs again
+        assert_eq!(*cache.get_or_run((1, 1), || b"reply".to_vec()), b"reply");
+    }
+}
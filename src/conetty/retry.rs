@@ -0,0 +1,302 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::errors::Error;
+
+/// the default max attempts of a call, including the first one
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
+/// the default backoff before the first retry
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+/// the default max backoff between retries
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(1);
+/// the default max retries that the budget holds
+const DEFAULT_BUDGET_MAX: u32 = 10;
+/// the default retries that each successful call refunds
+const DEFAULT_BUDGET_RATIO: f32 = 0.1;
+/// the budget tokens are counted in 1/1000 of a retry
+const TOKEN_SCALE: f32 = 1000.0;
+
+/// the class of the errors that a call can be retried on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RetryCode {
+    /// the call timed out, the server may have processed it
+    Timeout,
+    /// the connection is refused, closed or broken
+    Unavailable,
+    /// the server returned a status error, e.g. it's overloaded
+    Status,
+}
+
+impl RetryCode {
+    /// the class of the error, `None` if it's never retried, e.g. a serialization error
+    pub fn of(e: &Error) -> Option<Self> {
+        match e {
+            Error::Timeout => Some(RetryCode::Timeout),
+            Error::Status(_) => Some(RetryCode::Status),
+            Error::Io(e) => match e.kind() {
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof => Some(RetryCode::Unavailable),
+                io::ErrorKind::TimedOut => Some(RetryCode::Timeout),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// check if the call failed before the request is sent to the server
+/// e.g. the connection is closed or the request is refused by a going away server
+pub(crate) fn is_unsent(e: &Error) -> bool {
+    matches!(e, Error::Io(e) if matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotConnected
+    ))
+}
+
+#[derive(Debug)]
+struct Budget {
+    // all are in 1/1000 of a retry
+    max: u64,
+    ratio: u64,
+    tokens: AtomicU64,
+}
+
+/// the retries that can be spent, shared by all the calls and clients that hold it
+///
+/// each retry takes one token and each successful call refunds a fraction of it,
+/// so the retries stop once most of the calls are failing instead of multiplying the load
+#[derive(Clone)]
+pub struct RetryBudget(Arc<Budget>);
+
+impl fmt::Debug for RetryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryBudget")
+            .field("max", &(self.0.max / TOKEN_SCALE as u64))
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget::new(DEFAULT_BUDGET_MAX, DEFAULT_BUDGET_RATIO)
+    }
+}
+
+impl RetryBudget {
+    /// at most `max` retries can be spent in a row
+    /// each successful call refunds `ratio` of a retry, e.g. 0.1 allows one retry per 10 calls
+    pub fn new(max: u32, ratio: f32) -> Self {
+        let max = max as u64 * TOKEN_SCALE as u64;
+        RetryBudget(Arc::new(Budget {
+            max,
+            ratio: (ratio.max(0.0) * TOKEN_SCALE) as u64,
+            tokens: AtomicU64::new(max),
+        }))
+    }
+
+    /// number of the retries that can be spent now
+    pub fn available(&self) -> u32 {
+        (self.0.tokens.load(Ordering::Relaxed) / TOKEN_SCALE as u64) as u32
+    }
+
+    /// take a token for a retry, return false if the budget is exhausted
+    pub(crate) fn withdraw(&self) -> bool {
+        let one = TOKEN_SCALE as u64;
+        self.0
+            .tokens
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |t| t.checked_sub(one))
+            .is_ok()
+    }
+
+    /// refund the budget for a successful call
+    pub(crate) fn deposit(&self) {
+        let Budget { max, ratio, .. } = *self.0;
+        self.0
+            .tokens
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |t| {
+                (t < max).then(|| (t + ratio).min(max))
+            })
+            .ok();
+    }
+}
+
+/// the retry policy of a client, it's set by `ClientConfig::set_retry_policy`
+///
+/// the methods that are marked `#[idempotent]` are retried on the retryable errors,
+/// other methods are only retried when the request is never sent to the server
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<RetryCode>,
+    budget: RetryBudget,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            retry_on: vec![RetryCode::Unavailable],
+            budget: RetryBudget::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// create a default policy
+    /// it makes 3 attempts at most and only retries on `RetryCode::Unavailable`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set the max attempts of a call including the first one, the default value is 3
+    pub fn set_max_attempts(&mut self, attempts: usize) {
+        self.max_attempts = attempts.max(1);
+    }
+
+    /// wait `initial` before the first retry, then double it for each retry up to `max`
+    /// each wait is a random time between half of it and all of it, so that the retries
+    /// of many clients are spread out, the default values are 50 ms and 1 second
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+    }
+
+    /// set the errors that a call is retried on
+    pub fn set_retry_on(&mut self, codes: &[RetryCode]) {
+        self.retry_on = codes.to_vec();
+    }
+
+    /// share the retry budget with other policies
+    /// each policy has its own budget by default, which allows 10 retries in a row
+    pub fn set_budget(&mut self, budget: RetryBudget) {
+        self.budget = budget;
+    }
+
+    /// the budget of the retries
+    pub fn budget(&self) -> &RetryBudget {
+        &self.budget
+    }
+
+    /// check if the failed attempt should be retried, the budget is taken if so
+    pub(crate) fn should_retry(&self, e: &Error, attempt: usize, idempotent: bool) -> bool {
+        attempt < self.max_attempts
+            && (idempotent || is_unsent(e))
+            && RetryCode::of(e).is_some_and(|code| self.retry_on.contains(&code))
+            && self.budget.withdraw()
+    }
+
+    /// the time to wait before the next attempt, with the jitter
+    pub(crate) fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 1u32 << (attempt.saturating_sub(1)).min(16);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        let half = backoff / 2;
+        half + (backoff - half).mul_f64(jitter())
+    }
+}
+
+/// a random number in `[0, 1)`, each std hasher is seeded differently
+fn jitter() -> f64 {
+    let r = RandomState::new().build_hasher().finish();
+    (r >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_err(kind: io::ErrorKind) -> Error {
+        io::Error::from(kind).into()
+    }
+
+    fn policy(codes: &[RetryCode]) -> RetryPolicy {
+        let mut policy = RetryPolicy::new();
+        policy.set_retry_on(codes);
+        policy.set_budget(RetryBudget::new(100, 0.0));
+        policy
+    }
+
+    #[test]
+    fn retry_codes() {
+        let policy = policy(&[RetryCode::Timeout, RetryCode::Unavailable]);
+        // the idempotent calls are retried on the configured codes
+        assert!(policy.should_retry(&Error::Timeout, 1, true));
+        assert!(policy.should_retry(&io_err(io::ErrorKind::ConnectionReset), 1, true));
+        assert!(!policy.should_retry(&Error::Status("busy".to_owned()), 1, true));
+        // the errors that are never retried
+        assert!(!policy.should_retry(&Error::ClientSerialize("bad".to_owned()), 1, true));
+        assert!(!policy.should_retry(&io_err(io::ErrorKind::InvalidData), 1, true));
+        // the max attempts include the first one
+        assert!(policy.should_retry(&Error::Timeout, 2, true));
+        assert!(!policy.should_retry(&Error::Timeout, 3, true));
+    }
+
+    #[test]
+    fn retry_non_idempotent() {
+        let policy = policy(&[RetryCode::Timeout, RetryCode::Unavailable]);
+        // the request may have reached the server
+        assert!(!policy.should_retry(&Error::Timeout, 1, false));
+        assert!(!policy.should_retry(&io_err(io::ErrorKind::ConnectionReset), 1, false));
+        // the request is never sent
+        assert!(policy.should_retry(&io_err(io::ErrorKind::NotConnected), 1, false));
+        assert!(policy.should_retry(&io_err(io::ErrorKind::ConnectionRefused), 1, false));
+    }
+
+    #[test]
+    fn retry_budget() {
+        let budget = RetryBudget::new(2, 0.5);
+        assert_eq!(budget.available(), 2);
+        assert!(budget.withdraw());
+        // the budget is shared by the clones
+        assert!(budget.clone().withdraw());
+        assert!(!budget.withdraw());
+        assert_eq!(budget.available(), 0);
+
+        // each deposit refunds half a retry
+        budget.deposit();
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(budget.withdraw());
+        // never exceeds the max
+        (0..10).for_each(|_| budget.deposit());
+        assert_eq!(budget.available(), 2);
+
+        // the retries stop once the budget is exhausted
+        let mut policy = policy(&[RetryCode::Timeout]);
+        policy.set_budget(RetryBudget::new(1, 0.0));
+        assert!(policy.should_retry(&Error::Timeout, 1, true));
+        assert!(!policy.should_retry(&Error::Timeout, 1, true));
+    }
+
+    #[test]
+    fn backoff_with_jitter() {
+        let mut policy = RetryPolicy::new();
+        policy.set_backoff(Duration::from_millis(10), Duration::from_millis(50));
+        let expected = [10, 20, 40, 50, 50];
+        for (attempt, max) in (1..).zip(expected) {
+            let max = Duration::from_millis(max);
+            let backoffs: Vec<_> = (0..20).map(|_| policy.backoff(attempt)).collect();
+            for backoff in &backoffs {
+                assert!(*backoff >= max / 2 && *backoff <= max, "{backoff:?}");
+            }
+            // the waits are spread out
+            assert!(backoffs.iter().any(|b| *b != backoffs[0]));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::io;
+use std::sync::Weak;
+use std::time::Duration;
+
+use super::errors::Error;
+use super::frame::{Frame, ReqBuf};
+use super::Client;
+
+/// the default timeout of each reverse call
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the server connection that the reverse calls are sent through
+pub(crate) trait ReverseConn: Send + Sync {
+    /// send the request to the callback service of the client and wait for the response
+    fn call(&self, buf: Vec<u8>, timeout: Option<Duration>) -> Result<Frame, Error>;
+}
+
+/// the client that calls the callback service of a connected client
+///
+/// it's obtained from `ReqContext::reverse` when the client registered a callback service
+/// by `ClientConfig::set_callback`, the requests are sent over the same connection
+/// a generated `XxxStub` can be created from it by `from_transport`
+#[derive(Clone)]
+pub struct ReverseClient {
+    // the connection is not kept alive by the client
+    conn: Weak<dyn ReverseConn>,
+    timeout: Option<Duration>,
+}
+
+impl fmt::Debug for ReverseClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReverseClient")
+            .field("timeout", &self.timeout)
+            .field("connected", &(self.conn.strong_count() > 0))
+            .finish()
+    }
+}
+
+impl ReverseClient {
+    pub(crate) fn new(conn: Weak<dyn ReverseConn>) -> Self {
+        ReverseClient {
+            conn,
+            timeout: Some(DEFAULT_TIMEOUT),
+        }
+    }
+
+    /// set the timeout value of each call
+    /// the initial timeout is 10 seconds
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+}
+
+impl Client for ReverseClient {
+    fn call_service(&self, req: ReqBuf) -> Result<Frame, Error> {
+        let Some(conn) = self.conn.upgrade() else {
+            return Err(io::Error::from(io::ErrorKind::NotConnected).into());
+        };
+        if req.is_batch() {
+            let s = "batch is not supported by the reverse calls".to_owned();
+            return Err(Error::Status(s));
+        }
+        // the id is assigned when the request is sent
+        conn.call(req.finish(0), self.timeout)
+    }
+}
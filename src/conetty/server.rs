@@ -1,12 +1,18 @@
-use std::io::{self, BufReader, Cursor};
-use std::net::ToSocketAddrs;
+use std::fmt;
+use std::io::{self, Cursor};
+use std::net::{SocketAddr, ToSocketAddrs};
 #[cfg(unix)]
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use super::frame::{Frame, RspBuf};
-use super::queued_writer::QueuedWriter;
-use crate::Server;
+use super::batch;
+use super::config::ServerConfig;
+use super::connection::{serve_conn, Conns, OnewayStats};
+use super::errors::{as_too_large, FrameTooLarge};
+use super::frame::{Decoder, RspBuf};
+use super::handshake::Settings;
+use super::udp_client::MAX_DATAGRAM_LEN;
+use crate::{Server, ServiceFactory, WireError};
 
 use bytes::BytesMut;
 use co_managed::Manager;
@@ -28,59 +34,183 @@ macro_rules! t {
     };
 }
 
+/// the remote address of a server connection
+#[derive(Debug, Clone)]
+pub enum Peer {
+    /// a tcp peer
+    Tcp(SocketAddr),
+    /// a unix domain socket peer, unnamed socket has no path
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Peer::Unix(Some(path)) => write!(f, "{}", path.display()),
+            #[cfg(unix)]
+            Peer::Unix(None) => write!(f, "(unnamed)"),
+        }
+    }
+}
+
+/// the reason why a server connection is closed
+#[derive(Debug)]
+pub enum DisconnectReason {
+    /// the peer closed the connection
+    Closed,
+    /// failed to read a request from the peer
+    Read(io::Error),
+    /// the connection preface from the peer is invalid or missing
+    Handshake(io::Error),
+    /// a frame from the peer is corrupted
+    Checksum,
+    /// failed to write a response to the peer
+    Write(io::Error),
+    /// there is no request for longer than the idle timeout
+    IdleTimeout,
+    /// the peer doesn't answer the heartbeats
+    HeartbeatTimeout,
+    /// the server sent GOAWAY and the outstanding requests are done
+    GoAway,
+}
+
 /// service instance
-pub struct ServerInstance(Option<coroutine::JoinHandle<()>>);
+pub struct ServerInstance {
+    // the accepting coroutine
+    handle: Option<coroutine::JoinHandle<()>>,
+    // the connection coroutines, they are cancelled when the instance is dropped
+    manager: Option<Arc<Manager>>,
+    // the live connections of the stream servers
+    conns: Option<Arc<Conns>>,
+}
 
 impl ServerInstance {
+    fn new(handle: coroutine::JoinHandle<()>) -> Self {
+        ServerInstance {
+            handle: Some(handle),
+            manager: None,
+            conns: None,
+        }
+    }
+
     /// join the service, this would wait until the service is stopped
     pub fn join(mut self) -> std::thread::Result<()> {
-        if let Some(handle) = self.0.take() {
+        if let Some(handle) = self.handle.take() {
             handle.join()
         } else {
             Ok(())
         }
     }
-}
 
-impl Drop for ServerInstance {
-    fn drop(&mut self) {
-        if let Some(s) = self.0.take() {
+    /// gracefully shutdown the service
+    /// stop accepting new connections and send GOAWAY to all the connections
+    /// this would wait until their outstanding requests are done or the goaway timeout expired
+    pub fn shutdown(mut self) {
+        self.stop_accept();
+        if let Some(conns) = self.conns.take() {
+            conns.go_away_all();
+            conns.wait_closed();
+        }
+    }
+
+    /// get the oneway call counters of the stream server
+    /// the udp server never counts them, the client always waits for its response
+    pub fn oneway_stats(&self) -> OnewayStats {
+        self.conns
+            .as_ref()
+            .map(|conns| conns.oneway_stats())
+            .unwrap_or_default()
+    }
+
+    fn stop_accept(&mut self) {
+        if let Some(s) = self.handle.take() {
             unsafe { s.coroutine().cancel() };
             s.join().ok();
         }
     }
 }
 
+impl Drop for ServerInstance {
+    fn drop(&mut self) {
+        self.stop_accept();
+        // cancel all the connections
+        self.manager.take();
+    }
+}
+
 /// Provides a function for starting the service.
 pub trait UdpServer: Server {
     /// Spawns the service, binding to the given address
     /// return a coroutine that you can cancel it when need to stop the service
     fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerInstance> {
+        self.start_with_config(addr, ServerConfig::default())
+    }
+
+    /// Spawns the service with the given config, binding to the given address
+    /// return a coroutine that you can cancel it when need to stop the service
+    fn start_with_config<L: ToSocketAddrs>(
+        self,
+        addr: L,
+        config: ServerConfig,
+    ) -> io::Result<ServerInstance> {
+        let reply_cache = config.reply_cache().cloned();
+        let max_len = config.max_frame_len();
         let sock = UdpSocket::bind(addr)?; // the write half
         let sock1 = sock.try_clone()?; // the read half
         let instance = go!(
             coroutine::Builder::new().name("UdpServer".to_owned()),
             move || {
                 let server = Arc::new(self);
-                let mut buf = vec![0u8; 1024];
+                // the head and the checksum are received in the same datagram
+                let mut buf = vec![0u8; (max_len + 20).min(MAX_DATAGRAM_LEN)];
                 // the write half need to be protected by mutex
                 // for that coroutine io obj can't shared safely
                 let sock = Arc::new(Mutex::new(sock));
                 let mut body_buf = BytesMut::with_capacity(1024 * 32);
                 loop {
-                    // each udp packet should be less than 1024 bytes
                     let (len, addr) = t!(sock1.recv_from(&mut buf));
                     info!("recv_from: len={len:?} addr={addr:?}");
 
-                    // if we failed to deserialize the request frame, just continue
-                    let req = t!(Frame::decode_from(&mut Cursor::new(&buf), &mut body_buf));
+                    let mut decoder = Decoder::new(max_len, Settings::default());
+                    let req = match decoder.decode(&mut Cursor::new(&buf[..len]), &mut body_buf) {
+                        Ok(req) => req,
+                        // reply the too large request with a status error
+                        Err(e) if as_too_large(&e).is_some_and(|e| !e.control) => {
+                            let &FrameTooLarge { id, len, max, .. } = as_too_large(&e).unwrap();
+                            let status = format!("request frame too large: len={len}, max={max}");
+                            let data = RspBuf::new().finish(id, Err(WireError::Status(status)));
+                            let s = sock.lock().unwrap();
+                            if let Err(err) = s.send_to(&data, addr) {
+                                error!("udp send_to failed, err={err:?}");
+                            }
+                            continue;
+                        }
+                        // if we failed to deserialize the request frame, just continue
+                        Err(e) => {
+                            error!("udp decode req failed, err={e:?}");
+                            continue;
+                        }
+                    };
                     let sock = sock.clone();
                     let server = server.clone();
+                    let reply_cache = reply_cache.clone();
                     // let mutex = mutex.clone();
                     go!(move || {
                         let mut rsp = RspBuf::new();
-                        let ret = server.service(req.decode_req(), &mut rsp);
-                        let data = rsp.finish(req.id, ret);
+                        let mut ret = if req.is_batch() {
+                            let cache = reply_cache.as_deref();
+                            batch::serve(&server, req.decode_req(), &mut rsp, max_len, cache)
+                        } else {
+                            server.service(req.decode_req(), &mut rsp)
+                        };
+                        if rsp.take_stream().is_some() {
+                            let s = "streaming is not supported by udp server".to_owned();
+                            ret = Err(WireError::Status(s));
+                        }
+                        let data = rsp.finish_limited(req.id, ret, max_len);
 
                         info!("send_to: len={:?} addr={:?}", data.len(), addr);
 
@@ -95,8 +225,79 @@ pub trait UdpServer: Server {
                 }
             }
         )?;
-        Ok(ServerInstance(Some(instance)))
+        Ok(ServerInstance::new(instance))
+    }
+}
+
+/// spawn the tcp accept loop, `new_service` provides the service for each connection
+fn start_tcp<L, T, F>(addr: L, config: ServerConfig, new_service: F) -> io::Result<ServerInstance>
+where
+    L: ToSocketAddrs,
+    T: Server,
+    F: Fn(&Peer) -> Arc<T> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let manager = Arc::new(Manager::new());
+    let conns = Arc::new(Conns::new(config));
+    let (m, c) = (manager.clone(), conns.clone());
+    let instance = go!(
+        coroutine::Builder::new().name("TcpServer".to_owned()),
+        move || {
+            let new_service = Arc::new(new_service);
+            for stream in listener.incoming() {
+                let stream = t!(stream);
+                t!(stream.set_nodelay(true));
+                let peer = Peer::Tcp(t!(stream.peer_addr()));
+                let new_service = new_service.clone();
+                let conns = c.clone();
+                m.add(move || serve_conn(&*new_service, &conns, stream, peer));
+            }
+        }
+    )?;
+    let mut instance = ServerInstance::new(instance);
+    instance.manager = Some(manager);
+    instance.conns = Some(conns);
+    Ok(instance)
+}
+
+/// spawn the uds accept loop, `new_service` provides the service for each connection
+#[cfg(unix)]
+fn start_uds<P, T, F>(path: P, config: ServerConfig, new_service: F) -> io::Result<ServerInstance>
+where
+    P: AsRef<Path>,
+    T: Server,
+    F: Fn(&Peer) -> Arc<T> + Send + Sync + 'static,
+{
+    struct AutoDrop(UnixListener, PathBuf);
+    impl Drop for AutoDrop {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.1).ok();
+        }
     }
+
+    std::fs::remove_file(&path).ok();
+    let listener = AutoDrop(UnixListener::bind(&path)?, path.as_ref().to_owned());
+    let manager = Arc::new(Manager::new());
+    let conns = Arc::new(Conns::new(config));
+    let (m, c) = (manager.clone(), conns.clone());
+    let instance = go!(
+        coroutine::Builder::new().name("Unix Socket Server".to_owned()),
+        move || {
+            let new_service = Arc::new(new_service);
+            for stream in listener.0.incoming() {
+                let stream = t!(stream);
+                let addr = t!(stream.peer_addr());
+                let peer = Peer::Unix(addr.as_pathname().map(Path::to_owned));
+                let new_service = new_service.clone();
+                let conns = c.clone();
+                m.add(move || serve_conn(&*new_service, &conns, stream, peer));
+            }
+        }
+    )?;
+    let mut instance = ServerInstance::new(instance);
+    instance.manager = Some(manager);
+    instance.conns = Some(conns);
+    Ok(instance)
 }
 
 /// Provides a function for starting the tcp service.
@@ -104,54 +305,18 @@ pub trait TcpServer: Server {
     /// Spawns the service, binding to the given address
     /// return a coroutine that you can cancel it when need to stop the service
     fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerInstance> {
-        let listener = TcpListener::bind(addr)?;
-        let instance = go!(
-            coroutine::Builder::new().name("TcpServer".to_owned()),
-            move || {
-                let server = Arc::new(self);
-                let manager = Manager::new();
-                for stream in listener.incoming() {
-                    let stream = t!(stream);
-                    stream.set_nodelay(true).unwrap();
-                    let server = server.clone();
-                    manager.add(move || {
-                        let rs = stream.try_clone().expect("failed to clone stream");
-                        // the read half of the stream
-                        let mut rs = BufReader::new(rs);
-                        // the write half of the stream
-                        let ws = Arc::new(QueuedWriter::new(stream));
-                        let mut buf = BytesMut::with_capacity(1024 * 32);
-                        loop {
-                            let req = match Frame::decode_from(&mut rs, &mut buf) {
-                                Ok(r) => r,
-                                Err(ref e) => {
-                                    if e.kind() == io::ErrorKind::UnexpectedEof {
-                                        info!("tcp server decode req: connection closed");
-                                    } else {
-                                        error!("tcp server decode req: err = {e:?}");
-                                    }
-                                    break;
-                                }
-                            };
-
-                            info!("get request: id={:?}", req.id);
-                            let w_stream = ws.clone();
-                            let server = server.clone();
-                            go!(move || {
-                                let mut rsp = RspBuf::new();
-                                let ret = server.service(req.decode_req(), &mut rsp);
-                                let data = rsp.finish(req.id, ret);
-
-                                info!("send rsp: id={}", req.id);
-                                // send the result back to client
-                                w_stream.write(data).expect("tcp write to client failed");
-                            });
-                        }
-                    });
-                }
-            }
-        )?;
-        Ok(ServerInstance(Some(instance)))
+        self.start_with_config(addr, ServerConfig::default())
+    }
+
+    /// Spawns the service with the given config, binding to the given address
+    /// return a coroutine that you can cancel it when need to stop the service
+    fn start_with_config<L: ToSocketAddrs>(
+        self,
+        addr: L,
+        config: ServerConfig,
+    ) -> io::Result<ServerInstance> {
+        let server = Arc::new(self);
+        start_tcp(addr, config, move |_| server.clone())
     }
 }
 
@@ -161,62 +326,59 @@ pub trait UdsServer: Server {
     /// Spawns the service, binding to the given address
     /// return a coroutine that you can cancel it when need to stop the service
     fn start<P: AsRef<Path>>(self, path: P) -> io::Result<ServerInstance> {
-        struct AutoDrop(UnixListener, PathBuf);
-        impl Drop for AutoDrop {
-            fn drop(&mut self) {
-                std::fs::remove_file(&self.1).ok();
-            }
-        }
+        self.start_with_config(path, ServerConfig::default())
+    }
 
-        std::fs::remove_file(&path).ok();
-        let listener = AutoDrop(UnixListener::bind(&path)?, path.as_ref().to_owned());
-        let instance = go!(
-            coroutine::Builder::new().name("Unix Socket Server".to_owned()),
-            move || {
-                let server = Arc::new(self);
-                let manager = Manager::new();
-                for stream in listener.0.incoming() {
-                    let stream = t!(stream);
-                    let server = server.clone();
-                    manager.add(move || {
-                        let rs = stream.try_clone().expect("failed to clone stream");
-                        // the read half of the stream
-                        let mut rs = BufReader::new(rs);
-                        // the write half need to be protected by mutex
-                        // for that coroutine io obj can't shared safely
-                        let ws = Arc::new(QueuedWriter::new(stream));
-                        let mut buf = BytesMut::with_capacity(1024 * 32);
-                        loop {
-                            let req = match Frame::decode_from(&mut rs, &mut buf) {
-                                Ok(r) => r,
-                                Err(ref e) => {
-                                    if e.kind() == io::ErrorKind::UnexpectedEof {
-                                        info!("uds server decode req: connection closed");
-                                    } else {
-                                        error!("uds server decode req: err = {e:?}");
-                                    }
-                                    break;
-                                }
-                            };
-
-                            info!("get request: id={:?}", req.id);
-                            let w_stream = ws.clone();
-                            let server = server.clone();
-                            go!(move || {
-                                let mut rsp = RspBuf::new();
-                                let ret = server.service(req.decode_req(), &mut rsp);
-                                let data = rsp.finish(req.id, ret);
-
-                                info!("send rsp: id={}", req.id);
-                                // send the result back to client
-                                w_stream.write(data).expect("uds write to client failed");
-                            });
-                        }
-                    });
-                }
-            }
-        )?;
-        Ok(ServerInstance(Some(instance)))
+    /// Spawns the service with the given config, binding to the given address
+    /// return a coroutine that you can cancel it when need to stop the service
+    fn start_with_config<P: AsRef<Path>>(
+        self,
+        path: P,
+        config: ServerConfig,
+    ) -> io::Result<ServerInstance> {
+        let server = Arc::new(self);
+        start_uds(path, config, move |_| server.clone())
+    }
+}
+
+/// Provides a function for starting the tcp service with a session per connection.
+pub trait TcpSessionServer: ServiceFactory {
+    /// Spawns the service, binding to the given address
+    /// each accepted connection is served by a new session from `new_session`
+    /// return a coroutine that you can cancel it when need to stop the service
+    fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerInstance> {
+        self.start_with_config(addr, ServerConfig::default())
+    }
+
+    /// Spawns the service with the given config, binding to the given address
+    /// return a coroutine that you can cancel it when need to stop the service
+    fn start_with_config<L: ToSocketAddrs>(
+        self,
+        addr: L,
+        config: ServerConfig,
+    ) -> io::Result<ServerInstance> {
+        start_tcp(addr, config, move |peer| Arc::new(self.new_session(peer)))
+    }
+}
+
+/// Provides a function for starting the unix domain socket service with a session per connection.
+#[cfg(unix)]
+pub trait UdsSessionServer: ServiceFactory {
+    /// Spawns the service, binding to the given address
+    /// each accepted connection is served by a new session from `new_session`
+    /// return a coroutine that you can cancel it when need to stop the service
+    fn start<P: AsRef<Path>>(self, path: P) -> io::Result<ServerInstance> {
+        self.start_with_config(path, ServerConfig::default())
+    }
+
+    /// Spawns the service with the given config, binding to the given address
+    /// return a coroutine that you can cancel it when need to stop the service
+    fn start_with_config<P: AsRef<Path>>(
+        self,
+        path: P,
+        config: ServerConfig,
+    ) -> io::Result<ServerInstance> {
+        start_uds(path, config, move |peer| Arc::new(self.new_session(peer)))
     }
 }
 
@@ -224,3 +386,6 @@ impl<T: Server> UdpServer for T {}
 impl<T: Server> TcpServer for T {}
 #[cfg(unix)]
 impl<T: Server> UdsServer for T {}
+impl<T: ServiceFactory> TcpSessionServer for T {}
+#[cfg(unix)]
+impl<T: ServiceFactory> UdsSessionServer for T {}
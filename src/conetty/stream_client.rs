@@ -3,8 +3,10 @@ use std::time::Duration;
 
 use bytes::BytesMut;
 
-use super::errors::Error;
-use super::frame::{Frame, ReqBuf};
+use super::errors::{as_too_large, Error};
+use super::frame::{check_frame_len, split_frame, Assembled, Assembler, Control, Frame, ReqBuf};
+use super::frame::{Decoder, DEFAULT_MAX_FRAME_LEN, DEFAULT_MAX_MESSAGE_LEN};
+use super::handshake::{client_handshake, Settings, DEFAULT_HANDSHAKE_TIMEOUT};
 use super::stream_ext::StreamExt;
 
 /// Stream Client
@@ -13,6 +15,16 @@ pub struct StreamClient<S: StreamExt> {
     id: u64,
     // the connection
     stream: BufReader<S>,
+    // the connection preface is exchanged
+    handshaked: bool,
+    // the max time to wait for the server preface
+    handshake_timeout: Duration,
+    // the decode buffer, reused by all the calls
+    buf: BytesMut,
+    // the max payload len of a frame
+    max_frame_len: usize,
+    // the max payload len of a message that is split into frames
+    max_message_len: usize,
 }
 
 impl<S: StreamExt> StreamClient<S> {
@@ -21,8 +33,32 @@ impl<S: StreamExt> StreamClient<S> {
         StreamClient {
             id: 0,
             stream: BufReader::with_capacity(1024 * 32, stream),
+            handshaked: false,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            buf: BytesMut::with_capacity(1024 * 32),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
         }
     }
+
+    /// set the max payload len of a frame, the default value is 1 MiB
+    /// a too large request or response would fail the call with `Error::FrameTooLarge`
+    pub fn set_max_frame_len(&mut self, len: usize) {
+        self.max_frame_len = len;
+    }
+
+    /// set the max payload len of a request or response, the default value is 16 MiB
+    /// messages larger than a frame are split into continuation frames
+    /// a too large message would fail the call with `Error::FrameTooLarge`
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = len;
+    }
+
+    /// set the max time to wait for the connection preface from the server
+    /// the first call fails when the timeout expired, the default value is 10 seconds
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
 }
 
 impl<S: StreamExt> StreamClient<S> {
@@ -37,20 +73,53 @@ impl<S: StreamExt> StreamClient<S> {
     /// the request must be encoded into the ReqBuf
     /// the response is the raw frame, you should parsing it into final response
     pub fn call_service(&mut self, req: ReqBuf) -> Result<Frame, Error> {
+        if !self.handshaked {
+            // no capabilities are asked, nothing is buffered before the preface
+            client_handshake(self.stream.get_mut(), 0, self.handshake_timeout)?;
+            self.handshaked = true;
+        }
+
         let id = self.id;
         self.id += 1;
         info!("request id = {id}");
 
         // encode the request
-        self.stream.get_mut().write_all(&(req.finish(id)))?;
+        let req = req.finish(id);
+        check_frame_len(&req, self.max_message_len)?;
+        for frame in split_frame(req, self.max_frame_len) {
+            self.stream.get_mut().write_all(&frame)?;
+        }
 
-        let mut buf = BytesMut::with_capacity(1024 * 32);
+        let mut decoder = Decoder::new(self.max_frame_len, Settings::default());
+        let mut assembler = Assembler::new(self.max_message_len);
 
         // read the response
         loop {
             // deserialize the rsp
-            let rsp_frame = Frame::decode_from(&mut self.stream, &mut buf)
-                .map_err(|e| Error::ClientDeserialize(e.to_string()))?;
+            let rsp_frame = decoder
+                .decode(&mut self.stream, &mut self.buf)
+                .map_err(|e| match as_too_large(&e) {
+                    Some(_) => Error::from(e),
+                    None => Error::ClientDeserialize(e.to_string()),
+                })?;
+
+            // answer the heartbeat from server while waiting for the response
+            if rsp_frame.is_control() {
+                if let Some((Control::Ping, body)) = rsp_frame.decode_control() {
+                    let pong = Control::Pong.encode(rsp_frame.id, body);
+                    self.stream.get_mut().write_all(&pong)?;
+                }
+                continue;
+            }
+
+            let rsp_frame = match assembler.push(rsp_frame)? {
+                Assembled::Done(frame) => frame,
+                Assembled::Partial | Assembled::Discarded => continue,
+                Assembled::TooLarge { id: rsp_id, len } if rsp_id == id => {
+                    return Err(Error::FrameTooLarge(len, self.max_message_len));
+                }
+                Assembled::TooLarge { .. } => continue,
+            };
 
             // discard the rsp that is is not belong to us
             if rsp_frame.id == id {
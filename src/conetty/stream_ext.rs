@@ -1,7 +1,7 @@
-use std::io::{self, Read, Write};
+use std::io::{self, IoSlice, Read, Write};
 use std::time::Duration;
 
-use may::io::SplitIo;
+use may::io::{SplitIo, SplitWriter};
 
 /// Stream Extension
 pub trait StreamExt: Sized + SplitIo + Read + Write + Send + 'static {
@@ -9,6 +9,8 @@ pub trait StreamExt: Sized + SplitIo + Read + Write + Send + 'static {
     fn try_clone(&self) -> io::Result<Self>;
     /// set read timeout
     fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+    /// shutdown both the read and write half of the stream
+    fn shutdown(&self) -> io::Result<()>;
 }
 
 macro_rules! impl_stream_ext {
@@ -20,10 +22,31 @@ macro_rules! impl_stream_ext {
             fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
                 (*self).set_read_timeout(Some(timeout))
             }
+            fn shutdown(&self) -> io::Result<()> {
+                (*self).shutdown(std::net::Shutdown::Both)
+            }
         }
     };
 }
 
+/// the write half of a split stream
+/// `SplitWriter` doesn't forward the vectored writes to the stream
+pub(crate) struct VectoredWriter<S>(pub SplitWriter<S>);
+
+impl<S: Write> Write for VectoredWriter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.0.inner_mut().write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
 impl_stream_ext!(may::net::TcpStream);
 #[cfg(unix)]
 impl_stream_ext!(may::os::unix::net::UnixStream);
@@ -0,0 +1,609 @@
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::errors::{Error, WireError};
+use super::frame::{add_frame_flags, encode_stream_end, Frame, RspBuf, FLAG_STREAM};
+
+use may::sync::{mpsc, Semphore};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// the number of items that can be sent ahead of the receiver
+pub(crate) const STREAM_WINDOW: u32 = 32;
+
+/// a stream of items that are produced incrementally
+///
+/// a streaming method returns it on the server, each item is sent as a frame once it's
+/// pulled from the iterator, at most `STREAM_WINDOW` items ahead of the client
+/// the client consumes it as a blocking iterator, dropping it cancels the rest items
+pub struct Stream<T> {
+    inner: Inner<T>,
+}
+
+enum Inner<T> {
+    // the items of a local iterator
+    Local(Box<dyn Iterator<Item = Result<T, Error>> + Send>),
+    // the items that are sent by a local sender
+    Channel(Channel<T>),
+    // the items that are received from the peer
+    Remote(Remote),
+    // the local iterator ended with an error
+    Failed,
+}
+
+/// the receiving half of a local channel
+struct Channel<T> {
+    rx: mpsc::Receiver<T>,
+    window: Arc<Window>,
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        // wake up the sender, no more items are needed
+        self.window.cancel();
+    }
+}
+
+impl<T> fmt::Debug for Stream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inner {
+            Inner::Local(_) | Inner::Channel(_) | Inner::Failed => {
+                f.debug_struct("Stream").finish_non_exhaustive()
+            }
+            Inner::Remote(ref r) => f
+                .debug_struct("Stream")
+                .field("id", &r.id)
+                .field("done", &r.done)
+                .finish(),
+        }
+    }
+}
+
+impl<T: 'static> Stream<T> {
+    /// create a stream from the items of the iterator
+    pub fn new<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        let iter = iter.into_iter().map(Ok);
+        Stream {
+            inner: Inner::Local(Box::new(iter)),
+        }
+    }
+
+    /// create a stream from the results of the iterator
+    /// the stream ends with the first error, the client gets it as `Error::Status`
+    pub fn try_new<I, E>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        I::IntoIter: Send + 'static,
+        E: fmt::Display,
+    {
+        let iter = iter
+            .into_iter()
+            .map(|item| item.map_err(|e| Error::Status(e.to_string())));
+        Stream {
+            inner: Inner::Local(Box::new(iter)),
+        }
+    }
+}
+
+impl<T> Stream<T> {
+    /// create a stream whose items are sent by the returned sender
+    /// the stream ends once the sender is dropped, the sender is at most
+    /// `STREAM_WINDOW` items ahead of the stream
+    pub fn channel() -> (Sender<T>, Self) {
+        let (tx, rx) = mpsc::channel();
+        let window = Arc::new(Window::new());
+        let sender = Sender {
+            inner: SenderInner::Local(tx, window.clone()),
+        };
+        let stream = Stream {
+            inner: Inner::Channel(Channel { rx, window }),
+        };
+        (sender, stream)
+    }
+
+    /// the stream that receives the items of a streaming call
+    pub(crate) fn remote(remote: Remote) -> Self {
+        Stream {
+            inner: Inner::Remote(remote),
+        }
+    }
+
+    /// receive the items that are uploaded by the client, used by the generated dispatch code
+    #[doc(hidden)]
+    pub fn receive(rsp: &mut RspBuf) -> Result<Self, WireError> {
+        match rsp.take_upload() {
+            Some(remote) => Ok(Stream::remote(remote)),
+            None => {
+                let s = "streaming method is called without a streaming call".to_owned();
+                Err(WireError::Status(s))
+            }
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Send + 'static> Stream<T> {
+    /// send the items before the response, used by the generated dispatch code
+    #[doc(hidden)]
+    pub fn serve(self, rsp: &mut RspBuf) -> Result<(), WireError> {
+        rsp.set_stream(Box::new(self));
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for Stream<T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner {
+            Inner::Local(ref mut iter) => {
+                let item = iter.next()?;
+                if item.is_err() {
+                    self.inner = Inner::Failed;
+                }
+                Some(item)
+            }
+            Inner::Channel(ref mut ch) => {
+                let item = ch.rx.recv().ok()?;
+                ch.window.grant(1);
+                Some(Ok(item))
+            }
+            Inner::Failed => None,
+            Inner::Remote(ref mut remote) => Some(remote.next_item()?.and_then(|frame| {
+                let data = frame.decode_rsp()?;
+                bincode::deserialize(data).map_err(|e| Error::ClientDeserialize(e.to_string()))
+            })),
+        }
+    }
+}
+
+/// the sending half of a stream
+///
+/// it's created with a local stream by `Stream::channel`, or returned by a bidirectional
+/// streaming call to send the items to the server, dropping it half closes the stream
+pub struct Sender<T> {
+    inner: SenderInner<T>,
+}
+
+enum SenderInner<T> {
+    // send to a local stream
+    Local(mpsc::Sender<T>, Arc<Window>),
+    // send to the peer
+    Remote(Upload),
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inner {
+            SenderInner::Local(..) => f.debug_struct("Sender").finish_non_exhaustive(),
+            SenderInner::Remote(ref u) => f.debug_struct("Sender").field("id", &u.id).finish(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// the sender that uploads the items of a streaming call
+    pub(crate) fn remote(upload: Upload) -> Self {
+        Sender {
+            inner: SenderInner::Remote(upload),
+        }
+    }
+
+    /// half close the stream, the receiver ends after the sent items
+    pub fn close(self) {}
+}
+
+impl<T: Serialize> Sender<T> {
+    /// send an item, block until the receiver is ready for it
+    ///
+    /// return an error if the receiver is dropped or the call is closed
+    pub fn send(&self, item: T) -> Result<(), Error> {
+        match self.inner {
+            SenderInner::Local(ref tx, ref window) => {
+                window.acquire(None).map_err(stream_closed)?;
+                tx.send(item).map_err(|_| stream_closed(Sent::Cancelled))
+            }
+            SenderInner::Remote(ref upload) => upload.send(&item),
+        }
+    }
+}
+
+/// the error of sending to a closed stream
+fn stream_closed(sent: Sent) -> Error {
+    match sent {
+        Sent::TimedOut => Error::Timeout,
+        _ => io::Error::new(io::ErrorKind::BrokenPipe, "stream is closed").into(),
+    }
+}
+
+/// the connection that the items are uploaded to
+pub(crate) trait SinkConn: Send + Sync {
+    /// write an encoded item or the stream end
+    fn write_item(&self, data: Vec<u8>) -> io::Result<()>;
+}
+
+/// the sending half of a streaming call
+pub(crate) struct Upload {
+    id: u64,
+    window: Arc<Window>,
+    conn: Arc<dyn SinkConn>,
+    max_len: usize,
+    // the max time to wait for each credit
+    timeout: Option<Duration>,
+}
+
+impl Upload {
+    pub fn new(
+        id: u64,
+        window: Arc<Window>,
+        conn: Arc<dyn SinkConn>,
+        max_len: usize,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Upload {
+            id,
+            window,
+            conn,
+            max_len,
+            timeout,
+        }
+    }
+
+    fn send<T: Serialize>(&self, item: &T) -> Result<(), Error> {
+        self.window.acquire(self.timeout).map_err(stream_closed)?;
+        let mut rsp = RspBuf::new();
+        bincode::serialize_into(&mut rsp, item)
+            .map_err(|e| Error::ClientSerialize(e.to_string()))?;
+        let data = encode_item(self.id, rsp, self.max_len).map_err(|e| match e {
+            WireError::Status(s) => Error::ClientSerialize(s),
+            _ => unreachable!("only the len is checked"),
+        })?;
+        Ok(self.conn.write_item(data)?)
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // the call is already closed if cancelled
+        if !self.window.is_cancelled() {
+            self.conn.write_item(encode_stream_end(self.id)).ok();
+        }
+    }
+}
+
+/// the items of a stream that are serialized one by one
+pub(crate) trait Source {
+    /// serialize the next item into the buf, `None` if there are no more items
+    fn next_into(&mut self, rsp: &mut RspBuf) -> Option<Result<(), WireError>>;
+}
+
+impl<T: Serialize + DeserializeOwned> Source for Stream<T> {
+    fn next_into(&mut self, rsp: &mut RspBuf) -> Option<Result<(), WireError>> {
+        // the items are pulled after the method returns, catch the panics here
+        let item = match panic::catch_unwind(AssertUnwindSafe(|| self.next())) {
+            Ok(item) => item?,
+            Err(_) => {
+                self.inner = Inner::Failed;
+                let s = "stream panicked in server!".to_owned();
+                return Some(Err(WireError::Status(s)));
+            }
+        };
+        let ret = match item {
+            Ok(item) => bincode::serialize_into(rsp, &item)
+                .map_err(|e| WireError::ServerSerialize(e.to_string())),
+            Err(Error::Status(s)) => Err(WireError::Status(s)),
+            Err(e) => Err(WireError::Status(e.to_string())),
+        };
+        Some(ret)
+    }
+}
+
+/// the items of an iterator that are uploaded by the client
+pub(crate) struct Items<I>(pub I);
+
+impl<I> Source for Items<I>
+where
+    I: Iterator,
+    I::Item: Serialize,
+{
+    fn next_into(&mut self, rsp: &mut RspBuf) -> Option<Result<(), WireError>> {
+        let item = self.0.next()?;
+        let ret = bincode::serialize_into(rsp, &item);
+        Some(ret.map_err(|e| WireError::ServerSerialize(e.to_string())))
+    }
+}
+
+/// why the sending of the items stopped
+#[derive(Debug)]
+pub(crate) enum Sent {
+    /// all the items are sent, or the source failed with the error
+    Done(Result<(), WireError>),
+    /// the receiver cancelled the stream or the connection is dead
+    Cancelled,
+    /// no credit is granted in time
+    TimedOut,
+}
+
+/// send the items of the source as stream frames with the window
+/// `write` returns false if the connection is dead
+pub(crate) fn send_items<F>(
+    id: u64,
+    window: &Window,
+    source: &mut dyn Source,
+    max_len: usize,
+    timeout: Option<Duration>,
+    mut write: F,
+) -> Sent
+where
+    F: FnMut(Vec<u8>) -> bool,
+{
+    loop {
+        if let Err(stop) = window.acquire(timeout) {
+            info!("stop sending the stream: id={id}, reason={stop:?}");
+            return stop;
+        }
+        let mut rsp = RspBuf::new();
+        match source.next_into(&mut rsp) {
+            Some(Ok(())) => {}
+            Some(Err(e)) => return Sent::Done(Err(e)),
+            None => return Sent::Done(Ok(())),
+        }
+        let data = match encode_item(id, rsp, max_len) {
+            Ok(data) => data,
+            Err(e) => return Sent::Done(Err(e)),
+        };
+        if !write(data) {
+            return Sent::Cancelled;
+        }
+    }
+}
+
+/// encode a serialized item as a stream frame
+fn encode_item(id: u64, rsp: RspBuf, max_len: usize) -> Result<Vec<u8>, WireError> {
+    // the item payload is ty(u8) + len(u64) + data
+    let len = rsp.data_len() + 9;
+    if len > max_len {
+        let s = format!("stream item too large: len={len}, max={max_len}");
+        return Err(WireError::Status(s));
+    }
+    let mut data = rsp.finish_limited(id, Ok(()), max_len);
+    add_frame_flags(&mut data, FLAG_STREAM);
+    Ok(data)
+}
+
+/// the send window of a stream, the receiver grants credits as it consumes the items
+#[derive(Debug)]
+pub(crate) struct Window {
+    credits: Semphore,
+    cancelled: AtomicBool,
+}
+
+impl Window {
+    pub fn new() -> Self {
+        Window {
+            credits: Semphore::new(STREAM_WINDOW as usize),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    /// wait for a credit to send an item
+    pub fn acquire(&self, timeout: Option<Duration>) -> Result<(), Sent> {
+        if self.is_cancelled() {
+            return Err(Sent::Cancelled);
+        }
+        let acquired = match timeout {
+            Some(timeout) => self.credits.wait_timeout(timeout),
+            None => {
+                self.credits.wait();
+                true
+            }
+        };
+        if self.is_cancelled() {
+            // pass the wakeup on to the other waiting senders
+            if acquired {
+                self.credits.post();
+            }
+            return Err(Sent::Cancelled);
+        }
+        if !acquired {
+            return Err(Sent::TimedOut);
+        }
+        Ok(())
+    }
+
+    /// the receiver consumed `n` items, the credits never exceed the window
+    pub fn grant(&self, n: u32) {
+        for _ in 0..n {
+            if self.credits.get_value() >= STREAM_WINDOW as usize {
+                break;
+            }
+            self.credits.post();
+        }
+    }
+
+    /// check if the receiver cancelled the stream
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// the receiver is not interested in the rest items, wake up the senders
+    pub fn cancel(&self) {
+        if !self.cancelled.swap(true, Ordering::AcqRel) {
+            self.credits.post();
+        }
+    }
+}
+
+/// the connection that a remote stream is received from
+pub(crate) trait StreamConn: Send + Sync {
+    /// grant the sender credits for `n` more items
+    fn credit(&self, id: u64, n: u32);
+    /// the stream is done, the sender is cancelled if `cancel` is set
+    fn finish(&self, id: u64, cancel: bool);
+}
+
+/// the receiving half of a streaming call
+pub(crate) struct Remote {
+    id: u64,
+    conn: Arc<dyn StreamConn>,
+    // the item frames and the response of the call
+    rx: mpsc::Receiver<io::Result<Frame>>,
+    // the max time to wait for each item
+    timeout: Option<Duration>,
+    // the items that are consumed but not granted back yet
+    consumed: u32,
+    // the stream is closed or cancelled
+    done: bool,
+}
+
+impl Remote {
+    pub fn new(
+        id: u64,
+        conn: Arc<dyn StreamConn>,
+        rx: mpsc::Receiver<io::Result<Frame>>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Remote {
+            id,
+            conn,
+            rx,
+            timeout,
+            consumed: 0,
+            done: false,
+        }
+    }
+
+    /// receive the next frame of the stream
+    fn recv(&mut self) -> Result<Frame, Error> {
+        let frame = match self.timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => Error::Timeout,
+                RecvTimeoutError::Disconnected => {
+                    io::Error::from(io::ErrorKind::NotConnected).into()
+                }
+            }),
+            None => self
+                .rx
+                .recv()
+                .map_err(|_| io::Error::from(io::ErrorKind::NotConnected).into()),
+        };
+        match frame {
+            Ok(Ok(frame)) => Ok(frame),
+            Ok(Err(e)) => {
+                self.finish(false);
+                Err(e.into())
+            }
+            Err(e) => {
+                self.finish(true);
+                Err(e)
+            }
+        }
+    }
+
+    /// receive the next item frame, `None` once the stream is closed
+    fn next_item(&mut self) -> Option<Result<Frame, Error>> {
+        if self.done {
+            return None;
+        }
+        let frame = match self.recv() {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+        if !frame.is_stream() {
+            // the response closes the stream, it's empty unless the stream failed
+            self.finish(false);
+            return frame.decode_rsp().err().map(Err);
+        }
+        if frame.is_stream_end() {
+            // the sender half closed the stream
+            self.finish(false);
+            return None;
+        }
+        self.consumed += 1;
+        if self.consumed >= STREAM_WINDOW / 2 {
+            self.conn.credit(self.id, self.consumed);
+            self.consumed = 0;
+        }
+        Some(Ok(frame))
+    }
+
+    /// the id of the streaming call
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// wait for the response that closes the call, the items before it are dropped
+    pub fn wait_rsp(mut self) -> Result<Frame, Error> {
+        loop {
+            let frame = self.recv()?;
+            if !frame.is_stream() {
+                self.finish(false);
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// close the stream without cancelling the sender, the peer would not send any items
+    pub fn close(mut self) {
+        self.finish(false);
+    }
+
+    fn finish(&mut self, cancel: bool) {
+        if !self.done {
+            self.done = true;
+            self.conn.finish(self.id, cancel);
+        }
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        self.finish(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_after_receiver_dropped() {
+        let (tx, mut rx) = Stream::<u32>::channel();
+        tx.send(1).unwrap();
+        assert_eq!(rx.next().unwrap().unwrap(), 1);
+        drop(rx);
+        // more than the window, none of them should block
+        for i in 0..STREAM_WINDOW * 3 {
+            assert!(tx.send(i).is_err());
+        }
+    }
+
+    #[test]
+    fn cancel_wakes_all_senders() {
+        let (tx, rx) = Stream::<u32>::channel();
+        for i in 0..STREAM_WINDOW {
+            tx.send(i).unwrap();
+        }
+        let tx = Arc::new(tx);
+        let senders: Vec<_> = (0..4)
+            .map(|i| {
+                let tx = tx.clone();
+                may::go!(move || tx.send(i))
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(50));
+        drop(rx);
+        for h in senders {
+            assert!(h.join().unwrap().is_err());
+        }
+        assert!(tx.send(0).is_err());
+    }
+}
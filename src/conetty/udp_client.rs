@@ -2,12 +2,19 @@ use std::io::{self, Cursor};
 use std::net::ToSocketAddrs;
 use std::time::Duration;
 
-use super::errors::Error;
-use super::frame::{Frame, ReqBuf};
+use super::errors::{as_too_large, Error};
+use super::frame::{check_frame_len, Decoder, Frame, ReqBuf, DEFAULT_MAX_FRAME_LEN};
+use super::handshake::Settings;
+use super::multiplex_client::random_id;
+use super::retry::RetryPolicy;
 
 use bytes::BytesMut;
+use may::coroutine;
 use may::net::UdpSocket;
 
+/// the max payload len of a udp datagram
+pub(crate) const MAX_DATAGRAM_LEN: usize = 65507;
+
 /// Udp Client
 #[derive(Debug)]
 pub struct UdpClient {
@@ -17,6 +24,18 @@ pub struct UdpClient {
     sock: UdpSocket,
     // send/recv buf
     buf: Vec<u8>,
+    // the decode buffer, reused by all the calls
+    rsp_buf: BytesMut,
+    // the max payload len of a frame
+    max_frame_len: usize,
+    // retry the failed calls by the policy
+    retry_policy: Option<RetryPolicy>,
+    // attach an idempotency key to each call
+    idempotency_key: bool,
+    // the random id of the client for the idempotency keys
+    client_id: u64,
+    // the next idempotency key
+    next_key: u64,
 }
 
 impl UdpClient {
@@ -31,37 +50,113 @@ impl UdpClient {
             sock,
             id: 0,
             buf: vec![0; 1024],
+            rsp_buf: BytesMut::with_capacity(1024 * 32),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            retry_policy: None,
+            idempotency_key: false,
+            client_id: random_id(),
+            next_key: 0,
         })
     }
 
+    /// set the max payload len of a frame, the default value is 1 MiB
+    /// a too large request or response would fail the call with `Error::FrameTooLarge`
+    /// the response can't be larger than a udp datagram in any case
+    pub fn set_max_frame_len(&mut self, len: usize) {
+        self.max_frame_len = len;
+        // the head and the checksum are received in the same datagram
+        self.buf.resize((len + 20).min(MAX_DATAGRAM_LEN), 0);
+    }
+
     /// set the default timeout value
     /// the initial timeout is 1 seconds
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.sock.set_read_timeout(Some(timeout)).unwrap();
     }
+
+    /// retry the failed calls by the policy, the calls are not retried by default
+    /// a udp request may always have reached the server, so only the requests that carry
+    /// an idempotency key are retried, they are resent with the same id and key
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = Some(policy);
+    }
+
+    /// attach an idempotency key to each call so that it can be retried, the server must
+    /// enable the reply cache by `ServerConfig::set_reply_cache`, the default value is false
+    pub fn set_idempotency_key(&mut self, enable: bool) {
+        self.idempotency_key = enable;
+    }
 }
 
 impl UdpClient {
     /// call the server
     /// the request must be encoded into the ReqBuf
     /// the response is the raw frame, you should parsing it into final response
-    pub fn call_service(&mut self, req: ReqBuf) -> Result<Frame, Error> {
+    /// the failed call is retried by the retry policy if the request carries a key
+    pub fn call_service(&mut self, mut req: ReqBuf) -> Result<Frame, Error> {
+        if self.idempotency_key && !req.has_idempotency_key() {
+            req.set_idempotency_key(self.client_id, self.next_key);
+            self.next_key += 1;
+        }
+        let keyed = req.has_idempotency_key();
         let id = self.id;
         self.id += 1;
         info!("request id = {id}");
 
-        // send the data to server
-        self.sock.send(&(req.finish(id))).map_err(Error::from)?;
+        let req = req.finish(id);
+        check_frame_len(&req, self.max_frame_len)?;
+        let mut attempt = 1;
+        loop {
+            let ret = self.call_once(id, &req);
+            let Some(policy) = self.retry_policy.as_ref() else {
+                return ret;
+            };
+            // the status error is only known after decoding the response
+            let status;
+            let err = match &ret {
+                Ok(frame) => match frame.decode_rsp() {
+                    Err(e @ Error::Status(_)) => {
+                        status = e;
+                        &status
+                    }
+                    _ => {
+                        policy.budget().deposit();
+                        return ret;
+                    }
+                },
+                Err(e) => e,
+            };
+            if !policy.should_retry(err, attempt, keyed) {
+                return ret;
+            }
+            let backoff = policy.backoff(attempt);
+            info!("retry request: attempt = {attempt}, backoff = {backoff:?}, err = {err:?}");
+            coroutine::sleep(backoff);
+            attempt += 1;
+        }
+    }
 
-        let mut buf = BytesMut::with_capacity(1024 * 32);
+    /// send the request and wait for its response
+    /// a late response of a former attempt has the same id and is also accepted
+    fn call_once(&mut self, id: u64, req: &[u8]) -> Result<Frame, Error> {
+        // send the data to server
+        self.sock.send(req).map_err(Error::from)?;
 
         // read the response
         loop {
-            self.sock.recv(&mut self.buf).map_err(Error::from)?;
+            self.sock.recv(&mut self.buf).map_err(|e| match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+                _ => Error::from(e),
+            })?;
 
             // deserialize the rsp
-            let rsp_frame = Frame::decode_from(&mut Cursor::new(&self.buf), &mut buf)
-                .map_err(|e| Error::ClientDeserialize(e.to_string()))?;
+            let mut decoder = Decoder::new(self.max_frame_len, Settings::default());
+            let rsp_frame = decoder
+                .decode(&mut Cursor::new(&self.buf), &mut self.rsp_buf)
+                .map_err(|e| match as_too_large(&e) {
+                    Some(_) => Error::from(e),
+                    None => Error::ClientDeserialize(e.to_string()),
+                })?;
 
             // discard the rsp that is is not belong to us
             if rsp_frame.id == id {
@@ -71,3 +166,93 @@ impl UdpClient {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conetty::{
+        RetryBudget, RetryCode, RspBuf, Server, ServerConfig, UdpServer, WireError,
+    };
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// reply after the client timeout for the first call
+    #[derive(Clone, Default)]
+    struct Slow(Arc<AtomicUsize>);
+
+    impl Server for Slow {
+        fn service(&self, _req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            if self.0.fetch_add(1, Ordering::Relaxed) == 0 {
+                coroutine::sleep(Duration::from_millis(300));
+            }
+            rsp.write_all(&[1]).unwrap();
+            Ok(())
+        }
+    }
+
+    fn connect(port: u16) -> UdpClient {
+        let mut policy = RetryPolicy::new();
+        policy.set_max_attempts(10);
+        policy.set_backoff(Duration::from_millis(1), Duration::from_millis(1));
+        policy.set_retry_on(&[RetryCode::Timeout]);
+        policy.set_budget(RetryBudget::new(100, 0.0));
+        let mut client = UdpClient::connect(("127.0.0.1", port)).unwrap();
+        client.set_timeout(Duration::from_millis(100));
+        client.set_retry_policy(policy);
+        client
+    }
+
+    #[test]
+    fn retry_keyed_only() {
+        let slow = Slow::default();
+        let mut config = ServerConfig::default();
+        config.set_reply_cache(Duration::from_secs(10), 1 << 20);
+        let _server =
+            UdpServer::start_with_config(slow.clone(), ("127.0.0.1", 42321), config).unwrap();
+
+        // the request without a key is not retried
+        let mut client = connect(42321);
+        let ret = client.call_service(ReqBuf::new());
+        assert!(matches!(ret, Err(Error::Timeout)), "{ret:?}");
+
+        // the keyed request is retried and runs only once on the server
+        slow.0.store(0, Ordering::Relaxed);
+        client.set_idempotency_key(true);
+        let frame = client.call_service(ReqBuf::new()).unwrap();
+        assert_eq!(frame.decode_rsp().unwrap(), [1]);
+        assert_eq!(slow.0.load(Ordering::Relaxed), 1);
+    }
+
+    /// echo the request back
+    struct Echo;
+
+    impl Server for Echo {
+        fn service(&self, req: &[u8], rsp: &mut RspBuf) -> Result<(), WireError> {
+            rsp.write_all(req).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn server_max_frame_len() {
+        let mut config = ServerConfig::default();
+        config.set_max_frame_len(4096);
+        let _server = UdpServer::start_with_config(Echo, ("127.0.0.1", 42322), config).unwrap();
+        let mut client = UdpClient::connect(("127.0.0.1", 42322)).unwrap();
+        client.set_max_frame_len(8192);
+
+        // larger than the default receive buffer
+        let mut req = ReqBuf::new();
+        req.write_all(&[7; 3000]).unwrap();
+        let frame = client.call_service(req).unwrap();
+        assert_eq!(frame.decode_rsp().unwrap(), [7; 3000]);
+
+        // the too large request is replied with a status error
+        let mut req = ReqBuf::new();
+        req.write_all(&[7; 5000]).unwrap();
+        let frame = client.call_service(req).unwrap();
+        let err = frame.decode_rsp().unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+    }
+}
@@ -27,12 +27,19 @@ extern crate log;
 
 mod conetty;
 
-#[cfg(unix)]
-pub use conetty::UdsServer;
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub use conetty::fuzz;
 pub use conetty::{
-    Client, Error, Frame, MultiplexClient, ReqBuf, RspBuf, Server, ServerInstance, StreamClient,
-    StreamExt, TcpServer, UdpClient, UdpServer, WireError,
+    join_all, Batch, BatchItem, BatchResults, BufPoolStats, CircuitBreaker, CircuitPolicy,
+    CircuitState, CircuitStats, Client, ClientConfig, ClientStats, Compression, CompressionStats,
+    ConnState, DisconnectReason, Error, Frame, MultiplexClient, OnewayStats, Peer, PendingCall,
+    Reject, ReplyCacheStats, ReqBuf, ReqContext, RetryBudget, RetryCode, RetryPolicy,
+    ReverseClient, RspBuf, Sender, Server, ServerConfig, ServerInstance, ServiceFactory, Stream,
+    StreamClient, StreamExt, TcpServer, TcpSessionServer, UdpClient, UdpServer, WireError,
 };
+#[cfg(unix)]
+pub use conetty::{UdsServer, UdsSessionServer};
 pub use may_rpc_derive::{service, Server};
 
 pub use bincode;